@@ -0,0 +1,267 @@
+use crate::matcher::find_column;
+
+use anyhow::{Context, Result};
+use std::cmp::min;
+
+/// Which columns are shown, in what order and how wide. Columns are always
+/// referred to by their index in the file; positions are among the visible
+/// columns.
+pub struct Columns {
+    /// All columns in display order, including hidden ones
+    order: Vec<usize>,
+    hidden: Vec<bool>,
+    /// Number of leading visible columns kept in view while scrolling
+    frozen: usize,
+    /// Widths set by hand, instead of the automatic ones
+    widths: Vec<Option<u16>>,
+}
+
+impl Columns {
+    pub fn new(num_cols: usize) -> Columns {
+        Columns {
+            order: (0..num_cols).collect(),
+            hidden: vec![false; num_cols],
+            frozen: 0,
+            widths: vec![None; num_cols],
+        }
+    }
+
+    /// All columns in display order, including hidden ones
+    pub fn order(&self) -> &[usize] {
+        &self.order
+    }
+
+    /// Visible columns in display order
+    pub fn visible(&self) -> Vec<usize> {
+        self.order
+            .iter()
+            .copied()
+            .filter(|&c| !self.is_hidden(c))
+            .collect()
+    }
+
+    pub fn num_hidden(&self) -> usize {
+        self.hidden.iter().filter(|&&h| h).count()
+    }
+
+    pub fn is_hidden(&self, column: usize) -> bool {
+        self.hidden.get(column).copied().unwrap_or(false)
+    }
+
+    /// Position of a column among the visible columns
+    pub fn position(&self, column: usize) -> Option<usize> {
+        self.visible().iter().position(|&c| c == column)
+    }
+
+    pub fn next_visible(&self, column: usize) -> Option<usize> {
+        let i = self.index_of(column)?;
+        self.order[i + 1..]
+            .iter()
+            .copied()
+            .find(|&c| !self.is_hidden(c))
+    }
+
+    pub fn prev_visible(&self, column: usize) -> Option<usize> {
+        let i = self.index_of(column)?;
+        self.order[..i]
+            .iter()
+            .rev()
+            .copied()
+            .find(|&c| !self.is_hidden(c))
+    }
+
+    /// The column itself if visible, otherwise the closest visible one after
+    /// or else before it
+    pub fn nearest_visible(&self, column: usize) -> usize {
+        if !self.is_hidden(column) {
+            return column;
+        }
+        self.next_visible(column)
+            .or_else(|| self.prev_visible(column))
+            .unwrap_or(column)
+    }
+
+    /// Hide a column, unless it is the last one visible. Returns whether it
+    /// was hidden.
+    pub fn hide(&mut self, column: usize) -> bool {
+        if self.is_hidden(column) || column >= self.hidden.len() || self.visible().len() <= 1 {
+            return false;
+        }
+        // Don't let the next column slip into the frozen ones
+        if self.position(column).unwrap() < self.num_frozen() {
+            self.frozen -= 1;
+        }
+        self.hidden[column] = true;
+        true
+    }
+
+    /// Number of leading visible columns kept in view while scrolling
+    pub fn num_frozen(&self) -> usize {
+        min(self.frozen, self.visible().len())
+    }
+
+    pub fn set_frozen(&mut self, n: usize) {
+        self.frozen = n;
+    }
+
+    /// Freeze the first N columns given a number, or else the given comma
+    /// separated columns, moved to the front
+    pub fn freeze_from_arg(&mut self, arg: &str, headers: &[String]) -> Result<()> {
+        if let Ok(n) = arg.trim().parse::<usize>() {
+            self.frozen = n;
+            return Ok(());
+        }
+        let mut columns = vec![];
+        for name in arg.split(',').map(|s| s.trim()) {
+            let column = find_column(name, headers)
+                .context(format!("Unknown column to freeze: {}", name))?;
+            if !columns.contains(&column) {
+                columns.push(column);
+            }
+        }
+        self.order.retain(|c| !columns.contains(c));
+        self.order.splice(0..0, columns.iter().copied());
+        self.frozen = columns.len();
+        Ok(())
+    }
+
+    pub fn show_all(&mut self) {
+        self.hidden.iter_mut().for_each(|h| *h = false);
+    }
+
+    pub fn toggle(&mut self, column: usize) {
+        if self.is_hidden(column) {
+            self.hidden[column] = false;
+        } else {
+            self.hide(column);
+        }
+    }
+
+    /// Move a column before the previous visible column
+    pub fn move_left(&mut self, column: usize) {
+        if let (Some(i), Some(prev)) = (self.index_of(column), self.prev_visible(column)) {
+            let j = self.index_of(prev).unwrap();
+            self.order.remove(i);
+            self.order.insert(j, column);
+        }
+    }
+
+    /// Move a column after the next visible column
+    pub fn move_right(&mut self, column: usize) {
+        if let (Some(i), Some(next)) = (self.index_of(column), self.next_visible(column)) {
+            let j = self.index_of(next).unwrap();
+            self.order.remove(i);
+            self.order.insert(j, column);
+        }
+    }
+
+    /// Width set by hand, if any
+    pub fn width(&self, column: usize) -> Option<u16> {
+        self.widths.get(column).copied().flatten()
+    }
+
+    pub fn set_width(&mut self, column: usize, width: u16) {
+        if let Some(w) = self.widths.get_mut(column) {
+            *w = Some(width);
+        }
+    }
+
+    /// Go back to the automatic width
+    pub fn reset_width(&mut self, column: usize) {
+        if let Some(w) = self.widths.get_mut(column) {
+            *w = None;
+        }
+    }
+
+    fn index_of(&self, column: usize) -> Option<usize> {
+        self.order.iter().position(|&c| c == column)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hide() {
+        let mut columns = Columns::new(4);
+        assert!(columns.hide(1));
+        assert!(!columns.hide(1));
+        assert_eq!(columns.visible(), vec![0, 2, 3]);
+        assert_eq!(columns.position(2), Some(1));
+        assert_eq!(columns.position(1), None);
+        assert_eq!(columns.next_visible(0), Some(2));
+        assert_eq!(columns.prev_visible(2), Some(0));
+        assert_eq!(columns.nearest_visible(1), 2);
+        assert_eq!(columns.num_hidden(), 1);
+
+        // the last visible column stays
+        assert!(columns.hide(0));
+        assert!(columns.hide(2));
+        assert!(!columns.hide(3));
+        assert_eq!(columns.visible(), vec![3]);
+        assert_eq!(columns.nearest_visible(2), 3);
+
+        columns.toggle(0);
+        assert_eq!(columns.visible(), vec![0, 3]);
+        columns.show_all();
+        assert_eq!(columns.visible(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_freeze() {
+        let headers: Vec<String> = ["id", "time", "name", "value"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let mut columns = Columns::new(4);
+        columns.freeze_from_arg("2", &headers).unwrap();
+        assert_eq!(columns.num_frozen(), 2);
+        assert_eq!(columns.order(), &[0, 1, 2, 3]);
+
+        columns.freeze_from_arg("value, ID", &headers).unwrap();
+        assert_eq!(columns.order(), &[3, 0, 1, 2]);
+        assert_eq!(columns.num_frozen(), 2);
+        assert!(columns.freeze_from_arg("id,nope", &headers).is_err());
+
+        // hiding a frozen column unfreezes it
+        columns.hide(3);
+        assert_eq!(columns.num_frozen(), 1);
+        columns.hide(1);
+        assert_eq!(columns.num_frozen(), 1);
+        columns.set_frozen(10);
+        assert_eq!(columns.num_frozen(), 2);
+    }
+
+    #[test]
+    fn test_move() {
+        let mut columns = Columns::new(4);
+        columns.move_right(0);
+        assert_eq!(columns.order(), &[1, 0, 2, 3]);
+        columns.move_left(1);
+        assert_eq!(columns.order(), &[1, 0, 2, 3]);
+        columns.move_left(3);
+        assert_eq!(columns.order(), &[1, 0, 3, 2]);
+
+        // moving skips over hidden columns
+        columns.hide(3);
+        columns.move_right(0);
+        assert_eq!(columns.order(), &[1, 3, 2, 0]);
+        assert_eq!(columns.visible(), vec![1, 2, 0]);
+        columns.move_left(0);
+        columns.move_left(0);
+        assert_eq!(columns.order(), &[0, 1, 3, 2]);
+    }
+
+    #[test]
+    fn test_width() {
+        let mut columns = Columns::new(2);
+        assert_eq!(columns.width(1), None);
+        columns.set_width(1, 12);
+        columns.set_width(5, 12);
+        assert_eq!(columns.width(1), Some(12));
+        assert_eq!(columns.width(5), None);
+        columns.reset_width(1);
+        assert_eq!(columns.width(1), None);
+    }
+}
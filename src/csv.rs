@@ -1,11 +1,20 @@
 extern crate csv;
 
-use anyhow;
-use anyhow::{bail, Result};
-use csv::{Position, Reader};
+use crate::index_file::{self, LoadedIndex, SavedIndex};
+use crate::matcher::Matcher;
+use crate::parallel_index::{self, IndexProgress};
+use crate::profile::{self, ColumnProfile, Profiler};
+use crate::row_cache::{RowBlock, RowCache, MAX_ROWS_PER_BLOCK};
+use crate::seekable_file::StreamStatus;
+use crate::types::ValueFormat;
+
+use anyhow::Result;
+use csv::{ByteRecord, Position, Reader, ReaderBuilder, StringRecord};
 use std::cmp::max;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::SeekFrom;
+use std::os::unix::fs::{FileExt, MetadataExt};
 use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 use std::time;
@@ -18,20 +27,405 @@ fn string_record_to_vec(record: &csv::StringRecord) -> Vec<String> {
     string_vec
 }
 
+/// Settings shared by everything that parses the file (the viewer, the
+/// background indexer and the finder), so that they agree on how records are
+/// split.
+#[derive(Debug)]
+pub struct CsvConfig {
+    path: String,
+    delimiter: u8,
+    no_headers: bool,
+    stream_status: Option<StreamStatus>,
+    follow: bool,
+    persistent_index: bool,
+    value_format: ValueFormat,
+}
+
+impl CsvConfig {
+    pub fn new(path: &str, delimiter: u8, no_headers: bool) -> CsvConfig {
+        CsvConfig {
+            path: path.to_string(),
+            delimiter,
+            no_headers,
+            stream_status: None,
+            follow: false,
+            persistent_index: false,
+            value_format: ValueFormat::default(),
+        }
+    }
+
+    /// Keep watching the file for appended records, like `tail -f`
+    pub fn set_follow(&mut self, follow: bool) {
+        self.follow = follow;
+    }
+
+    pub fn follow(&self) -> bool {
+        self.follow
+    }
+
+    /// Save the position table to disk once indexed, and load it from there
+    /// next time the same file is opened
+    pub fn set_persistent_index(&mut self, persistent_index: bool) {
+        self.persistent_index = persistent_index;
+    }
+
+    pub fn persistent_index(&self) -> bool {
+        self.persistent_index
+    }
+
+    /// How values are shown, which the indexer measures column widths by
+    pub fn set_value_format(&mut self, value_format: ValueFormat) {
+        self.value_format = value_format;
+    }
+
+    pub fn value_format(&self) -> ValueFormat {
+        self.value_format
+    }
+
+    /// Mark the file as still being written to by a background copy
+    pub fn set_stream_status(&mut self, stream_status: Option<StreamStatus>) {
+        self.stream_status = stream_status;
+    }
+
+    /// Whether more data might still be appended to the file
+    pub fn is_growing(&self) -> bool {
+        if self.follow {
+            return true;
+        }
+        match &self.stream_status {
+            Some(s) => !s.is_done(),
+            None => false,
+        }
+    }
+
+    pub fn filename(&self) -> &str {
+        self.path.as_str()
+    }
+
+    pub fn delimiter(&self) -> u8 {
+        self.delimiter
+    }
+
+    pub fn no_headers(&self) -> bool {
+        self.no_headers
+    }
+
+    /// Number of records preceding the first data row, i.e. the record number
+    /// (as in `csv::Position::record()`) of row index 0.
+    pub fn header_offset(&self) -> u64 {
+        if self.no_headers {
+            0
+        } else {
+            1
+        }
+    }
+
+    pub fn reader_builder(&self) -> ReaderBuilder {
+        let mut builder = ReaderBuilder::new();
+        builder
+            .delimiter(self.delimiter)
+            .has_headers(!self.no_headers);
+        builder
+    }
+
+    /// Reader for displaying rows, which are shown even if they have a
+    /// different number of fields than the headers
+    pub fn new_reader(&self) -> Result<Reader<File>> {
+        let reader = self
+            .reader_builder()
+            .flexible(true)
+            .from_path(self.path.as_str())?;
+        Ok(reader)
+    }
+}
+
+pub enum ScanResult {
+    Record(Position, ByteRecord),
+    /// No complete record available yet, but the file is still growing
+    Pending,
+    /// The file was truncated or replaced (e.g. rotated), and scanning
+    /// restarted from the first record
+    Reset,
+    Done,
+}
+
+/// Sequential scan over all data records that can be resumed as the file
+/// grows. While the file is growing, the last record before the end of file
+/// might be incomplete, so it is only returned once terminated by a newline,
+/// followed by another record, or once the file is complete.
+pub struct RecordScanner {
+    config: Arc<CsvConfig>,
+    reader: Reader<File>,
+    lookahead: Option<(Position, ByteRecord)>,
+    file: File,
+    file_id: (u64, u64),
+    /// Length and hash of the start of the file as read, to tell when it is
+    /// rewritten in place
+    head_hash: Option<(u64, u64)>,
+}
+
+impl RecordScanner {
+    pub fn new(config: Arc<CsvConfig>) -> Result<RecordScanner> {
+        RecordScanner::open(config, None)
+    }
+
+    /// Resume scanning from `start`, which must be the position of a record
+    pub fn new_at(config: Arc<CsvConfig>, start: Position) -> Result<RecordScanner> {
+        RecordScanner::open(config, Some(start))
+    }
+
+    fn open(config: Arc<CsvConfig>, start: Option<Position>) -> Result<RecordScanner> {
+        let file = File::open(config.filename())?;
+        let file_id = file_id(&file.metadata()?);
+        let mut reader = config.reader_builder().from_reader(file.try_clone()?);
+        let start = match start {
+            Some(start) => start,
+            None if config.no_headers() => Position::new(),
+            None => {
+                reader.byte_headers()?;
+                reader.position().clone()
+            }
+        };
+        reader.seek_raw(SeekFrom::Start(start.byte()), start)?;
+        let scanner = RecordScanner {
+            config,
+            reader,
+            lookahead: None,
+            file,
+            file_id,
+            head_hash: None,
+        };
+        Ok(scanner)
+    }
+
+    pub fn next(&mut self) -> ScanResult {
+        // Must be checked before reading, otherwise data appended right
+        // after hitting the end of file could be missed
+        let is_growing = self.config.is_growing();
+
+        let current = match self.lookahead.take() {
+            Some(current) => current,
+            None => {
+                let pos = self.reader.position().clone();
+                match self.read() {
+                    Some(current) => current,
+                    None if is_growing => {
+                        self.rewind(pos);
+                        return self.pending_or_reset();
+                    }
+                    None => return ScanResult::Done,
+                }
+            }
+        };
+        match self.read() {
+            Some(next) => {
+                self.lookahead = Some(next);
+            }
+            None if is_growing && !self.is_terminated(self.reader.position()) => {
+                self.rewind(current.0);
+                return self.pending_or_reset();
+            }
+            None => {}
+        }
+        ScanResult::Record(current.0, current.1)
+    }
+
+    /// Position right after the last record returned
+    pub fn position(&self) -> &Position {
+        match &self.lookahead {
+            Some((pos, _)) => pos,
+            None => self.reader.position(),
+        }
+    }
+
+    /// Whether the record ending at `pos` is terminated by a newline
+    fn is_terminated(&self, pos: &Position) -> bool {
+        let mut last_byte = [0; 1];
+        match pos.byte().checked_sub(1) {
+            Some(i) => self.file.read_exact_at(&mut last_byte, i).is_ok() && last_byte[0] == b'\n',
+            None => false,
+        }
+    }
+
+    fn pending_or_reset(&mut self) -> ScanResult {
+        if self.is_replaced() {
+            // This fails if the new file doesn't even have headers yet, in
+            // which case try again later
+            if let Ok(scanner) = RecordScanner::new(self.config.clone()) {
+                *self = scanner;
+                return ScanResult::Reset;
+            }
+        }
+        ScanResult::Pending
+    }
+
+    fn is_replaced(&mut self) -> bool {
+        if !self.config.follow() {
+            return false;
+        }
+        let read_len = self.reader.position().byte();
+        match fs::metadata(self.config.filename()) {
+            Ok(m) if file_id(&m) != self.file_id || m.len() < read_len => return true,
+            Ok(_) => {}
+            // Possibly in the middle of being rotated, check again later
+            Err(_) => return false,
+        }
+        // Rewritten in place with at least as much data as had been read
+        if let Some((len, hash)) = self.head_hash {
+            match index_file::hash_region(&self.file, 0, len) {
+                Ok(h) if h != hash => return true,
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+        }
+        let len = read_len.min(index_file::HASH_REGION_SIZE);
+        if self
+            .head_hash
+            .is_none_or(|(hashed_len, _)| hashed_len < len)
+        {
+            self.head_hash = index_file::hash_region(&self.file, 0, len)
+                .ok()
+                .map(|hash| (len, hash));
+        }
+        false
+    }
+
+    fn read(&mut self) -> Option<(Position, ByteRecord)> {
+        let pos = self.reader.position().clone();
+        let mut record = ByteRecord::new();
+        match self.reader.read_byte_record(&mut record) {
+            Ok(true) => Some((pos, record)),
+            Ok(false) => None,
+            // Keep going past malformed records, they still count as rows
+            Err(_) if self.reader.position().byte() > pos.byte() => Some((pos, ByteRecord::new())),
+            Err(_) => None,
+        }
+    }
+
+    fn rewind(&mut self, pos: Position) {
+        // seek() is a no-op if already at pos, which would keep the end of
+        // file state around
+        let _ = self.reader.seek_raw(SeekFrom::Start(pos.byte()), pos);
+    }
+}
+
+fn file_id(metadata: &fs::Metadata) -> (u64, u64) {
+    (metadata.dev(), metadata.ino())
+}
+
+pub enum FilteredScan {
+    /// A record selected by all the filters, with invalid UTF-8 replaced
+    Record(Position, StringRecord),
+    /// A record left out by the filters
+    FilteredOut,
+    /// The file was truncated or replaced, and scanning restarted from the
+    /// first record
+    Reset,
+    /// No complete record available yet, after waiting a bit for more
+    Waiting,
+}
+
+/// Scan over all data records, or the ones selected by every one of
+/// `filters`, for the background tasks going through the whole file. Every
+/// record read is a step of the iteration, so that the caller gets to check
+/// whether to stop even when the filters select nothing.
+pub struct FilteredScanner {
+    scanner: RecordScanner,
+    filters: Vec<Matcher>,
+    filename: String,
+    follow: bool,
+}
+
+impl FilteredScanner {
+    pub fn new(config: Arc<CsvConfig>, filters: Vec<Matcher>) -> Result<FilteredScanner> {
+        let filename = config.filename().to_owned();
+        let follow = config.follow();
+        Ok(FilteredScanner {
+            scanner: RecordScanner::new(config)?,
+            filters,
+            filename,
+            follow,
+        })
+    }
+
+    /// Fraction of the file scanned
+    pub fn progress(&self) -> f64 {
+        match fs::metadata(&self.filename) {
+            Ok(m) if m.len() > 0 => {
+                (self.scanner.position().byte() as f64 / m.len() as f64).min(1.0)
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+impl Iterator for FilteredScanner {
+    type Item = FilteredScan;
+
+    fn next(&mut self) -> Option<FilteredScan> {
+        match self.scanner.next() {
+            ScanResult::Record(pos, r) => {
+                let record = StringRecord::from_byte_record(r)
+                    .unwrap_or_else(|e| StringRecord::from_byte_record_lossy(e.into_byte_record()));
+                if self
+                    .filters
+                    .iter()
+                    .all(|m| !m.matching_columns(&record).is_empty())
+                {
+                    Some(FilteredScan::Record(pos, record))
+                } else {
+                    Some(FilteredScan::FilteredOut)
+                }
+            }
+            // A followed file never ends, so go with what has been written so far
+            ScanResult::Pending if self.follow => None,
+            ScanResult::Pending => {
+                thread::sleep(time::Duration::from_millis(100));
+                Some(FilteredScan::Waiting)
+            }
+            ScanResult::Reset => Some(FilteredScan::Reset),
+            ScanResult::Done => None,
+        }
+    }
+}
+
+/// Poll `f` until it gives a result, such as the outcome of a background
+/// scan, failing the test if that takes too long
+#[cfg(test)]
+pub(crate) fn wait_for<T>(mut f: impl FnMut() -> Option<T>) -> T {
+    let deadline = time::Instant::now() + time::Duration::from_secs(30);
+    loop {
+        if let Some(result) = f() {
+            return result;
+        }
+        assert!(time::Instant::now() < deadline, "timed out waiting");
+        thread::sleep(time::Duration::from_millis(10));
+    }
+}
+
 pub struct CsvLensReader {
+    config: Arc<CsvConfig>,
     reader: Reader<File>,
     pub headers: Vec<String>,
     internal: Arc<Mutex<ReaderInternalState>>,
+    generation: u64,
+    cache: RowCache,
+    /// Row index and position the reader is at, if known
+    cursor: Option<(u64, Position)>,
+    /// Position of the first row, after the headers
+    first_row_pos: Position,
+    #[allow(dead_code)]
     bg_handle: thread::JoinHandle<()>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Row {
     pub record_num: usize,
     pub fields: Vec<String>,
 }
 
 impl Row {
+    #[cfg(test)]
     pub fn new(record_num: usize, fields: Vec<&str>) -> Row {
         Row {
             record_num,
@@ -41,17 +435,40 @@ impl Row {
 }
 
 impl CsvLensReader {
-    pub fn new(filename: &str) -> Result<Self> {
-        let mut reader = Reader::from_path(filename)?;
+    pub fn new(config: Arc<CsvConfig>) -> Result<Self> {
+        let mut reader = config.new_reader()?;
         let headers_record = reader.headers().unwrap();
-        let headers = string_record_to_vec(headers_record);
+        let headers = if config.no_headers() {
+            // Without a header row this is the first record, only used for its length
+            (1..=headers_record.len()).map(|i| i.to_string()).collect()
+        } else {
+            string_record_to_vec(headers_record)
+        };
+
+        let first_row_pos = if config.no_headers() {
+            Position::new()
+        } else {
+            reader.position().clone()
+        };
+        // Without headers, the first record had been read ahead and the
+        // reader is not really at first_row_pos
+        let cursor = if config.no_headers() {
+            None
+        } else {
+            Some((0, first_row_pos.clone()))
+        };
 
-        let (m_internal, handle) = ReaderInternalState::init_internal(filename);
+        let (m_internal, handle) = ReaderInternalState::init_internal(config.clone());
 
         let reader = Self {
+            config,
             reader,
             headers,
             internal: m_internal,
+            generation: 0,
+            cache: RowCache::new(),
+            cursor,
+            first_row_pos,
             bg_handle: handle,
         };
         Ok(reader)
@@ -62,115 +479,260 @@ impl CsvLensReader {
         self.get_rows_impl(&indices).map(|x| x.0)
     }
 
+    /// Get rows in the order of `indices`, which don't have to be sorted,
+    /// e.g. when the view is sorted by a column
     pub fn get_rows_for_indices(&mut self, indices: &[u64]) -> Result<Vec<Row>> {
-        self.get_rows_impl(indices).map(|x| x.0)
+        if indices.windows(2).all(|w| w[0] < w[1]) {
+            return self.get_rows_impl(indices).map(|x| x.0);
+        }
+        let mut sorted = indices.to_vec();
+        sorted.sort_unstable();
+        sorted.dedup();
+        let rows = self.get_rows_impl(&sorted)?.0;
+        let rows: HashMap<u64, Row> = rows
+            .into_iter()
+            .map(|r| (r.record_num as u64 - 1, r))
+            .collect();
+        Ok(indices
+            .iter()
+            .filter_map(|i| rows.get(i).cloned())
+            .collect())
     }
 
     fn get_rows_impl(&mut self, indices: &[u64]) -> Result<(Vec<Row>, GetRowsStats)> {
         // stats for debugging and testing
         let mut stats = GetRowsStats::new();
 
-        let pos = Position::new();
-        self.reader.seek(pos)?;
+        // The last record might still be incomplete while the file is
+        // growing, so only return those that had been indexed
+        let available_indices: Vec<u64>;
+        let indices = if self.config.is_growing() {
+            let n = self.get_total_line_numbers_approx().unwrap_or(0) as u64;
+            available_indices = indices.iter().cloned().filter(|&i| i < n).collect();
+            &available_indices
+        } else {
+            indices
+        };
 
-        let pos_table = self.get_pos_table();
-        let mut pos_iter = pos_table.iter();
-        let mut indices_iter = indices.iter();
+        // The file had been replaced, e.g. rotated in follow mode
+        let generation = self.internal.lock().unwrap().generation;
+        if generation != self.generation {
+            self.reader = self.config.new_reader()?;
+            self.generation = generation;
+            self.cache.clear();
+            self.cursor = None;
+        }
 
         let mut res = Vec::new();
+        let mut rest = indices;
+        while let Some(&first) = rest.first() {
+            let (segment_start, segment_end) = self.find_segment(first);
+            let n = rest
+                .iter()
+                .take_while(|&&i| i >= segment_start.0 && segment_end.is_none_or(|end| i < end))
+                .count();
+            let (group, remaining) = rest.split_at(n);
+            res.extend(self.get_rows_in_segment(group, segment_start, &mut stats)?);
+            rest = remaining;
+        }
 
-        let mut next_pos = pos_iter.next();
-        let mut next_wanted = indices_iter.next();
-        loop {
-            if next_wanted.is_none() {
-                break;
-            }
-            // seek as close to the next wanted record index as possible
-            let index = *next_wanted.unwrap();
-            loop {
-                if let Some(pos) = next_pos {
-                    if pos.record() - 1 <= index {
-                        self.reader.seek(pos.clone())?;
-                        stats.log_seek();
-                    } else {
-                        break;
-                    }
-                    next_pos = pos_iter.next();
-                } else {
-                    break;
-                }
+        Ok((res, stats))
+    }
+
+    /// Find the segment of the position table that contains row `index`.
+    /// Returns the row index and position of its first row, and the row index
+    /// where the next segment starts.
+    fn find_segment(&self, index: u64) -> ((u64, Position), Option<u64>) {
+        let header_offset = self.config.header_offset();
+        let m = self.internal.lock().unwrap();
+        let pos_table = &m.pos_table;
+        let i = pos_table.partition_point(|p| p.record() - header_offset <= index);
+        let start = match i.checked_sub(1) {
+            Some(j) => {
+                let pos = &pos_table[j];
+                (pos.record() - header_offset, pos.clone())
             }
+            None => (0, self.first_row_pos.clone()),
+        };
+        let end = pos_table.get(i).map(|p| p.record() - header_offset);
+        (start, end)
+    }
 
-            // note that records() excludes header by default, but here the first entry is header
-            // because of the seek() above.
-            let mut records = self.reader.records();
+    /// Get rows for sorted `indices` within a segment of the position table,
+    /// decoding those that aren't cached yet
+    fn get_rows_in_segment(
+        &mut self,
+        indices: &[u64],
+        segment_start: (u64, Position),
+        stats: &mut GetRowsStats,
+    ) -> Result<Vec<Row>> {
+        let segment = segment_start.0;
+        let (first, last) = (indices[0], indices[indices.len() - 1]);
+        // Rows before the ones wanted are parsed anyway, so decode some of
+        // them too in case of scrolling up
+        let decode_from = |to: u64| {
+            first
+                .min(to.saturating_sub(MAX_ROWS_PER_BLOCK as u64 - 1))
+                .max(segment)
+        };
 
-            // parse records and collect those that are wanted
-            loop {
-                // exit early if all found. This should be common in case of consecutive indices
-                if next_wanted.is_none() {
-                    break;
+        let cached = self
+            .cache
+            .get(segment)
+            .map(|b| (b.first_row, b.end_row(), b.next_pos.clone()));
+        match cached {
+            None => {
+                let (rows, next_pos) =
+                    self.read_rows(segment_start.clone(), decode_from(last), last, stats)?;
+                let block = RowBlock {
+                    first_row: decode_from(last),
+                    rows,
+                    next_pos,
+                };
+                self.cache.insert(segment, block);
+            }
+            Some((first_row, mut end_row, mut next_pos)) => {
+                if first < first_row {
+                    let to = first_row - 1;
+                    let (rows, rows_next_pos) =
+                        self.read_rows(segment_start.clone(), decode_from(to), to, stats)?;
+                    let block = self.cache.get(segment).unwrap();
+                    block.prepend(decode_from(to), rows, rows_next_pos);
+                    // The block is replaced by the new rows if it got too
+                    // large, in which case the rest has to be read again
+                    end_row = block.end_row();
+                    next_pos = block.next_pos.clone();
                 }
-                let wanted_index = *next_wanted.unwrap();
-                let record_num = records.reader().position().record();
-                if let Some(r) = records.next() {
-                    stats.log_parsed_record();
-                    // no effective pre-seeking happened, this is still the header
-                    if record_num == 0 {
-                        continue;
-                    }
-                    if record_num - 1 == wanted_index {
-                        let string_record = r?;
-                        let mut fields = Vec::new();
-                        for field in string_record.iter() {
-                            fields.push(String::from(field));
-                        }
-                        let row = Row {
-                            record_num: record_num as usize,
-                            fields,
-                        };
-                        res.push(row);
-                        next_wanted = indices_iter.next();
-                    }
-                    // stop parsing if done scanning whole block between marked positions
-                    if let Some(pos) = next_pos {
-                        if record_num >= pos.record() {
-                            break;
-                        }
-                    }
-                } else {
-                    // no more records
-                    break;
+                if last >= end_row {
+                    // Continue from the cached rows instead of the start of
+                    // the segment, even if there's a gap
+                    let (rows, next_pos) =
+                        self.read_rows((end_row, next_pos), end_row, last, stats)?;
+                    let block = self.cache.get(segment).unwrap();
+                    block.extend(rows, next_pos);
                 }
             }
+        }
 
-            if next_pos.is_none() {
-                // If here, the last block had been scanned, and we should be
-                // done. If next_wanted is not None, that means an out of bound
-                // index was provided - that could happen for small input - and
-                // we should ignore it and stop here regardless
-                break;
+        let block = self.cache.get(segment).unwrap();
+        let mut res = Vec::new();
+        let mut missing = vec![];
+        for &i in indices {
+            match block.get(i) {
+                Some(row) => res.push(row.clone()),
+                None if i >= block.first_row => {}
+                // Dropped from the block, if more rows were requested than
+                // it keeps
+                None => missing.push(i),
             }
         }
+        if !missing.is_empty() {
+            let (rows, _) =
+                self.read_rows(segment_start, missing[0], *missing.last().unwrap(), stats)?;
+            let mut found: Vec<Row> = rows
+                .into_iter()
+                .filter(|r| missing.binary_search(&(r.record_num as u64 - 1)).is_ok())
+                .collect();
+            found.append(&mut res);
+            res = found;
+        }
+        Ok(res)
+    }
 
-        Ok((res, stats))
+    /// Parse rows from `start` (a row index and its position) through row
+    /// `to`, and decode those from `decode_from` on. Continues from the
+    /// reader's current position if that is closer.
+    fn read_rows(
+        &mut self,
+        start: (u64, Position),
+        decode_from: u64,
+        to: u64,
+        stats: &mut GetRowsStats,
+    ) -> Result<(Vec<Row>, Position)> {
+        let mut row = match &self.cursor {
+            Some(cursor) if cursor.0 >= start.0 && cursor.0 <= decode_from => cursor.0,
+            _ => {
+                // seek() is a no-op if already at pos, which would keep the
+                // end of file state around
+                self.reader
+                    .seek_raw(SeekFrom::Start(start.1.byte()), start.1.clone())?;
+                stats.log_seek();
+                start.0
+            }
+        };
+
+        let mut rows = Vec::new();
+        let mut record = ByteRecord::new();
+        self.cursor = None;
+        while row <= to {
+            if !self.reader.read_byte_record(&mut record)? {
+                // Out of bound indices, which could happen for small input
+                break;
+            }
+            stats.log_parsed_record();
+            if row >= decode_from {
+                rows.push(Row {
+                    record_num: row as usize + 1,
+                    fields: record
+                        .iter()
+                        .map(|x| String::from_utf8_lossy(x).into_owned())
+                        .collect(),
+                });
+            }
+            row += 1;
+        }
+        let next_pos = self.reader.position().clone();
+        if row > to {
+            self.cursor = Some((row, next_pos.clone()));
+        }
+        Ok((rows, next_pos))
     }
 
     pub fn get_total_line_numbers(&self) -> Option<usize> {
-        let res = (*self.internal.lock().unwrap()).total_line_number;
+        let res = self.internal.lock().unwrap().total_line_number;
         res
     }
 
     pub fn get_total_line_numbers_approx(&self) -> Option<usize> {
-        let res = (*self.internal.lock().unwrap()).total_line_number_approx;
-        res
+        let m = self.internal.lock().unwrap();
+        let estimate = m.progress.as_ref().and_then(|p| p.estimate_records());
+        estimate.or(m.total_line_number_approx)
+    }
+
+    /// Fraction of the file indexed so far, while it is being indexed in
+    /// parallel
+    pub fn get_index_progress(&self) -> Option<f64> {
+        let m = self.internal.lock().unwrap();
+        m.progress.as_ref().map(|p| p.fraction())
+    }
+
+    /// Why indexing stopped before the end of the file, if it did
+    pub fn get_index_error(&self) -> Option<String> {
+        self.internal.lock().unwrap().error.clone()
+    }
+
+    /// Types and widths of the columns, from the records indexed so far
+    pub fn get_column_profile(&self) -> ColumnProfile {
+        self.internal.lock().unwrap().profile.clone()
+    }
+
+    pub fn is_growing(&self) -> bool {
+        self.config.is_growing()
+    }
+
+    /// Changes whenever the file is replaced and all rows should be fetched
+    /// again
+    pub fn generation(&self) -> u64 {
+        self.internal.lock().unwrap().generation
     }
 
+    #[cfg(test)]
     pub fn get_pos_table(&self) -> Vec<Position> {
-        let res = (*self.internal.lock().unwrap()).pos_table.clone();
+        let res = self.internal.lock().unwrap().pos_table.clone();
         res
     }
 
+    #[allow(dead_code)]
     fn wait_internal(&self) {
         loop {
             if self.internal.lock().unwrap().done {
@@ -204,78 +766,298 @@ impl GetRowsStats {
     }
 }
 
+const POS_TABLE_NUM_ENTRIES: usize = 10000;
+
+// handle small csv (don't keep pos every line)
+const POS_TABLE_MINIMUM_INTERVAL: usize = 100;
+
 struct ReaderInternalState {
     total_line_number: Option<usize>,
     total_line_number_approx: Option<usize>,
     pos_table: Vec<Position>,
+    /// Set while the whole file is being indexed in parallel
+    progress: Option<Arc<IndexProgress>>,
+    profile: ColumnProfile,
     done: bool,
+    /// Why indexing stopped early
+    error: Option<String>,
+    /// Incremented whenever the file is replaced and indexing starts over
+    generation: u64,
 }
 
 impl ReaderInternalState {
-    fn init_internal(filename: &str) -> (Arc<Mutex<ReaderInternalState>>, JoinHandle<()>) {
-        let internal = ReaderInternalState {
+    fn init_internal(config: Arc<CsvConfig>) -> (Arc<Mutex<ReaderInternalState>>, JoinHandle<()>) {
+        let mut internal = ReaderInternalState {
             total_line_number: None,
             total_line_number_approx: None,
             pos_table: vec![],
+            progress: None,
+            profile: ColumnProfile::default(),
             done: false,
+            error: None,
+            generation: 0,
         };
 
+        // Reuse the index from a previous run if the file hasn't changed, or
+        // has only been appended to since
+        let saved = if config.persistent_index() {
+            index_file::load(&config)
+        } else {
+            None
+        };
+        let (saved, is_complete) = match saved {
+            Some(LoadedIndex::Complete(index)) => (Some(index), true),
+            Some(LoadedIndex::Partial(index)) => (Some(index), false),
+            None => (None, false),
+        };
+        if let Some(index) = &saved {
+            internal.pos_table = index.pos_table.clone();
+            internal.total_line_number_approx = Some(index.num_records);
+            internal.profile = index.profile.profile();
+            if is_complete && !config.is_growing() {
+                internal.total_line_number = Some(index.num_records);
+                internal.done = true;
+            }
+        }
+
         let m_state = Arc::new(Mutex::new(internal));
 
         let _m = m_state.clone();
-        let _filename = filename.to_string();
         let handle = thread::spawn(move || {
-            // quick line count
-            let total_line_number_approx;
-            {
-                let file = File::open(_filename.as_str()).unwrap();
-                let buf_reader = BufReader::new(file);
-                // subtract 1 for headers
-                total_line_number_approx = buf_reader.lines().count().saturating_sub(1);
-
+            if _m.lock().unwrap().done {
+                return;
+            }
+            // Reading the file failed, e.g. it was deleted, so stop indexing
+            // there and tell why
+            if let Err(e) = index_records(config, saved, &_m) {
                 let mut m = _m.lock().unwrap();
-                (*m).total_line_number_approx = Some(total_line_number_approx);
+                m.error = Some(e.to_string());
+                m.progress = None;
+                m.done = true;
             }
+        });
+
+        (m_state, handle)
+    }
+}
+
+/// Index the records after `saved`, or all of them, following the file as
+/// it grows if it is still growing
+fn index_records(
+    config: Arc<CsvConfig>,
+    saved: Option<SavedIndex>,
+    m_state: &Mutex<ReaderInternalState>,
+) -> Result<()> {
+    let header_offset = config.header_offset();
+    let is_growing = config.is_growing();
+
+    let mut pos_table_update_every = POS_TABLE_MINIMUM_INTERVAL;
+    let mut n = 0;
+    let mut profiler = Profiler::new(config.value_format());
+    let start = match &saved {
+        Some(index) => {
+            pos_table_update_every = index.pos_table_update_every;
+            n = index.num_records;
+            profiler = index.profile.clone();
+            index.end.clone()
+        }
+        None => RecordScanner::new(config.clone())?.position().clone(),
+    };
+
+    // The whole file is there, so parse byte ranges of it in parallel
+    if !is_growing {
+        let file_size = fs::metadata(config.filename())?.len();
+        let remaining = file_size.saturating_sub(start.byte());
+        let progress = Arc::new(IndexProgress::new(n as u64, remaining));
+        m_state.lock().unwrap().progress = Some(progress.clone());
 
-            let pos_table_num_entries = 10000;
-            let minimum_interval = 100; // handle small csv (don't keep pos every line)
-            let pos_table_update_every = max(
-                minimum_interval,
-                total_line_number_approx / pos_table_num_entries,
-            );
-
-            // full csv parsing
-            let bg_reader = Reader::from_path(_filename.as_str()).unwrap();
-            let mut n = 0;
-            let mut iter = bg_reader.into_records();
-            loop {
-                let next_pos = iter.reader().position().clone();
-                if iter.next().is_none() {
-                    break;
+        // Show types and widths from the first records until the
+        // whole file is profiled
+        if saved.is_none() {
+            let sample = sample_profile(config.clone(), start.clone());
+            m_state.lock().unwrap().profile = sample.profile();
+        }
+
+        let num_chunks = thread::available_parallelism()
+            .map_or(1, |x| x.get())
+            .min((remaining / parallel_index::MIN_CHUNK_SIZE) as usize)
+            .max(1);
+        let mut is_first_chunk = saved.is_none();
+        let (end, chunks_profiler) = parallel_index::build(
+            config.clone(),
+            start,
+            num_chunks,
+            progress.clone(),
+            |positions| {
+                if is_first_chunk {
+                    // All chunks are underway by now, so this should
+                    // be close
+                    let estimate = progress.estimate_records().unwrap_or(0);
+                    pos_table_update_every =
+                        max(POS_TABLE_MINIMUM_INTERVAL, estimate / POS_TABLE_NUM_ENTRIES);
+                    is_first_chunk = false;
+                }
+                let mut m = m_state.lock().unwrap();
+                extend_pos_table(
+                    &mut m.pos_table,
+                    positions,
+                    pos_table_update_every,
+                    header_offset,
+                );
+            },
+        )?;
+
+        n = (end.record() - header_offset) as usize;
+        profiler.merge(&chunks_profiler);
+        m_state.lock().unwrap().profile = profiler.profile();
+        if config.persistent_index() {
+            save_index(&config, m_state, pos_table_update_every, n, end, &profiler);
+        }
+        let mut m = m_state.lock().unwrap();
+        m.total_line_number = Some(n);
+        m.progress = None;
+        m.done = true;
+        return Ok(());
+    }
+
+    // full csv parsing, following the file as it grows
+    let mut scanner = RecordScanner::new_at(config.clone(), start)?;
+    let mut has_unsaved_records = saved.is_none();
+    let mut has_unpublished_profile = false;
+    loop {
+        match scanner.next() {
+            ScanResult::Record(pos, record) => {
+                profiler.add_sampled(n as u64, &record);
+                has_unpublished_profile = true;
+                if n as u64 + 1 == profile::SAMPLE_SIZE {
+                    m_state.lock().unwrap().profile = profiler.profile();
+                    has_unpublished_profile = false;
                 }
                 // must not include headers position here (n > 0)
                 if n > 0 && n % pos_table_update_every == 0 {
-                    let mut m = _m.lock().unwrap();
-                    (*m).pos_table.push(next_pos);
+                    let mut m = m_state.lock().unwrap();
+                    m.pos_table.push(pos);
+                    m.total_line_number_approx = Some(n);
+                    // Size of the input is unknown in advance, keep
+                    // the table small by dropping every other entry
+                    if m.pos_table.len() >= 2 * POS_TABLE_NUM_ENTRIES {
+                        pos_table_update_every *= 2;
+                        let pos_table = std::mem::take(&mut m.pos_table);
+                        extend_pos_table(
+                            &mut m.pos_table,
+                            pos_table,
+                            pos_table_update_every,
+                            header_offset,
+                        );
+                    }
                 }
                 n += 1;
+                has_unsaved_records = true;
             }
-            let mut m = _m.lock().unwrap();
-            (*m).total_line_number = Some(n);
-            (*m).done = true;
-        });
+            ScanResult::Pending => {
+                if has_unpublished_profile {
+                    m_state.lock().unwrap().profile = profiler.profile();
+                    has_unpublished_profile = false;
+                }
+                // Caught up with the end of a followed file
+                if has_unsaved_records && config.persistent_index() && config.follow() {
+                    save_index(
+                        &config,
+                        m_state,
+                        pos_table_update_every,
+                        n,
+                        scanner.position().clone(),
+                        &profiler,
+                    );
+                    has_unsaved_records = false;
+                }
+                m_state.lock().unwrap().total_line_number_approx = Some(n);
+                thread::sleep(time::Duration::from_millis(100));
+            }
+            ScanResult::Reset => {
+                n = 0;
+                pos_table_update_every = POS_TABLE_MINIMUM_INTERVAL;
+                has_unsaved_records = true;
+                profiler = Profiler::new(config.value_format());
+                has_unpublished_profile = false;
+                let mut m = m_state.lock().unwrap();
+                m.pos_table.clear();
+                m.profile = ColumnProfile::default();
+                m.total_line_number_approx = Some(0);
+                m.generation += 1;
+            }
+            ScanResult::Done => break,
+        }
+    }
+    let mut m = m_state.lock().unwrap();
+    m.total_line_number = Some(n);
+    m.profile = profiler.profile();
+    m.done = true;
+    Ok(())
+}
 
-        (m_state, handle)
+/// Append `positions` to the table, skipping those less than `every` rows
+/// after the previous entry
+fn extend_pos_table(
+    pos_table: &mut Vec<Position>,
+    positions: Vec<Position>,
+    every: usize,
+    header_offset: u64,
+) {
+    for pos in positions {
+        let last = pos_table.last().map_or(0, |p| p.record() - header_offset);
+        if pos.record() - header_offset >= last + every as u64 {
+            pos_table.push(pos);
+        }
     }
 }
 
+/// Profile of the first records from `start`
+fn sample_profile(config: Arc<CsvConfig>, start: Position) -> Profiler {
+    let mut profiler = Profiler::new(config.value_format());
+    if let Ok(mut scanner) = RecordScanner::new_at(config, start) {
+        while profiler.num_records() < profile::SAMPLE_SIZE {
+            match scanner.next() {
+                ScanResult::Record(_, record) => profiler.add(&record),
+                _ => break,
+            }
+        }
+    }
+    profiler
+}
+
+fn save_index(
+    config: &CsvConfig,
+    m_state: &Mutex<ReaderInternalState>,
+    pos_table_update_every: usize,
+    num_records: usize,
+    end: Position,
+    profile: &Profiler,
+) {
+    let index = SavedIndex {
+        pos_table: m_state.lock().unwrap().pos_table.clone(),
+        pos_table_update_every,
+        num_records,
+        end,
+        profile: profile.clone(),
+    };
+    // Not being able to save only means indexing again next time
+    let _ = index_file::save(config, &index);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::ColumnType;
+    use std::io::{Seek, Write};
+
+    fn test_config(filename: &str) -> Arc<CsvConfig> {
+        Arc::new(CsvConfig::new(filename, b',', false))
+    }
 
     #[test]
     fn test_cities_get_rows() {
-        let mut r = CsvLensReader::new("tests/data/cities.csv").unwrap();
+        let mut r = CsvLensReader::new(test_config("tests/data/cities.csv")).unwrap();
         r.wait_internal();
         let rows = r.get_rows(2, 3).unwrap();
         let expected = vec![
@@ -321,7 +1103,7 @@ mod tests {
 
     #[test]
     fn test_simple_get_rows() {
-        let mut r = CsvLensReader::new("tests/data/simple.csv").unwrap();
+        let mut r = CsvLensReader::new(test_config("tests/data/simple.csv")).unwrap();
         r.wait_internal();
         let rows = r.get_rows(1234, 2).unwrap();
         let expected = vec![
@@ -333,7 +1115,7 @@ mod tests {
 
     #[test]
     fn test_simple_get_rows_out_of_bound() {
-        let mut r = CsvLensReader::new("tests/data/simple.csv").unwrap();
+        let mut r = CsvLensReader::new(test_config("tests/data/simple.csv")).unwrap();
         r.wait_internal();
         let indices = vec![5000];
         let (rows, _stats) = r.get_rows_impl(&indices).unwrap();
@@ -342,7 +1124,7 @@ mod tests {
 
     #[test]
     fn test_simple_get_rows_impl_1() {
-        let mut r = CsvLensReader::new("tests/data/simple.csv").unwrap();
+        let mut r = CsvLensReader::new(test_config("tests/data/simple.csv")).unwrap();
         r.wait_internal();
         let indices = vec![1, 3, 5, 1234, 2345, 3456, 4999];
         let (rows, stats) = r.get_rows_impl(&indices).unwrap();
@@ -357,22 +1139,22 @@ mod tests {
         ];
         assert_eq!(rows, expected);
         let expected = GetRowsStats {
-            num_seek: 49,
-            num_parsed_record: 505,
+            num_seek: 4,
+            num_parsed_record: 244,
         };
         assert_eq!(stats, expected);
     }
 
     #[test]
     fn test_simple_get_rows_impl_2() {
-        let mut r = CsvLensReader::new("tests/data/simple.csv").unwrap();
+        let mut r = CsvLensReader::new(test_config("tests/data/simple.csv")).unwrap();
         r.wait_internal();
         let indices = vec![1234];
         let (rows, stats) = r.get_rows_impl(&indices).unwrap();
         let expected = vec![Row::new(1235, vec!["A1235", "B1235"])];
         assert_eq!(rows, expected);
         let expected = GetRowsStats {
-            num_seek: 12,
+            num_seek: 1,
             num_parsed_record: 35,
         };
         assert_eq!(stats, expected);
@@ -380,7 +1162,7 @@ mod tests {
 
     #[test]
     fn test_simple_get_rows_impl_3() {
-        let mut r = CsvLensReader::new("tests/data/simple.csv").unwrap();
+        let mut r = CsvLensReader::new(test_config("tests/data/simple.csv")).unwrap();
         r.wait_internal();
         let indices = vec![2];
         let (rows, stats) = r.get_rows_impl(&indices).unwrap();
@@ -388,14 +1170,92 @@ mod tests {
         assert_eq!(rows, expected);
         let expected = GetRowsStats {
             num_seek: 0,
-            num_parsed_record: 4, // 3 + 1 (including header)
+            num_parsed_record: 3, // headers had already been read
         };
         assert_eq!(stats, expected);
     }
 
+    #[test]
+    fn test_scroll_up_in_large_segment() {
+        let mut r = CsvLensReader::new(test_config("tests/data/simple.csv")).unwrap();
+        r.wait_internal();
+        // A segment of all rows, as in files with many millions of rows
+        let segment_start = (0, r.first_row_pos.clone());
+        let mut stats = GetRowsStats::new();
+        for from in [1500, 549] {
+            let indices: Vec<u64> = (from..from + 50).collect();
+            let rows = r
+                .get_rows_in_segment(&indices, segment_start.clone(), &mut stats)
+                .unwrap();
+            let expected: Vec<Row> = (from + 1..from + 51)
+                .map(|n| Row {
+                    record_num: n as usize,
+                    fields: vec![format!("A{}", n), format!("B{}", n)],
+                })
+                .collect();
+            assert_eq!(rows, expected);
+        }
+    }
+
+    #[test]
+    fn test_simple_get_rows_for_unsorted_indices() {
+        let mut r = CsvLensReader::new(test_config("tests/data/simple.csv")).unwrap();
+        r.wait_internal();
+        let rows = r.get_rows_for_indices(&[4000, 2, 1234, 3, 2]).unwrap();
+        let record_nums: Vec<usize> = rows.iter().map(|x| x.record_num).collect();
+        assert_eq!(record_nums, vec![4001, 3, 1235, 4, 3]);
+        assert_eq!(rows[2], Row::new(1235, vec!["A1235", "B1235"]));
+    }
+
+    fn get_rows_stats(r: &mut CsvLensReader, rows_from: u64, num_rows: u64) -> GetRowsStats {
+        let indices: Vec<u64> = (rows_from..rows_from + num_rows).collect();
+        let (rows, stats) = r.get_rows_impl(&indices).unwrap();
+        let record_nums: Vec<usize> = rows.iter().map(|x| x.record_num).collect();
+        let expected: Vec<usize> =
+            (rows_from as usize + 1..=(rows_from + num_rows) as usize).collect();
+        assert_eq!(record_nums, expected);
+        stats
+    }
+
+    fn stats(num_seek: u64, num_parsed_record: u64) -> GetRowsStats {
+        GetRowsStats {
+            num_seek,
+            num_parsed_record,
+        }
+    }
+
+    #[test]
+    fn test_simple_get_rows_scrolling() {
+        let mut r = CsvLensReader::new(test_config("tests/data/simple.csv")).unwrap();
+        r.wait_internal();
+        assert_eq!(get_rows_stats(&mut r, 1010, 50), stats(1, 60));
+        // scrolling down continues from the reader's position
+        assert_eq!(get_rows_stats(&mut r, 1011, 50), stats(0, 1));
+        assert_eq!(get_rows_stats(&mut r, 1061, 50), stats(0, 50));
+        // scrolling back up is cached, including rows before those shown
+        assert_eq!(get_rows_stats(&mut r, 1000, 50), stats(0, 0));
+        // crossing into the previous segment
+        assert_eq!(get_rows_stats(&mut r, 999, 50), stats(1, 100));
+        assert_eq!(get_rows_stats(&mut r, 950, 50), stats(0, 0));
+        // jumping elsewhere and back
+        assert_eq!(get_rows_stats(&mut r, 4000, 50), stats(1, 50));
+        assert_eq!(get_rows_stats(&mut r, 1020, 50), stats(0, 0));
+    }
+
+    #[test]
+    fn test_simple_get_rows_scrolling_up() {
+        let mut r = CsvLensReader::new(test_config("tests/data/simple.csv")).unwrap();
+        r.wait_internal();
+        assert_eq!(get_rows_stats(&mut r, 4990, 10), stats(1, 100));
+        for i in (4900..4990).rev() {
+            assert_eq!(get_rows_stats(&mut r, i, 10), stats(0, 0));
+        }
+        assert_eq!(get_rows_stats(&mut r, 4899, 10), stats(1, 100));
+    }
+
     #[test]
     fn test_small() {
-        let mut r = CsvLensReader::new("tests/data/small.csv").unwrap();
+        let mut r = CsvLensReader::new(test_config("tests/data/small.csv")).unwrap();
         r.wait_internal();
         let rows = r.get_rows(0, 50).unwrap();
         let expected = vec![
@@ -404,4 +1264,191 @@ mod tests {
         ];
         assert_eq!(rows, expected);
     }
+
+    #[test]
+    fn test_cities_tsv_get_rows() {
+        let config = Arc::new(CsvConfig::new("tests/data/cities.tsv", b'\t', false));
+        let mut r = CsvLensReader::new(config).unwrap();
+        r.wait_internal();
+        assert_eq!(r.headers.len(), 10);
+        let rows = r.get_rows(2, 1).unwrap();
+        let expected = vec![Row::new(
+            3,
+            vec![
+                "46", "35", "59", "N", "120", "30", "36", "W", "Yakima", "WA",
+            ],
+        )];
+        assert_eq!(rows, expected);
+        assert_eq!(r.get_total_line_numbers(), Some(128));
+    }
+
+    #[test]
+    fn test_small_no_headers() {
+        let config = Arc::new(CsvConfig::new("tests/data/small.csv", b',', true));
+        let mut r = CsvLensReader::new(config).unwrap();
+        r.wait_internal();
+        assert_eq!(r.headers, vec!["1", "2"]);
+        let rows = r.get_rows(0, 50).unwrap();
+        let expected = vec![
+            Row::new(1, vec!["COL1", " COL2"]),
+            Row::new(2, vec!["c1", " v1"]),
+            Row::new(3, vec!["c2", " v2"]),
+        ];
+        assert_eq!(rows, expected);
+        let rows = r.get_rows_for_indices(&[2]).unwrap();
+        assert_eq!(rows, vec![Row::new(3, vec!["c2", " v2"])]);
+        assert_eq!(r.get_total_line_numbers(), Some(3));
+    }
+
+    #[test]
+    fn test_simple_get_rows_no_headers() {
+        let config = Arc::new(CsvConfig::new("tests/data/simple.csv", b',', true));
+        let mut r = CsvLensReader::new(config).unwrap();
+        r.wait_internal();
+        let indices = vec![0, 1234, 5000];
+        let rows = r.get_rows_for_indices(&indices).unwrap();
+        let expected = vec![
+            Row::new(1, vec!["a", "b"]),
+            Row::new(1235, vec!["A1234", "B1234"]),
+            Row::new(5001, vec!["A5000", "B5000"]),
+        ];
+        assert_eq!(rows, expected);
+        assert_eq!(r.get_total_line_numbers(), Some(5001));
+    }
+
+    #[test]
+    fn test_persistent_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("simple.csv");
+        fs::copy("tests/data/simple.csv", &filename).unwrap();
+        let filename = filename.to_str().unwrap();
+        let config = || {
+            let mut config = CsvConfig::new(filename, b',', false);
+            config.set_persistent_index(true);
+            Arc::new(config)
+        };
+
+        let r = CsvLensReader::new(config()).unwrap();
+        r.wait_internal();
+        let pos_table = r.get_pos_table();
+        let profile = r.get_column_profile();
+        assert_eq!(profile.types, vec![ColumnType::Text, ColumnType::Text]);
+        assert_eq!(profile.widths, vec![5, 5]);
+
+        // Loaded right away on reopen
+        let mut r = CsvLensReader::new(config()).unwrap();
+        assert_eq!(r.get_total_line_numbers(), Some(5000));
+        assert_eq!(r.get_pos_table(), pos_table);
+        assert_eq!(r.get_column_profile(), profile);
+        let rows = r.get_rows(1234, 1).unwrap();
+        assert_eq!(rows, vec![Row::new(1235, vec!["A1235", "B1235"])]);
+
+        // Extended after appending
+        let mut file = fs::OpenOptions::new().append(true).open(filename).unwrap();
+        for i in 5001..=5100 {
+            writeln!(file, "A{},B{}", i, i).unwrap();
+        }
+        let mut r = CsvLensReader::new(config()).unwrap();
+        r.wait_internal();
+        assert_eq!(r.get_total_line_numbers(), Some(5100));
+        let rebuilt = CsvLensReader::new(test_config(filename)).unwrap();
+        rebuilt.wait_internal();
+        assert_eq!(r.get_pos_table(), rebuilt.get_pos_table());
+        assert_eq!(r.get_column_profile(), rebuilt.get_column_profile());
+        let rows = r.get_rows(5099, 1).unwrap();
+        assert_eq!(rows, vec![Row::new(5100, vec!["A5100", "B5100"])]);
+    }
+
+    #[test]
+    fn test_index_error() {
+        let (m_state, handle) =
+            ReaderInternalState::init_internal(test_config("tests/data/missing.csv"));
+        handle.join().unwrap();
+        let m = m_state.lock().unwrap();
+        assert!(m.done);
+        assert!(m.progress.is_none());
+        assert!(m.error.is_some());
+    }
+
+    #[test]
+    fn test_scanner_growing_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"a,b\n1,2\n3,").unwrap();
+
+        let stream_status = StreamStatus::new();
+        let mut config = CsvConfig::new(file.path().to_str().unwrap(), b',', false);
+        config.set_stream_status(Some(stream_status.clone()));
+        let mut scanner = RecordScanner::new(Arc::new(config)).unwrap();
+
+        let mut next_record = || match scanner.next() {
+            ScanResult::Record(pos, r) => {
+                let fields: Vec<String> = r
+                    .iter()
+                    .map(|x| String::from_utf8_lossy(x).to_string())
+                    .collect();
+                Some((pos.record(), fields.join(",")))
+            }
+            ScanResult::Pending => Some((0, "pending".to_owned())),
+            ScanResult::Reset | ScanResult::Done => None,
+        };
+
+        assert_eq!(next_record(), Some((1, "1,2".to_owned())));
+        // "3," might still be incomplete
+        assert_eq!(next_record(), Some((0, "pending".to_owned())));
+
+        file.write_all(b"4\n5,6\n7").unwrap();
+        assert_eq!(next_record(), Some((2, "3,4".to_owned())));
+        assert_eq!(next_record(), Some((3, "5,6".to_owned())));
+        assert_eq!(next_record(), Some((0, "pending".to_owned())));
+
+        file.write_all(b",8\n").unwrap();
+        assert_eq!(next_record(), Some((4, "7,8".to_owned())));
+        assert_eq!(next_record(), Some((0, "pending".to_owned())));
+
+        stream_status.set_done();
+        assert_eq!(next_record(), None);
+    }
+
+    #[test]
+    fn test_scanner_follow_truncated() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"a,b\n1,2\n3,4\n").unwrap();
+
+        let mut config = CsvConfig::new(file.path().to_str().unwrap(), b',', false);
+        config.set_follow(true);
+        let mut scanner = RecordScanner::new(Arc::new(config)).unwrap();
+
+        assert!(matches!(scanner.next(), ScanResult::Record(_, _)));
+        assert!(matches!(scanner.next(), ScanResult::Record(_, _)));
+        assert!(matches!(scanner.next(), ScanResult::Pending));
+
+        file.as_file().set_len(0).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(b"a,b\n5,6\n7,8\n").unwrap();
+        // as large as before, but the start of the file changed
+        assert!(matches!(scanner.next(), ScanResult::Reset));
+        match scanner.next() {
+            ScanResult::Record(_, r) => assert_eq!(r.get(0), Some("5".as_bytes())),
+            _ => panic!("expected a record"),
+        }
+        assert!(matches!(scanner.next(), ScanResult::Record(_, _)));
+        assert!(matches!(scanner.next(), ScanResult::Pending));
+
+        // appended to, not rewritten
+        file.write_all(b"9,0\n").unwrap();
+        assert!(matches!(scanner.next(), ScanResult::Record(_, _)));
+        assert!(matches!(scanner.next(), ScanResult::Pending));
+
+        file.as_file().set_len(0).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(b"x\n9\n").unwrap();
+        assert!(matches!(scanner.next(), ScanResult::Reset));
+        match scanner.next() {
+            ScanResult::Record(pos, r) => {
+                assert_eq!(pos.record(), 1);
+                assert_eq!(r.get(0), Some("9".as_bytes()));
+            }
+            _ => panic!("expected a record"),
+        }
+    }
 }
@@ -0,0 +1,157 @@
+use anyhow::{bail, Result};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Delimiters considered when sniffing, in order of preference for ties
+const CANDIDATES: [u8; 4] = [b',', b'\t', b';', b'|'];
+
+/// Number of bytes read from the start of the file for sniffing
+const SNIFF_SIZE: u64 = 8192;
+
+/// Delimiter as requested on the command line
+#[derive(Debug, PartialEq)]
+pub enum Delimiter {
+    /// Guess from the file extension and content
+    Auto,
+    Character(u8),
+}
+
+impl Delimiter {
+    pub fn from_arg(arg: &Option<String>) -> Result<Delimiter> {
+        let s = match arg {
+            Some(s) => s.as_str(),
+            None => return Ok(Delimiter::Auto),
+        };
+        match s {
+            "auto" => Ok(Delimiter::Auto),
+            "\\t" | "tab" => Ok(Delimiter::Character(b'\t')),
+            _ => {
+                let bytes = s.as_bytes();
+                if bytes.len() != 1 {
+                    bail!(
+                        "Delimiter should be a single ASCII character, \"tab\" or \"auto\": {}",
+                        s
+                    );
+                }
+                Ok(Delimiter::Character(bytes[0]))
+            }
+        }
+    }
+
+    /// Resolve to the actual delimiter byte. `display_name` is the name given
+    /// by the user, which may differ from `path` (e.g. for piped input).
+    pub fn resolve(&self, path: &str, display_name: &str) -> u8 {
+        match self {
+            Delimiter::Character(c) => *c,
+            Delimiter::Auto => {
+                if let Some(c) = delimiter_from_extension(display_name) {
+                    return c;
+                }
+                match read_head(path) {
+                    Ok(head) => sniff_delimiter(&head),
+                    Err(_) => b',',
+                }
+            }
+        }
+    }
+}
+
+fn delimiter_from_extension(filename: &str) -> Option<u8> {
+    let extension = Path::new(filename).extension()?.to_str()?;
+    match extension.to_lowercase().as_str() {
+        "tsv" | "tab" => Some(b'\t'),
+        "psv" => Some(b'|'),
+        _ => None,
+    }
+}
+
+fn read_head(path: &str) -> Result<Vec<u8>> {
+    let file = File::open(path)?;
+    let mut head = vec![];
+    file.take(SNIFF_SIZE).read_to_end(&mut head)?;
+    Ok(head)
+}
+
+/// Guess the delimiter from the first bytes of a file. The candidate that
+/// appears the same non-zero number of times on the most lines wins.
+pub fn sniff_delimiter(head: &[u8]) -> u8 {
+    // Drop the last line since it is likely truncated
+    let complete = match head.iter().rposition(|&b| b == b'\n') {
+        Some(i) => &head[..i],
+        None => head,
+    };
+    let lines: Vec<&[u8]> = complete
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    let mut best = b',';
+    let mut best_score = 0;
+    for &candidate in CANDIDATES.iter() {
+        let counts: Vec<usize> = lines
+            .iter()
+            .map(|line| count_unquoted(line, candidate))
+            .collect();
+        let first = match counts.first() {
+            Some(&n) if n > 0 => n,
+            _ => continue,
+        };
+        let score = counts.iter().take_while(|&&n| n == first).count();
+        if score > best_score {
+            best = candidate;
+            best_score = score;
+        }
+    }
+    best
+}
+
+fn count_unquoted(line: &[u8], delimiter: u8) -> usize {
+    let mut in_quotes = false;
+    let mut count = 0;
+    for &b in line {
+        if b == b'"' {
+            in_quotes = !in_quotes;
+        } else if b == delimiter && !in_quotes {
+            count += 1;
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_arg() {
+        assert_eq!(Delimiter::from_arg(&None).unwrap(), Delimiter::Auto);
+        assert_eq!(
+            Delimiter::from_arg(&Some("\\t".to_owned())).unwrap(),
+            Delimiter::Character(b'\t')
+        );
+        assert_eq!(
+            Delimiter::from_arg(&Some(";".to_owned())).unwrap(),
+            Delimiter::Character(b';')
+        );
+        assert!(Delimiter::from_arg(&Some(";;".to_owned())).is_err());
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff_delimiter(b"a,b\n1,2\n3,4\n"), b',');
+        assert_eq!(sniff_delimiter(b"a\tb\tc\n1\t2\t3\n"), b'\t');
+        assert_eq!(sniff_delimiter(b"a;b\n\"1,5\";2\n\"3,5\";4\n"), b';');
+        assert_eq!(sniff_delimiter(b"a|b\n1|2\n3|"), b'|');
+        assert_eq!(sniff_delimiter(b"single column\nvalue\n"), b',');
+    }
+
+    #[test]
+    fn test_resolve() {
+        let d = Delimiter::Auto;
+        assert_eq!(d.resolve("tests/data/cities.csv", "cities.csv"), b',');
+        assert_eq!(d.resolve("tests/data/cities.tsv", "cities.tsv"), b'\t');
+        assert_eq!(d.resolve("tests/data/cities.tsv", "/dev/fd/63"), b'\t');
+        assert_eq!(d.resolve("tests/data/semicolon.csv", "semicolon.csv"), b';');
+    }
+}
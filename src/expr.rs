@@ -0,0 +1,677 @@
+use crate::matcher::{build_regex, find_column, CaseMode, MatchOptions};
+
+use anyhow::{bail, Result};
+use csv::StringRecord;
+use regex::Regex;
+use std::cmp::Ordering;
+
+/// Filter expression such as `Population > 100000 AND State != TX`, evaluated
+/// against whole records:
+///
+/// ```text
+/// expr       := and ("OR" and)*
+/// and        := not ("AND" not)*
+/// not        := "NOT" not | "(" expr ")" | comparison
+/// comparison := column op value
+///             | column "IS" ["NOT"] "EMPTY"
+///             | column ["NOT"] "IN" "(" value ("," value)* ")"
+/// op         := "=" | "!=" | "<" | "<=" | ">" | ">=" | "~" | "!~"
+/// ```
+///
+/// Columns are names, double quoted names or `#N`. Values are bare words or
+/// double quoted strings. Keywords are case insensitive.
+#[derive(Clone, Debug)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare {
+        column: usize,
+        op: CompareOp,
+        value: Value,
+    },
+    IsEmpty(usize),
+    In {
+        column: usize,
+        values: Vec<Value>,
+    },
+    Matches {
+        column: usize,
+        regex: Regex,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CompareOp {
+    fn holds(&self, ordering: Ordering) -> bool {
+        match self {
+            CompareOp::Eq => ordering == Ordering::Equal,
+            CompareOp::Ne => ordering != Ordering::Equal,
+            CompareOp::Lt => ordering == Ordering::Less,
+            CompareOp::Le => ordering != Ordering::Greater,
+            CompareOp::Gt => ordering == Ordering::Greater,
+            CompareOp::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+/// Value to compare fields with. A field is compared as a number if both
+/// sides are numbers, else as a date if both sides are dates, else as text.
+/// Fields that aren't numbers or dates are only equal or not to values that
+/// are, so that blank or `N/A` fields don't match `Population < 1000000`.
+#[derive(Clone, Debug)]
+pub struct Value {
+    text: String,
+    number: Option<f64>,
+    date: Option<Date>,
+    ignore_case: bool,
+}
+
+impl Value {
+    fn new(text: &str, case: CaseMode) -> Value {
+        let ignore_case = match case {
+            CaseMode::Sensitive => false,
+            CaseMode::Insensitive => true,
+            CaseMode::Smart => !text.chars().any(char::is_uppercase),
+        };
+        Value {
+            text: if ignore_case {
+                text.to_lowercase()
+            } else {
+                text.to_owned()
+            },
+            number: parse_number(text),
+            date: parse_date(text),
+            ignore_case,
+        }
+    }
+
+    fn compare(&self, field: &str, op: CompareOp) -> Option<Ordering> {
+        if let Some(n) = self.number {
+            if let Some(f) = parse_number(field) {
+                return f.partial_cmp(&n);
+            }
+        }
+        if let Some(d) = self.date {
+            if let Some(f) = parse_date(field) {
+                return Some(f.cmp(&d));
+            }
+        }
+        let is_ordering = !matches!(op, CompareOp::Eq | CompareOp::Ne);
+        if is_ordering && (self.number.is_some() || self.date.is_some()) {
+            return None;
+        }
+        if self.ignore_case {
+            Some(field.to_lowercase().as_str().cmp(self.text.as_str()))
+        } else {
+            Some(field.cmp(self.text.as_str()))
+        }
+    }
+}
+
+/// Year, month, day, hour, minute, second
+pub(crate) type Date = (u32, u32, u32, u32, u32, u32);
+
+pub(crate) fn parse_number(s: &str) -> Option<f64> {
+    s.trim().parse::<f64>().ok().filter(|n| !n.is_nan())
+}
+
+/// Parse dates like `2022-01-31`, `2022/01/31` and `2022-01-31 12:30:00`
+pub(crate) fn parse_date(s: &str) -> Option<Date> {
+    let s = s.trim();
+    let (date, time) = match s.find(['T', ' ']) {
+        Some(i) => (&s[..i], Some(s[i + 1..].trim_start())),
+        None => (s, None),
+    };
+    let parts: Vec<&str> = date.split(['-', '/']).collect();
+    if parts.len() != 3 || parts[0].len() != 4 {
+        return None;
+    }
+    let parse = |s: &str, max: u32| s.parse::<u32>().ok().filter(|&n| n <= max && s.len() <= 4);
+    let year = parse(parts[0], 9999)?;
+    let month = parse(parts[1], 12).filter(|&n| n > 0)?;
+    let day = parse(parts[2], 31).filter(|&n| n > 0)?;
+    let (mut hour, mut minute, mut second) = (0, 0, 0);
+    if let Some(time) = time {
+        let parts: Vec<&str> = time.split(':').collect();
+        if parts.len() < 2 || parts.len() > 3 {
+            return None;
+        }
+        hour = parse(parts[0], 23)?;
+        minute = parse(parts[1], 59)?;
+        if let Some(s) = parts.get(2) {
+            // Ignore fractions of a second
+            second = parse(s.split('.').next().unwrap_or(s), 60)?;
+        }
+    }
+    Some((year, month, day, hour, minute, second))
+}
+
+impl Expr {
+    /// Parse `query` as an expression if it looks like one: it starts with a
+    /// known column, possibly after `NOT` or `(`, and uses something beyond the simple
+    /// `name=value` and `name~pattern` queries, such as other comparisons,
+    /// keywords or parentheses. Returns `Ok(None)` otherwise, so that the
+    /// query can be taken as a pattern instead, unless it is an expression
+    /// but for an unknown column.
+    pub fn parse(query: &str, options: MatchOptions, headers: &[String]) -> Result<Option<Expr>> {
+        let (tokens, lex_error) = tokenize(query);
+        if !looks_like_expression(&tokens, headers)? {
+            return Ok(None);
+        }
+        if let Some(e) = lex_error {
+            bail!(e);
+        }
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            options,
+            headers,
+        };
+        let expr = parser.parse_or()?;
+        if let Some(token) = parser.peek() {
+            bail!("Unexpected {} in expression", token);
+        }
+        Ok(Some(expr))
+    }
+
+    pub fn is_match(&self, record: &StringRecord) -> bool {
+        let field = |column: &usize| record.get(*column).unwrap_or("");
+        match self {
+            Expr::And(a, b) => a.is_match(record) && b.is_match(record),
+            Expr::Or(a, b) => a.is_match(record) || b.is_match(record),
+            Expr::Not(e) => !e.is_match(record),
+            Expr::Compare { column, op, value } => value
+                .compare(field(column), *op)
+                .is_some_and(|ordering| op.holds(ordering)),
+            Expr::IsEmpty(column) => field(column).trim().is_empty(),
+            Expr::In { column, values } => values
+                .iter()
+                .any(|v| v.compare(field(column), CompareOp::Eq) == Some(Ordering::Equal)),
+            Expr::Matches { column, regex } => regex.is_match(field(column)),
+        }
+    }
+
+    /// Indices of the columns referred to, in order and without duplicates
+    pub fn columns(&self) -> Vec<usize> {
+        let mut columns = vec![];
+        self.collect_columns(&mut columns);
+        columns.sort_unstable();
+        columns.dedup();
+        columns
+    }
+
+    fn collect_columns(&self, columns: &mut Vec<usize>) {
+        match self {
+            Expr::And(a, b) | Expr::Or(a, b) => {
+                a.collect_columns(columns);
+                b.collect_columns(columns);
+            }
+            Expr::Not(e) => e.collect_columns(columns),
+            Expr::Compare { column, .. }
+            | Expr::IsEmpty(column)
+            | Expr::In { column, .. }
+            | Expr::Matches { column, .. } => columns.push(*column),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    ColumnNumber(usize),
+    Op(&'static str),
+    LParen,
+    RParen,
+    Comma,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(s) => write!(f, "{}", s),
+            Token::Quoted(s) => write!(f, "\"{}\"", s),
+            Token::ColumnNumber(n) => write!(f, "#{}", n),
+            Token::Op(op) => write!(f, "{}", op),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::Comma => write!(f, ","),
+        }
+    }
+}
+
+impl Token {
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self, Token::Word(s) if s.eq_ignore_ascii_case(keyword))
+    }
+}
+
+const OPERATORS: [&str; 10] = ["==", "!=", "<>", "<=", ">=", "!~", "=", "<", ">", "~"];
+
+/// Split the query into tokens, stopping at the first error
+fn tokenize(query: &str) -> (Vec<Token>, Option<String>) {
+    let mut tokens = vec![];
+    let mut rest = query.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let token = match c {
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            ',' => Token::Comma,
+            '"' => {
+                let mut s = String::new();
+                let mut chars = rest[1..].char_indices();
+                let mut end = None;
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            if let Some((_, c)) = chars.next() {
+                                s.push(c);
+                            }
+                        }
+                        '"' => {
+                            end = Some(i + 2);
+                            break;
+                        }
+                        c => s.push(c),
+                    }
+                }
+                match end {
+                    Some(end) => {
+                        tokens.push(Token::Quoted(s));
+                        rest = rest[end..].trim_start();
+                        continue;
+                    }
+                    None => {
+                        tokens.push(Token::Quoted(s));
+                        return (tokens, Some("Unterminated quote in expression".to_owned()));
+                    }
+                }
+            }
+            _ => {
+                if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                    rest = rest[op.len()..].trim_start();
+                    tokens.push(Token::Op(op));
+                    continue;
+                }
+                // A lone `!` starts a word too
+                let end = rest[c.len_utf8()..]
+                    .find(|c: char| c.is_whitespace() || "()=!<>~,\"".contains(c))
+                    .map_or(rest.len(), |i| i + c.len_utf8());
+                let word = &rest[..end];
+                rest = rest[end..].trim_start();
+                let number = word.strip_prefix('#').and_then(|n| n.parse::<usize>().ok());
+                tokens.push(match number {
+                    Some(n) => Token::ColumnNumber(n),
+                    None => Token::Word(word.to_owned()),
+                });
+                continue;
+            }
+        };
+        tokens.push(token);
+        rest = rest[1..].trim_start();
+    }
+    (tokens, None)
+}
+
+fn looks_like_expression(tokens: &[Token], headers: &[String]) -> Result<bool> {
+    let mut rest = tokens
+        .iter()
+        .skip_while(|t| **t == Token::LParen || t.is_keyword("not"));
+    let first_column = rest.next();
+    let (starts_with_column, unquoted_name) = match first_column {
+        Some(Token::Word(s)) => (find_column(s, headers).is_some(), true),
+        Some(Token::Quoted(s)) => (find_column(s, headers).is_some(), false),
+        Some(Token::ColumnNumber(_)) => (true, false),
+        _ => (false, false),
+    };
+    // Plain `name=value` and `name~pattern` are column scoped patterns instead
+    let has_expression_syntax = tokens.iter().any(|t| match t {
+        Token::Op(op) => !unquoted_name || !matches!(*op, "=" | "~"),
+        Token::Quoted(_) | Token::LParen | Token::RParen => true,
+        t => ["and", "or", "not", "is", "in"]
+            .iter()
+            .any(|k| t.is_keyword(k)),
+    });
+    if !starts_with_column {
+        // A misspelled column shouldn't quietly search for the whole query.
+        // Only comparisons right after the first word and capitalized AND and
+        // OR count, so that searches like `(a|b)` or `rock and roll` still work.
+        let compares = match rest.next() {
+            Some(Token::Op(op)) => !matches!(*op, "=" | "~"),
+            Some(t) => ["is", "in", "not"].iter().any(|k| t.is_keyword(k)),
+            None => false,
+        };
+        let has_logic = tokens
+            .iter()
+            .any(|t| matches!(t, Token::Word(s) if s == "AND" || s == "OR"));
+        match first_column {
+            Some(Token::Word(s)) | Some(Token::Quoted(s)) if compares || has_logic => {
+                bail!("Unknown column: {}", s)
+            }
+            _ => return Ok(false),
+        }
+    }
+    Ok(has_expression_syntax)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    options: MatchOptions,
+    headers: &'a [String],
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.peek().is_some_and(|t| t.is_keyword(keyword)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        match self.next() {
+            Some(t) if t == expected => Ok(()),
+            Some(t) => bail!("Expected {} but found {}", expected, t),
+            None => bail!("Expected {} at end of expression", expected),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_and()?;
+        while self.eat_keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_not()?;
+        while self.eat_keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr> {
+        if self.eat_keyword("not") {
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            let expr = self.parse_or()?;
+            self.expect(Token::RParen)?;
+            return Ok(expr);
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_column(&mut self) -> Result<usize> {
+        match self.next() {
+            Some(Token::ColumnNumber(n)) => {
+                if n == 0 || n > self.headers.len() {
+                    bail!(
+                        "No column #{}, columns are #1 to #{}",
+                        n,
+                        self.headers.len()
+                    );
+                }
+                Ok(n - 1)
+            }
+            Some(Token::Word(s)) | Some(Token::Quoted(s)) => match find_column(&s, self.headers) {
+                Some(index) => Ok(index),
+                None => bail!("Unknown column: {}", s),
+            },
+            Some(t) => bail!("Expected a column but found {}", t),
+            None => bail!("Expected a column at end of expression"),
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Word(s)) | Some(Token::Quoted(s)) => Ok(s),
+            Some(Token::ColumnNumber(n)) => Ok(format!("#{}", n)),
+            Some(t) => bail!("Expected a value but found {}", t),
+            None => bail!("Expected a value at end of expression"),
+        }
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr> {
+        let column = self.parse_column()?;
+        if self.eat_keyword("is") {
+            let negated = self.eat_keyword("not");
+            if !self.eat_keyword("empty") {
+                bail!("Expected EMPTY after IS");
+            }
+            let expr = Expr::IsEmpty(column);
+            return Ok(if negated {
+                Expr::Not(Box::new(expr))
+            } else {
+                expr
+            });
+        }
+        let negated = self.eat_keyword("not");
+        if self.eat_keyword("in") {
+            self.expect(Token::LParen)?;
+            let mut values = vec![];
+            loop {
+                values.push(Value::new(&self.parse_value()?, self.options.case));
+                if self.peek() == Some(&Token::Comma) {
+                    self.pos += 1;
+                } else {
+                    break;
+                }
+            }
+            self.expect(Token::RParen)?;
+            let expr = Expr::In { column, values };
+            return Ok(if negated {
+                Expr::Not(Box::new(expr))
+            } else {
+                expr
+            });
+        }
+        if negated {
+            bail!("Expected IN after NOT");
+        }
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            Some(t) => bail!("Expected a comparison but found {}", t),
+            None => bail!("Expected a comparison at end of expression"),
+        };
+        let value = self.parse_value()?;
+        let op = match op {
+            "=" | "==" => CompareOp::Eq,
+            "!=" | "<>" => CompareOp::Ne,
+            "<" => CompareOp::Lt,
+            "<=" => CompareOp::Le,
+            ">" => CompareOp::Gt,
+            ">=" => CompareOp::Ge,
+            _ => {
+                let expr = Expr::Matches {
+                    column,
+                    regex: build_regex(&value, self.options, false)?,
+                };
+                return Ok(if op == "!~" {
+                    Expr::Not(Box::new(expr))
+                } else {
+                    expr
+                });
+            }
+        };
+        Ok(Expr::Compare {
+            column,
+            op,
+            value: Value::new(&value, self.options.case),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers() -> Vec<String> {
+        vec!["City", "State", "Population", "Founded", "Zip Code"]
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    fn parse(query: &str) -> Result<Option<Expr>> {
+        Expr::parse(query, MatchOptions::default(), &headers())
+    }
+
+    fn is_match(query: &str, fields: &[&str]) -> bool {
+        let expr = parse(query).unwrap().unwrap();
+        expr.is_match(&StringRecord::from(fields.to_vec()))
+    }
+
+    #[test]
+    fn test_not_expression() {
+        for query in [
+            "foo",
+            "State=CA",
+            "city~york",
+            "rock and roll",
+            "(a|b)",
+            "#1 hit",
+            "City ! x",
+        ] {
+            assert!(parse(query).unwrap().is_none(), "{}", query);
+        }
+        // Expressions with a misspelled column
+        for (query, column) in [
+            ("a > b", "a"),
+            ("x AND y", "x"),
+            ("Popluation > 100000 AND State != TX", "Popluation"),
+            ("(Sate IN (CA, TX))", "Sate"),
+            ("State = CA OR Cty = Austin", "Cty"),
+        ] {
+            let error = parse(query).err().map(|e| e.to_string());
+            assert_eq!(
+                error.as_deref(),
+                Some(format!("Unknown column: {}", column).as_str()),
+                "{}",
+                query
+            );
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let row = ["Austin", "TX", "961855", "1839-12-27", "73301"];
+        assert!(is_match("Population > 100000", &row));
+        assert!(!is_match("Population > 100000 AND State != TX", &row));
+        assert!(is_match("Population >= 961855.0 and state = TX", &row));
+        // Numbers compare as numbers, not as text
+        assert!(is_match("Population < 1000000", &row));
+        assert!(is_match("Founded < 1900-01-01", &row));
+        assert!(is_match("Founded >= \"1839-12-27 00:00\"", &row));
+        assert!(is_match("City < Boston", &row));
+        assert!(is_match("\"Zip Code\" = 73301", &row));
+        assert!(is_match("#1 ~ ^Au AND #2 !~ A", &row));
+
+        // Fields that aren't numbers or dates are neither less nor greater
+        for population in ["", "N/A"] {
+            let row = ["Austin", "TX", population, "", "73301"];
+            assert!(!is_match("Population < 1000000", &row));
+            assert!(!is_match("Population > 100000", &row));
+            assert!(!is_match("Founded <= 1900-01-01", &row));
+            assert!(is_match("Population != 1000000", &row));
+            assert!(!is_match("Population = \"1000000\"", &row));
+        }
+        assert!(is_match(
+            "Population IN (N/A, NA)",
+            &["", "", "N/A", "", ""]
+        ));
+    }
+
+    #[test]
+    fn test_logic() {
+        let row = ["Austin", "TX", "", "1839", "73301"];
+        assert!(is_match("Population is empty", &row));
+        assert!(!is_match("Population IS NOT EMPTY", &row));
+        assert!(is_match("State in (CA, TX, \"New York\")", &row));
+        assert!(!is_match("State not in (CA, TX)", &row));
+        assert!(is_match("NOT State = CA", &row));
+        assert!(is_match("State = CA OR State = TX AND City = Austin", &row));
+        assert!(!is_match(
+            "(State = CA OR State = TX) AND City = Dallas",
+            &row
+        ));
+        // OR binds looser than AND
+        assert!(is_match(
+            "City = Austin OR State = CA AND Founded > 2000",
+            &row
+        ));
+    }
+
+    #[test]
+    fn test_case() {
+        let expr = |query, case| {
+            let options = MatchOptions {
+                case,
+                ..Default::default()
+            };
+            Expr::parse(query, options, &headers()).unwrap().unwrap()
+        };
+        let record = StringRecord::from(vec!["Austin", "TX"]);
+        assert!(!expr("State in (tx)", CaseMode::Sensitive).is_match(&record));
+        assert!(expr("State in (tx)", CaseMode::Insensitive).is_match(&record));
+        assert!(expr("State in (tx)", CaseMode::Smart).is_match(&record));
+        assert!(!expr("City != austin", CaseMode::Insensitive).is_match(&record));
+    }
+
+    #[test]
+    fn test_errors() {
+        for query in [
+            "Population >",
+            "Population > 1 AND",
+            "(State = CA",
+            "State = CA)",
+            "State in CA",
+            "State is full",
+            "Population > 1 AND Country = US",
+            "#9 > 1",
+            "State = \"CA",
+            "State ~ ( OR City = x",
+            "State !~ (",
+        ] {
+            assert!(parse(query).is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("2022-01-31"), Some((2022, 1, 31, 0, 0, 0)));
+        assert_eq!(
+            parse_date("2022/01/31T12:30:05.25"),
+            Some((2022, 1, 31, 12, 30, 5))
+        );
+        assert_eq!(parse_date("2022-13-01"), None);
+        assert_eq!(parse_date("22-01-01"), None);
+        assert_eq!(parse_date("2022-01-31 noon"), None);
+    }
+}
@@ -1,16 +1,25 @@
-extern crate csv;
+use crate::csv::{CsvConfig, RecordScanner, ScanResult};
+use crate::matcher::Matcher;
 
 use anyhow::Result;
-use csv::Reader;
+use csv::StringRecord;
 use std::cmp::min;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard};
-use std::thread::{self, JoinHandle};
+use std::thread;
+use std::time;
+
+/// Source of `Finder::id`
+static NEXT_FINDER_ID: AtomicUsize = AtomicUsize::new(0);
 
 pub struct Finder {
+    id: usize,
     internal: Arc<Mutex<FinderInternalState>>,
     cursor: Option<usize>,
     row_hint: usize,
-    target: String,
+    matcher: Matcher,
+    /// Matchers of the filters this one narrows down, outermost first
+    parents: Vec<Matcher>,
 }
 
 #[derive(Clone, Debug)]
@@ -27,24 +36,36 @@ impl FoundRecord {
     pub fn column_indices(&self) -> &Vec<usize> {
         &self.column_indices
     }
-
-    pub fn first_column(&self) -> usize {
-        *self.column_indices.first().unwrap()
-    }
 }
 
 impl Finder {
-    pub fn new(filename: &str, target: &str) -> Result<Self> {
-        let internal = FinderInternalState::init(filename, target);
+    pub fn new(config: Arc<CsvConfig>, matcher: Matcher) -> Result<Self> {
+        Finder::new_within(config, matcher, vec![])
+    }
+
+    /// Find records that match `matcher` among the ones found by this finder
+    pub fn narrow(&self, config: Arc<CsvConfig>, matcher: Matcher) -> Result<Self> {
+        Finder::new_within(config, matcher, self.chain())
+    }
+
+    fn new_within(config: Arc<CsvConfig>, matcher: Matcher, parents: Vec<Matcher>) -> Result<Self> {
+        let internal = FinderInternalState::init(config, matcher.clone(), parents.clone());
         let finder = Finder {
+            id: NEXT_FINDER_ID.fetch_add(1, Ordering::Relaxed),
             internal,
             cursor: None,
             row_hint: 0,
-            target: target.to_owned(),
+            matcher,
+            parents,
         };
         Ok(finder)
     }
 
+    /// Tells finders apart, even ones created after others are dropped
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
     pub fn count(&self) -> usize {
         (self.internal.lock().unwrap()).count
     }
@@ -63,8 +84,15 @@ impl Finder {
             .map(|x| x.row_index())
     }
 
-    pub fn target(&self) -> String {
-        self.target.clone()
+    pub fn matcher(&self) -> Matcher {
+        self.matcher.clone()
+    }
+
+    /// Matchers of the filters narrowed down so far, ending with this one
+    pub fn chain(&self) -> Vec<Matcher> {
+        let mut chain = self.parents.clone();
+        chain.push(self.matcher.clone());
+        chain
     }
 
     pub fn reset_cursor(&mut self) {
@@ -75,6 +103,7 @@ impl Finder {
         self.row_hint = row_hint;
     }
 
+    #[allow(dead_code)]
     pub fn row_hint(&self) -> usize {
         self.row_hint
     }
@@ -129,11 +158,22 @@ impl Finder {
         m_guard.terminate();
     }
 
+    #[allow(dead_code)]
     pub fn get_all_found(&self) -> Vec<FoundRecord> {
         let m_guard = self.internal.lock().unwrap();
         m_guard.founds.clone()
     }
 
+    /// Row indices of all records found so far
+    pub fn get_all_found_rows(&self) -> Vec<u64> {
+        let m_guard = self.internal.lock().unwrap();
+        m_guard
+            .founds
+            .iter()
+            .map(|x| x.row_index() as u64)
+            .collect()
+    }
+
     pub fn get_subset_found(&self, offset: usize, num_rows: usize) -> Vec<u64> {
         let m_guard = self.internal.lock().unwrap();
         let founds = &m_guard.founds;
@@ -162,7 +202,11 @@ struct FinderInternalState {
 }
 
 impl FinderInternalState {
-    pub fn init(filename: &str, target: &str) -> Arc<Mutex<FinderInternalState>> {
+    pub fn init(
+        config: Arc<CsvConfig>,
+        matcher: Matcher,
+        parents: Vec<Matcher>,
+    ) -> Arc<Mutex<FinderInternalState>> {
         let internal = FinderInternalState {
             count: 0,
             founds: vec![],
@@ -173,31 +217,45 @@ impl FinderInternalState {
         let m_state = Arc::new(Mutex::new(internal));
 
         let _m = m_state.clone();
-        let _filename = filename.to_owned();
-        let _target = target.to_owned();
 
         let _handle = thread::spawn(move || {
-            let mut bg_reader = Reader::from_path(_filename.as_str()).unwrap();
-
-            // note that records() exludes header
-            let records = bg_reader.records();
-
-            for (row_index, r) in records.enumerate() {
-                let mut column_indices = vec![];
-                if let Ok(valid_record) = r {
-                    for (column_index, field) in valid_record.iter().enumerate() {
-                        if field.contains(_target.as_str()) {
-                            column_indices.push(column_index);
+            let header_offset = config.header_offset();
+            let mut scanner = RecordScanner::new(config).unwrap();
+
+            loop {
+                let (pos, r) = match scanner.next() {
+                    ScanResult::Record(pos, r) => (pos, r),
+                    ScanResult::Pending => {
+                        thread::sleep(time::Duration::from_millis(100));
+                        if _m.lock().unwrap().should_terminate {
+                            break;
                         }
+                        continue;
                     }
-                }
+                    ScanResult::Reset => {
+                        _m.lock().unwrap().reset();
+                        continue;
+                    }
+                    ScanResult::Done => break,
+                };
+                let row_index = (pos.record() - header_offset) as usize;
+                let record = StringRecord::from_byte_record(r)
+                    .unwrap_or_else(|e| StringRecord::from_byte_record_lossy(e.into_byte_record()));
+                let column_indices = if parents
+                    .iter()
+                    .all(|m| !m.matching_columns(&record).is_empty())
+                {
+                    matcher.matching_columns(&record)
+                } else {
+                    vec![]
+                };
                 if !column_indices.is_empty() {
                     let found = FoundRecord {
                         row_index,
                         column_indices,
                     };
                     let mut m = _m.lock().unwrap();
-                    (*m).found_one(found);
+                    m.found_one(found);
                 }
                 let m = _m.lock().unwrap();
                 if m.should_terminate {
@@ -206,12 +264,17 @@ impl FinderInternalState {
             }
 
             let mut m = _m.lock().unwrap();
-            (*m).done = true;
+            m.done = true;
         });
 
         m_state
     }
 
+    fn reset(&mut self) {
+        self.founds.clear();
+        self.count = 0;
+    }
+
     fn found_one(&mut self, found: FoundRecord) {
         self.founds.push(found);
         self.count += 1;
@@ -238,3 +301,135 @@ impl FinderInternalState {
         self.should_terminate = true;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::{wait_for, CsvLensReader};
+    use crate::matcher::MatchOptions;
+    use std::io::Write;
+
+    fn new_finder(config: CsvConfig, pattern: &str) -> Finder {
+        let matcher = Matcher::new(pattern, MatchOptions::default()).unwrap();
+        Finder::new(Arc::new(config), matcher).unwrap()
+    }
+
+    fn wait_finder(finder: &Finder) {
+        wait_for(|| Some(()).filter(|_| finder.done()));
+    }
+
+    fn found_row_indices(finder: &Finder) -> Vec<usize> {
+        finder
+            .get_all_found()
+            .iter()
+            .map(|x| x.row_index())
+            .collect()
+    }
+
+    #[test]
+    fn test_small() {
+        let config = CsvConfig::new("tests/data/small.csv", b',', false);
+        let finder = new_finder(config, "c2");
+        wait_finder(&finder);
+        assert_eq!(found_row_indices(&finder), vec![1]);
+    }
+
+    #[test]
+    fn test_small_no_headers() {
+        let config = CsvConfig::new("tests/data/small.csv", b',', true);
+        let finder = new_finder(config, "C");
+        wait_finder(&finder);
+        assert_eq!(found_row_indices(&finder), vec![0]);
+
+        let config = CsvConfig::new("tests/data/small.csv", b',', true);
+        let finder = new_finder(config, "c2");
+        wait_finder(&finder);
+        assert_eq!(found_row_indices(&finder), vec![2]);
+    }
+
+    #[test]
+    fn test_cities_regex() {
+        let config = CsvConfig::new("tests/data/cities.csv", b',', false);
+        let finder = new_finder(config, "^Wa(co|rren)$");
+        wait_finder(&finder);
+        assert_eq!(found_row_indices(&finder), vec![27, 29]);
+        assert_eq!(finder.get_all_found()[0].column_indices(), &vec![8]);
+    }
+
+    #[test]
+    fn test_cities_column_scoped() {
+        let headers: Vec<String> = (1..=10).map(|i| i.to_string()).collect();
+        let matcher = Matcher::parse("#10:^W", MatchOptions::default(), &headers).unwrap();
+        let config = Arc::new(CsvConfig::new("tests/data/cities.csv", b',', false));
+        let finder = Finder::new(config, matcher).unwrap();
+        wait_finder(&finder);
+        let found = finder.get_all_found();
+        assert_eq!(found.len(), 14);
+        assert!(found.iter().all(|x| x.column_indices() == &vec![9]));
+    }
+
+    #[test]
+    fn test_cities_inverted() {
+        let headers: Vec<String> = (1..=10).map(|i| i.to_string()).collect();
+        let matcher = Matcher::parse("!#10:^W", MatchOptions::default(), &headers).unwrap();
+        let config = Arc::new(CsvConfig::new("tests/data/cities.csv", b',', false));
+        let finder = Finder::new(config, matcher).unwrap();
+        wait_finder(&finder);
+        let found = finder.get_all_found();
+        assert_eq!(found.len(), 128 - 14);
+        assert_eq!(found[0].row_index(), 0);
+        assert_eq!(found[2].row_index(), 3);
+        assert!(found.iter().all(|x| x.column_indices() == &vec![9]));
+    }
+
+    #[test]
+    fn test_cities_narrow() {
+        let headers: Vec<String> = (1..=10).map(|i| i.to_string()).collect();
+        let parse = |query| Matcher::parse(query, MatchOptions::default(), &headers).unwrap();
+        let config = Arc::new(CsvConfig::new("tests/data/cities.csv", b',', false));
+        let finder = Finder::new(config.clone(), parse("#10:^W")).unwrap();
+        let narrowed = finder.narrow(config, parse("#1:47")).unwrap();
+        wait_finder(&narrowed);
+        assert_eq!(found_row_indices(&narrowed), vec![17, 54, 68, 78]);
+        assert_eq!(narrowed.get_all_found()[0].column_indices(), &vec![0]);
+        assert_eq!(narrowed.chain().len(), 2);
+        // The outer filter is unaffected
+        wait_finder(&finder);
+        assert_eq!(finder.count(), 14);
+    }
+
+    #[test]
+    fn test_cities_expression() {
+        let config = CsvConfig::new("tests/data/cities.csv", b',', false);
+        let config = Arc::new(config);
+        let headers = CsvLensReader::new(config.clone()).unwrap().headers;
+        let matcher = Matcher::parse(
+            "LatD > 45 AND (State = WA OR State in (OR, ID)) AND EW is not empty",
+            MatchOptions::default(),
+            &headers,
+        )
+        .unwrap();
+        let finder = Finder::new(config, matcher).unwrap();
+        wait_finder(&finder);
+        assert_eq!(found_row_indices(&finder), vec![2, 17, 28, 54, 68, 78]);
+        assert_eq!(finder.get_all_found()[0].column_indices(), &vec![0, 7, 9]);
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"name,city\nJos\xe9,Caf\xe9\nAnn,Oslo\n")
+            .unwrap();
+        let filename = file.path().to_str().unwrap();
+        let finder = new_finder(CsvConfig::new(filename, b',', false), "Caf");
+        wait_finder(&finder);
+        assert_eq!(found_row_indices(&finder), vec![0]);
+        // Not containing the pattern includes rows that aren't valid UTF-8
+        let headers = vec!["name".to_owned(), "city".to_owned()];
+        let matcher = Matcher::parse("!Oslo", MatchOptions::default(), &headers).unwrap();
+        let config = Arc::new(CsvConfig::new(filename, b',', false));
+        let finder = Finder::new(config, matcher).unwrap();
+        wait_finder(&finder);
+        assert_eq!(found_row_indices(&finder), vec![0]);
+    }
+}
@@ -0,0 +1,294 @@
+use crate::csv::{CsvConfig, FilteredScan, FilteredScanner};
+use crate::matcher::Matcher;
+use crate::sort::SortKey;
+use crate::stats::{ENTRY_OVERHEAD_BYTES, MAX_COUNTS_BYTES};
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{self, Instant};
+
+/// Distinct values counted, beyond which new values are left out
+const MAX_VALUES: usize = 1_000_000;
+
+/// How often the counts shown are updated while scanning, at the least
+const UPDATE_INTERVAL: time::Duration = time::Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrequencyOrder {
+    /// Most frequent first
+    Count,
+    /// Numbers by value before text
+    Value,
+}
+
+impl FrequencyOrder {
+    pub fn toggle(&self) -> FrequencyOrder {
+        match self {
+            FrequencyOrder::Count => FrequencyOrder::Value,
+            FrequencyOrder::Value => FrequencyOrder::Count,
+        }
+    }
+}
+
+/// Distinct values of a column with how often each occurs, as far as the
+/// column has been scanned
+#[derive(Debug)]
+pub struct FrequencyTable {
+    /// Values and their counts, most frequent first
+    by_count: Vec<(String, u64)>,
+    /// Indices into `by_count` in order of the values
+    by_value: Vec<usize>,
+    /// Rows counted
+    pub total: u64,
+    /// Whether some values were left out for there being too many, or them
+    /// taking too much memory
+    pub truncated: bool,
+    /// Fraction of the file scanned
+    pub progress: f64,
+    pub done: bool,
+}
+
+impl FrequencyTable {
+    fn new(counts: &HashMap<String, u64>, total: u64, truncated: bool) -> FrequencyTable {
+        let mut by_count: Vec<(String, u64)> =
+            counts.iter().map(|(v, &c)| (v.clone(), c)).collect();
+        by_count.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let keys: Vec<SortKey> = by_count
+            .iter()
+            .map(|(v, _)| SortKey::new(v.as_bytes()))
+            .collect();
+        let mut by_value: Vec<usize> = (0..by_count.len()).collect();
+        by_value.sort_by(|&a, &b| keys[a].cmp(&keys[b]));
+        FrequencyTable {
+            by_count,
+            by_value,
+            total,
+            truncated,
+            progress: 0.0,
+            done: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_count.len()
+    }
+
+    /// Value at position `i` in the given order, with its count
+    pub fn get(&self, i: usize, order: FrequencyOrder) -> Option<&(String, u64)> {
+        let i = match order {
+            FrequencyOrder::Count => i,
+            FrequencyOrder::Value => *self.by_value.get(i)?,
+        };
+        self.by_count.get(i)
+    }
+}
+
+/// Counts the values of a column in the background, over all rows or the
+/// rows selected by every one of `filters`
+pub struct Frequency {
+    internal: Arc<Mutex<FrequencyInternalState>>,
+    column: usize,
+}
+
+impl Frequency {
+    pub fn new(config: Arc<CsvConfig>, column: usize, filters: Vec<Matcher>) -> Frequency {
+        let internal =
+            FrequencyInternalState::init(config, column, filters, MAX_VALUES, MAX_COUNTS_BYTES);
+        Frequency { internal, column }
+    }
+
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    pub fn get(&self) -> Arc<FrequencyTable> {
+        self.internal.lock().unwrap().table.clone()
+    }
+}
+
+impl Drop for Frequency {
+    fn drop(&mut self) {
+        self.internal.lock().unwrap().should_terminate = true;
+    }
+}
+
+struct FrequencyInternalState {
+    table: Arc<FrequencyTable>,
+    should_terminate: bool,
+}
+
+impl FrequencyInternalState {
+    fn init(
+        config: Arc<CsvConfig>,
+        column: usize,
+        filters: Vec<Matcher>,
+        max_values: usize,
+        max_bytes: usize,
+    ) -> Arc<Mutex<FrequencyInternalState>> {
+        let internal = FrequencyInternalState {
+            table: Arc::new(FrequencyTable::new(&HashMap::new(), 0, false)),
+            should_terminate: false,
+        };
+        let m_state = Arc::new(Mutex::new(internal));
+        let _m = m_state.clone();
+        let _handle = thread::spawn(move || {
+            // Errors reading the file just end the scan early
+            let table =
+                count_values(config, column, filters, max_values, max_bytes, &_m).unwrap_or(None);
+            let mut m = _m.lock().unwrap();
+            match table {
+                Some(table) => m.table = Arc::new(table),
+                None => {
+                    let mut table = FrequencyTable::new(&HashMap::new(), 0, false);
+                    table.done = true;
+                    m.table = Arc::new(table);
+                }
+            }
+        });
+        m_state
+    }
+}
+
+/// Scan all records, publishing the counts as they go, and return the final
+/// counts or `None` if terminated early. New values are left out once there
+/// are `max_values` of them, or they take about `max_bytes` of memory.
+fn count_values(
+    config: Arc<CsvConfig>,
+    column: usize,
+    filters: Vec<Matcher>,
+    max_values: usize,
+    max_bytes: usize,
+    m_state: &Mutex<FrequencyInternalState>,
+) -> Result<Option<FrequencyTable>> {
+    let mut scanner = FilteredScanner::new(config, filters)?;
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    let mut counts_bytes = 0;
+    let mut total = 0;
+    let mut truncated = false;
+    let mut next_update = Instant::now() + UPDATE_INTERVAL;
+    while let Some(scanned) = scanner.next() {
+        match scanned {
+            FilteredScan::Record(_, record) => {
+                let value = record.get(column).unwrap_or_default();
+                total += 1;
+                if let Some(c) = counts.get_mut(value) {
+                    *c += 1;
+                } else if counts.len() < max_values && counts_bytes < max_bytes {
+                    counts.insert(value.to_owned(), 1);
+                    counts_bytes += value.len() + ENTRY_OVERHEAD_BYTES;
+                } else {
+                    truncated = true;
+                }
+            }
+            FilteredScan::Reset => {
+                counts.clear();
+                counts_bytes = 0;
+                total = 0;
+                truncated = false;
+            }
+            FilteredScan::FilteredOut | FilteredScan::Waiting => {}
+        }
+        if Instant::now() >= next_update {
+            if m_state.lock().unwrap().should_terminate {
+                return Ok(None);
+            }
+            let start = Instant::now();
+            let mut table = FrequencyTable::new(&counts, total, truncated);
+            table.progress = scanner.progress();
+            m_state.lock().unwrap().table = Arc::new(table);
+            // Don't spend most of the time sorting when there are many values
+            next_update = Instant::now() + UPDATE_INTERVAL.max(start.elapsed() * 4);
+        }
+    }
+    let mut table = FrequencyTable::new(&counts, total, truncated);
+    table.progress = 1.0;
+    table.done = true;
+    Ok(Some(table))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::{wait_for, CsvLensReader};
+    use crate::matcher::MatchOptions;
+
+    fn count(
+        column: usize,
+        query: Option<&str>,
+        max_values: usize,
+        max_bytes: usize,
+    ) -> Arc<FrequencyTable> {
+        let config = Arc::new(CsvConfig::new("tests/data/cities.csv", b',', false));
+        let headers = CsvLensReader::new(config.clone()).unwrap().headers;
+        let filters = query
+            .map(|q| Matcher::parse(q, MatchOptions::default(), &headers).unwrap())
+            .into_iter()
+            .collect();
+        let m_state = FrequencyInternalState::init(config, column, filters, max_values, max_bytes);
+        wait_for(|| Some(m_state.lock().unwrap().table.clone()).filter(|t| t.done))
+    }
+
+    fn values(table: &FrequencyTable, order: FrequencyOrder, n: usize) -> Vec<(String, u64)> {
+        (0..n)
+            .filter_map(|i| table.get(i, order).cloned())
+            .collect()
+    }
+
+    #[test]
+    fn test_count() {
+        let table = count(0, None, MAX_VALUES, MAX_COUNTS_BYTES);
+        assert_eq!(table.total, 128);
+        assert_eq!(table.len(), 25);
+        assert!(!table.truncated);
+        assert_eq!(
+            values(&table, FrequencyOrder::Count, 2),
+            vec![("41".to_owned(), 12), ("38".to_owned(), 11)]
+        );
+        // numerically, not as text
+        assert_eq!(
+            values(&table, FrequencyOrder::Value, 3),
+            vec![
+                ("26".to_owned(), 1),
+                ("27".to_owned(), 2),
+                ("28".to_owned(), 1)
+            ]
+        );
+        assert_eq!(table.get(25, FrequencyOrder::Value), None);
+    }
+
+    #[test]
+    fn test_count_filtered() {
+        let table = count(9, Some("State~^[NO]"), MAX_VALUES, MAX_COUNTS_BYTES);
+        assert_eq!(table.total, 25);
+        assert_eq!(
+            values(&table, FrequencyOrder::Count, 2),
+            vec![("OH".to_owned(), 6), ("NY".to_owned(), 5)]
+        );
+        assert_eq!(
+            values(&table, FrequencyOrder::Value, 5),
+            vec![
+                ("NB".to_owned(), 1),
+                ("NC".to_owned(), 3),
+                ("ND".to_owned(), 2),
+                ("NJ".to_owned(), 1),
+                ("NM".to_owned(), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_count_truncated() {
+        let table = count(8, None, 10, MAX_COUNTS_BYTES);
+        assert_eq!(table.total, 128);
+        assert_eq!(table.len(), 10);
+        assert!(table.truncated);
+
+        // Room for the first 5 values
+        let table = count(8, None, MAX_VALUES, 5 * ENTRY_OVERHEAD_BYTES);
+        assert_eq!(table.total, 128);
+        assert_eq!(table.len(), 5);
+        assert!(table.truncated);
+    }
+}
@@ -0,0 +1,398 @@
+use crate::csv::CsvConfig;
+use crate::profile::Profiler;
+use crate::types::ColumnType;
+
+use anyhow::{bail, Result};
+use csv::Position;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::UNIX_EPOCH;
+
+const MAGIC: &[u8; 8] = b"CSVLIDX3";
+
+/// Tells apart the temporary files of indexes written at the same time
+static NEXT_TEMP_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Extension of the index saved next to the csv file
+const SIDECAR_EXTENSION: &str = "csvlens-idx";
+
+/// Number of bytes hashed at the start and the end of the indexed part of the
+/// file to detect changes
+pub const HASH_REGION_SIZE: u64 = 64 * 1024;
+
+/// The position table and column profile built by the background indexer,
+/// saved to disk so that reopening a huge file doesn't require parsing it
+/// again.
+#[derive(Debug, PartialEq)]
+pub struct SavedIndex {
+    pub pos_table: Vec<Position>,
+    pub pos_table_update_every: usize,
+    pub num_records: usize,
+    /// Position right after the last indexed record
+    pub end: Position,
+    pub profile: Profiler,
+}
+
+pub enum LoadedIndex {
+    /// The file is unchanged since the index was saved
+    Complete(SavedIndex),
+    /// Records had been appended to the file since, indexing can resume from
+    /// the end of the saved index
+    Partial(SavedIndex),
+}
+
+#[derive(Debug, PartialEq)]
+struct Fingerprint {
+    size: u64,
+    mtime: (u64, u32),
+    head_hash: u64,
+    tail_hash: u64,
+}
+
+impl Fingerprint {
+    fn new(filename: &str, end: u64) -> Result<Fingerprint> {
+        let file = File::open(filename)?;
+        let metadata = file.metadata()?;
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+        let head_hash = hash_region(&file, 0, HASH_REGION_SIZE.min(end))?;
+        let tail_start = end.saturating_sub(HASH_REGION_SIZE);
+        let tail_hash = hash_region(&file, tail_start, end - tail_start)?;
+        Ok(Fingerprint {
+            size: metadata.len(),
+            mtime: (mtime.as_secs(), mtime.subsec_nanos()),
+            head_hash,
+            tail_hash,
+        })
+    }
+}
+
+/// Load the saved index for the file in `config`, if there is one that is
+/// still usable
+pub fn load(config: &CsvConfig) -> Option<LoadedIndex> {
+    for path in candidate_paths(config.filename()) {
+        if let Ok((fingerprint, index)) = read_index_file(&path, config) {
+            let current = match Fingerprint::new(config.filename(), index.end.byte()) {
+                Ok(f) => f,
+                Err(_) => return None,
+            };
+            if current == fingerprint {
+                return Some(LoadedIndex::Complete(index));
+            }
+            if current.size > fingerprint.size
+                && current.head_hash == fingerprint.head_hash
+                && current.tail_hash == fingerprint.tail_hash
+                && ends_with_newline(config.filename(), index.end.byte())
+            {
+                return Some(LoadedIndex::Partial(index));
+            }
+            return None;
+        }
+    }
+    None
+}
+
+/// Save the index for the file in `config`, next to the file if possible or
+/// else in the cache directory
+pub fn save(config: &CsvConfig, index: &SavedIndex) -> Result<()> {
+    let fingerprint = Fingerprint::new(config.filename(), index.end.byte())?;
+    let mut last_error = None;
+    for path in candidate_paths(config.filename()) {
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() {
+                let _ = fs::create_dir_all(dir);
+            }
+        }
+        match write_index_file(&path, config, &fingerprint, index) {
+            Ok(()) => return Ok(()),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    match last_error {
+        Some(e) => Err(e),
+        None => bail!("No location to save the index to"),
+    }
+}
+
+/// Whether the last indexed record is terminated, otherwise appended data
+/// might have continued it
+fn ends_with_newline(filename: &str, end: u64) -> bool {
+    let mut last_byte = [0; 1];
+    match (File::open(filename), end.checked_sub(1)) {
+        (Ok(f), Some(i)) => f.read_exact_at(&mut last_byte, i).is_ok() && last_byte[0] == b'\n',
+        _ => false,
+    }
+}
+
+fn candidate_paths(filename: &str) -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(format!("{}.{}", filename, SIDECAR_EXTENSION))];
+    let cache_dir = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|x| Path::new(&x).join(".cache")));
+    if let Some(dir) = cache_dir {
+        let absolute = fs::canonicalize(filename).unwrap_or_else(|_| PathBuf::from(filename));
+        let key = fnv1a(absolute.to_string_lossy().as_bytes(), FNV_OFFSET_BASIS);
+        paths.push(
+            dir.join("csvlens")
+                .join(format!("{:016x}.{}", key, SIDECAR_EXTENSION)),
+        );
+    }
+    paths
+}
+
+/// Write the index to a temporary file next to `path` and then move it over
+/// `path`, so that the index there is never seen half written
+fn write_index_file(
+    path: &Path,
+    config: &CsvConfig,
+    fingerprint: &Fingerprint,
+    index: &SavedIndex,
+) -> Result<()> {
+    let mut temp_name = path.as_os_str().to_owned();
+    let temp_id = NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed);
+    temp_name.push(format!(".{}-{}.tmp", process::id(), temp_id));
+    let temp_path = PathBuf::from(temp_name);
+    let result = write_index(&temp_path, config, fingerprint, index)
+        .and_then(|_| Ok(fs::rename(&temp_path, path)?));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+fn write_index(
+    path: &Path,
+    config: &CsvConfig,
+    fingerprint: &Fingerprint,
+    index: &SavedIndex,
+) -> Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(MAGIC)?;
+    w.write_all(&settings(config))?;
+    write_u64(&mut w, fingerprint.size)?;
+    write_u64(&mut w, fingerprint.mtime.0)?;
+    write_u64(&mut w, fingerprint.mtime.1 as u64)?;
+    write_u64(&mut w, fingerprint.head_hash)?;
+    write_u64(&mut w, fingerprint.tail_hash)?;
+    write_u64(&mut w, index.pos_table_update_every as u64)?;
+    write_u64(&mut w, index.num_records as u64)?;
+    write_position(&mut w, &index.end)?;
+    write_u64(&mut w, index.pos_table.len() as u64)?;
+    for pos in index.pos_table.iter() {
+        write_position(&mut w, pos)?;
+    }
+    write_u64(&mut w, index.profile.num_records())?;
+    let columns = index.profile.columns();
+    write_u64(&mut w, columns.len() as u64)?;
+    for (column_type, counts) in columns {
+        write_u64(&mut w, type_code(column_type))?;
+        write_u64(&mut w, counts.len() as u64)?;
+        for (width, count) in counts {
+            write_u64(&mut w, width as u64)?;
+            write_u64(&mut w, count)?;
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
+
+fn read_index_file(path: &Path, config: &CsvConfig) -> Result<(Fingerprint, SavedIndex)> {
+    let mut r = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("Not a csvlens index: {}", path.display());
+    }
+    let mut saved_settings = [0; 4];
+    r.read_exact(&mut saved_settings)?;
+    if saved_settings != settings(config) {
+        bail!("Index built with different settings: {}", path.display());
+    }
+    let fingerprint = Fingerprint {
+        size: read_u64(&mut r)?,
+        mtime: (read_u64(&mut r)?, read_u64(&mut r)? as u32),
+        head_hash: read_u64(&mut r)?,
+        tail_hash: read_u64(&mut r)?,
+    };
+    let pos_table_update_every = read_u64(&mut r)? as usize;
+    let num_records = read_u64(&mut r)? as usize;
+    let end = read_position(&mut r)?;
+    let num_entries = read_u64(&mut r)?;
+    let mut pos_table = vec![];
+    for _ in 0..num_entries {
+        pos_table.push(read_position(&mut r)?);
+    }
+    let num_profiled = read_u64(&mut r)?;
+    let num_cols = read_u64(&mut r)?;
+    let mut columns = vec![];
+    for _ in 0..num_cols {
+        let column_type = match type_of_code(read_u64(&mut r)?) {
+            Some(t) => t,
+            None => bail!("Unknown column type: {}", path.display()),
+        };
+        let num_widths = read_u64(&mut r)?;
+        let mut counts = BTreeMap::new();
+        for _ in 0..num_widths {
+            counts.insert(read_u64(&mut r)? as u16, read_u64(&mut r)?);
+        }
+        columns.push((column_type, counts));
+    }
+    let profile = Profiler::from_columns(config.value_format(), num_profiled, columns);
+    let index = SavedIndex {
+        pos_table,
+        pos_table_update_every,
+        num_records,
+        end,
+        profile,
+    };
+    Ok((fingerprint, index))
+}
+
+/// Settings the index depends on; column widths depend on how values are
+/// formatted
+fn settings(config: &CsvConfig) -> [u8; 4] {
+    let format = config.value_format();
+    [
+        config.delimiter(),
+        config.no_headers() as u8,
+        format.thousands_separator as u8,
+        format
+            .decimals
+            .map_or(u8::MAX, |d| d.min(u8::MAX as usize - 1) as u8),
+    ]
+}
+
+const COLUMN_TYPES: [ColumnType; 7] = [
+    ColumnType::Empty,
+    ColumnType::Integer,
+    ColumnType::Float,
+    ColumnType::Boolean,
+    ColumnType::Date,
+    ColumnType::DateTime,
+    ColumnType::Text,
+];
+
+fn type_code(column_type: ColumnType) -> u64 {
+    COLUMN_TYPES.iter().position(|&t| t == column_type).unwrap() as u64
+}
+
+fn type_of_code(code: u64) -> Option<ColumnType> {
+    COLUMN_TYPES.get(code as usize).copied()
+}
+
+fn write_u64<W: Write>(w: &mut W, n: u64) -> Result<()> {
+    w.write_all(&n.to_le_bytes())?;
+    Ok(())
+}
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn write_position<W: Write>(w: &mut W, pos: &Position) -> Result<()> {
+    write_u64(w, pos.byte())?;
+    write_u64(w, pos.line())?;
+    write_u64(w, pos.record())?;
+    Ok(())
+}
+
+fn read_position<R: Read>(r: &mut R) -> Result<Position> {
+    let mut pos = Position::new();
+    pos.set_byte(read_u64(r)?);
+    pos.set_line(read_u64(r)?);
+    pos.set_record(read_u64(r)?);
+    Ok(pos)
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+
+/// FNV-1a, which unlike the std hashers is stable across releases
+fn fnv1a(bytes: &[u8], mut hash: u64) -> u64 {
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Hash of `len` bytes from `start`, read without moving the file's cursor
+pub fn hash_region(file: &File, start: u64, len: u64) -> Result<u64> {
+    let mut buf = vec![0; len as usize];
+    file.read_exact_at(&mut buf, start)?;
+    Ok(fnv1a(&buf, FNV_OFFSET_BASIS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ValueFormat;
+    use csv::ByteRecord;
+    use std::fs::OpenOptions;
+
+    fn sample_index(end: u64) -> SavedIndex {
+        let mut pos = Position::new();
+        pos.set_byte(9).set_line(3).set_record(2);
+        let mut end_pos = Position::new();
+        end_pos.set_byte(end).set_line(5).set_record(4);
+        let mut profile = Profiler::new(ValueFormat::default());
+        for record in [["1", "2"], ["3", "4"], ["5", "abc"]].iter() {
+            profile.add(&ByteRecord::from(record.to_vec()));
+        }
+        SavedIndex {
+            pos_table: vec![pos],
+            pos_table_update_every: 2,
+            num_records: 3,
+            end: end_pos,
+            profile,
+        }
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("a.csv");
+        fs::write(&filename, "a,b\n1,2\n3,4\n5,6\n").unwrap();
+        let config = CsvConfig::new(filename.to_str().unwrap(), b',', false);
+
+        assert!(load(&config).is_none());
+        save(&config, &sample_index(16)).unwrap();
+        assert!(dir.path().join("a.csv.csvlens-idx").exists());
+        match load(&config) {
+            Some(LoadedIndex::Complete(index)) => assert_eq!(index, sample_index(16)),
+            _ => panic!("expected a complete index"),
+        }
+
+        // different settings
+        let other_config = CsvConfig::new(filename.to_str().unwrap(), b';', false);
+        assert!(load(&other_config).is_none());
+
+        // appended
+        let mut f = OpenOptions::new().append(true).open(&filename).unwrap();
+        f.write_all(b"7,8\n").unwrap();
+        assert!(matches!(load(&config), Some(LoadedIndex::Partial(_))));
+
+        // saved over the old index, without leaving temporary files behind
+        save(&config, &sample_index(20)).unwrap();
+        match load(&config) {
+            Some(LoadedIndex::Complete(index)) => assert_eq!(index, sample_index(20)),
+            _ => panic!("expected a complete index"),
+        }
+        let mut names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["a.csv", "a.csv.csvlens-idx"]);
+
+        // modified
+        fs::write(&filename, "a,b\n1,2\n3,9\n5,6\n7,8\n").unwrap();
+        assert!(load(&config).is_none());
+    }
+}
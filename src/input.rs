@@ -1,3 +1,4 @@
+use crate::matcher::MatchOptions;
 use crate::util::events::{Event, Events};
 use termion::event::Key;
 
@@ -12,11 +13,42 @@ pub enum Control {
     ScrollTo(usize),
     ScrollToNextFound,
     ScrollToPrevFound,
-    Find(String),
-    Filter(String),
+    Find(String, MatchOptions),
+    Filter(String, MatchOptions),
     Quit,
     BufferContent(String),
     BufferReset,
+    PopFilter,
+    ToggleSort,
+    ToggleTransposed,
+    ShowDetail,
+    DetailScrollUp,
+    DetailScrollDown,
+    DetailToggle,
+    DetailClose,
+    HideColumn,
+    ToggleFreeze,
+    ShowAllColumns,
+    MoveColumnLeft,
+    MoveColumnRight,
+    WidenColumn,
+    NarrowColumn,
+    ResetColumnWidth,
+    ShowColumnPicker,
+    PickerUp,
+    PickerDown,
+    PickerToggle,
+    PickerClose,
+    ShowStats,
+    StatsClose,
+    ShowFrequency,
+    FrequencyUp,
+    FrequencyDown,
+    FrequencyPageUp,
+    FrequencyPageDown,
+    FrequencyToggleOrder,
+    FrequencySelect,
+    FrequencyClose,
     Nothing,
 }
 
@@ -31,20 +63,30 @@ pub enum InputMode {
     GotoLine,
     Find,
     Filter,
+    /// Popup with the full value of the selected cell or record
+    Detail,
+    /// Popup listing all columns to choose the visible ones
+    ColumnPicker,
+    /// Panel with the stats of the selected column
+    Stats,
+    /// Distinct values of the selected column with their counts
+    Frequency,
 }
 
 pub struct InputHandler {
     events: Events,
     mode: InputMode,
     buffer_state: BufferState,
+    match_options: MatchOptions,
 }
 
 impl InputHandler {
-    pub fn new() -> InputHandler {
+    pub fn new(match_options: MatchOptions) -> InputHandler {
         InputHandler {
             events: Events::new(),
             mode: InputMode::Default,
             buffer_state: BufferState::Inactive,
+            match_options,
         }
     }
 
@@ -52,6 +94,14 @@ impl InputHandler {
         if let Event::Input(key) = self.events.next().unwrap() {
             if self.is_input_buffering() {
                 return self.handler_buffering(key);
+            } else if self.mode == InputMode::Detail {
+                return self.handler_detail(key);
+            } else if self.mode == InputMode::ColumnPicker {
+                return self.handler_column_picker(key);
+            } else if self.mode == InputMode::Stats {
+                return self.handler_stats(key);
+            } else if self.mode == InputMode::Frequency {
+                return self.handler_frequency(key);
             } else {
                 return self.handler_default(key);
             }
@@ -72,6 +122,33 @@ impl InputHandler {
             Key::Char('N') => Control::ScrollToPrevFound,
             Key::Ctrl('f') | Key::PageDown => Control::ScrollPageDown,
             Key::Ctrl('b') | Key::PageUp => Control::ScrollPageUp,
+            Key::Backspace => Control::PopFilter,
+            Key::Char('s') => Control::ToggleSort,
+            Key::Char('t') => Control::ToggleTransposed,
+            Key::Char('-') => Control::HideColumn,
+            Key::Char('f') => Control::ToggleFreeze,
+            Key::Char('+') => Control::ShowAllColumns,
+            Key::Char('<') => Control::MoveColumnLeft,
+            Key::Char('>') => Control::MoveColumnRight,
+            Key::Char(']') => Control::WidenColumn,
+            Key::Char('[') => Control::NarrowColumn,
+            Key::Char('=') => Control::ResetColumnWidth,
+            Key::Char('c') => {
+                self.mode = InputMode::ColumnPicker;
+                Control::ShowColumnPicker
+            }
+            Key::Char('S') => {
+                self.mode = InputMode::Stats;
+                Control::ShowStats
+            }
+            Key::Char('F') => {
+                self.mode = InputMode::Frequency;
+                Control::ShowFrequency
+            }
+            Key::Char('\n') => {
+                self.mode = InputMode::Detail;
+                Control::ShowDetail
+            }
             Key::Char(x) if "0123456789".contains(x.to_string().as_str()) => {
                 let init_buffer = x.to_string();
                 self.buffer_state = BufferState::Active(init_buffer.clone());
@@ -92,6 +169,67 @@ impl InputHandler {
         }
     }
 
+    fn handler_detail(&mut self, key: Key) -> Control {
+        match key {
+            Key::Esc | Key::Char('q') | Key::Char('\n') => {
+                self.mode = InputMode::Default;
+                Control::DetailClose
+            }
+            Key::Char('j') | Key::Down => Control::DetailScrollDown,
+            Key::Char('k') | Key::Up => Control::DetailScrollUp,
+            // Switch between the selected cell and the whole record
+            Key::Char('\t') => Control::DetailToggle,
+            _ => Control::Nothing,
+        }
+    }
+
+    fn handler_column_picker(&mut self, key: Key) -> Control {
+        match key {
+            Key::Esc | Key::Char('q') | Key::Char('c') | Key::Char('\n') => {
+                self.mode = InputMode::Default;
+                Control::PickerClose
+            }
+            Key::Char('j') | Key::Down => Control::PickerDown,
+            Key::Char('k') | Key::Up => Control::PickerUp,
+            Key::Char(' ') => Control::PickerToggle,
+            _ => Control::Nothing,
+        }
+    }
+
+    fn handler_stats(&mut self, key: Key) -> Control {
+        match key {
+            Key::Esc | Key::Char('q') | Key::Char('S') => {
+                self.mode = InputMode::Default;
+                Control::StatsClose
+            }
+            // Show the stats of another column
+            Key::Char('l') | Key::Right => Control::ScrollRight,
+            Key::Char('h') | Key::Left => Control::ScrollLeft,
+            _ => Control::Nothing,
+        }
+    }
+
+    fn handler_frequency(&mut self, key: Key) -> Control {
+        match key {
+            Key::Esc | Key::Char('q') | Key::Char('F') => {
+                self.mode = InputMode::Default;
+                Control::FrequencyClose
+            }
+            // Filter the rows with the selected value
+            Key::Char('\n') => {
+                self.mode = InputMode::Default;
+                Control::FrequencySelect
+            }
+            Key::Char('j') | Key::Down => Control::FrequencyDown,
+            Key::Char('k') | Key::Up => Control::FrequencyUp,
+            Key::Ctrl('f') | Key::PageDown => Control::FrequencyPageDown,
+            Key::Ctrl('b') | Key::PageUp => Control::FrequencyPageUp,
+            // Order by count or by value
+            Key::Char('s') => Control::FrequencyToggleOrder,
+            _ => Control::Nothing,
+        }
+    }
+
     fn handler_buffering(&mut self, key: Key) -> Control {
         let cur_buffer = match &self.buffer_state {
             BufferState::Active(buffer) => buffer.as_str(),
@@ -135,22 +273,30 @@ impl InputHandler {
             }
             Key::Char('\n') => {
                 let control;
-                if cur_buffer == "" {
+                if cur_buffer.is_empty() {
                     control = Control::BufferReset;
                 } else if self.mode == InputMode::Find {
-                    control = Control::Find(cur_buffer.to_string());
+                    control = Control::Find(cur_buffer.to_string(), self.match_options);
                 } else if self.mode == InputMode::Filter {
-                    control = Control::Filter(cur_buffer.to_string());
+                    control = Control::Filter(cur_buffer.to_string(), self.match_options);
                 } else {
                     control = Control::BufferReset;
                 }
                 self.reset_buffer();
                 control
             }
-            Key::Char('/') => {
-                if cur_buffer == "" && self.mode == InputMode::Find {
-                    self.mode = InputMode::Filter;
-                }
+            // Toggle between regex and literal matching
+            Key::Ctrl('r') if self.mode == InputMode::Find || self.mode == InputMode::Filter => {
+                self.match_options.literal = !self.match_options.literal;
+                Control::BufferContent(cur_buffer.to_string())
+            }
+            // Cycle through case sensitive, insensitive and smart case
+            Key::Ctrl('t') if self.mode == InputMode::Find || self.mode == InputMode::Filter => {
+                self.match_options.case = self.match_options.case.next();
+                Control::BufferContent(cur_buffer.to_string())
+            }
+            Key::Char('/') if cur_buffer.is_empty() && self.mode == InputMode::Find => {
+                self.mode = InputMode::Filter;
                 Control::BufferContent("".to_string())
             }
             Key::Char(x) => {
@@ -166,10 +312,7 @@ impl InputHandler {
     }
 
     fn is_input_buffering(&self) -> bool {
-        match self.buffer_state {
-            BufferState::Active(_) => true,
-            _ => false,
-        }
+        matches!(self.buffer_state, BufferState::Active(_))
     }
 
     fn reset_buffer(&mut self) {
@@ -180,4 +323,8 @@ impl InputHandler {
     pub fn mode(&self) -> InputMode {
         self.mode.clone()
     }
+
+    pub fn match_options(&self) -> MatchOptions {
+        self.match_options
+    }
 }
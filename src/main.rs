@@ -1,49 +1,88 @@
+mod columns;
 mod csv;
+mod delimiter;
+mod expr;
 mod find;
+mod frequency;
+mod index_file;
 mod input;
+mod matcher;
+mod parallel_index;
+mod profile;
+mod row_cache;
+mod seekable_file;
+mod sort;
+mod stats;
+mod types;
 mod ui;
 #[allow(dead_code)]
 mod util;
 mod view;
+use crate::delimiter::Delimiter;
+use crate::frequency::Frequency;
 use crate::input::{Control, InputHandler};
-use crate::ui::{CsvTable, CsvTableState, FinderState};
+use crate::matcher::{CaseMode, MatchOptions, Matcher};
+use crate::seekable_file::SeekableFile;
+use crate::sort::{SortOrder, Sorter};
+use crate::stats::Stats;
+use crate::types::ValueFormat;
+use crate::ui::{
+    ColumnPickerState, CsvTable, CsvTableState, DetailState, FinderState, FrequencyViewState,
+    SortState, TransposedLayout,
+};
 
 extern crate csv as sushi_csv;
 
 use anyhow::{Context, Result};
 use clap::Parser;
-use std::fs::File;
-use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::usize;
-use tempfile::NamedTempFile;
+use std::cmp::min;
+use std::io;
+use std::sync::Arc;
 use termion::{raw::IntoRawMode, screen::AlternateScreen};
 use tui::backend::TermionBackend;
+use tui::layout::Rect;
 use tui::Terminal;
 
-fn get_offsets_to_make_visible(
-    found_record: find::FoundRecord,
-    rows_view: &view::RowsView,
-    csv_table_state: &CsvTableState,
-) -> (Option<u64>, Option<u64>) {
-    let new_rows_offset;
-    // TODO: row_index() should probably be u64
-    if rows_view.in_view(found_record.row_index() as u64) {
-        new_rows_offset = None;
-    } else {
-        new_rows_offset = Some(found_record.row_index() as u64);
-    }
+/// Cells a column is widened or narrowed by at a time
+const COLUMN_WIDTH_STEP: i16 = 2;
 
-    let new_cols_offset;
-    let cols_offset = csv_table_state.cols_offset;
-    let last_rendered_col = cols_offset.saturating_add(csv_table_state.num_cols_rendered);
-    let column_index = found_record.first_column() as u64;
-    if column_index >= cols_offset && column_index < last_rendered_col {
-        new_cols_offset = None;
-    } else {
-        new_cols_offset = Some(column_index)
+/// Number of rows to fetch for a frame: lines for the table, or records side
+/// by side in the transposed view
+fn frame_num_rows(
+    size: Rect,
+    csv_table_state: &CsvTableState,
+    headers: &[String],
+    num_rows_not_visible: u64,
+) -> u64 {
+    if csv_table_state.transposed {
+        return TransposedLayout::new(headers, size.width).num_records;
     }
+    // TODO: check type of num_rows too big?
+    size.height
+        .saturating_sub(num_rows_not_visible as u16)
+        .saturating_sub(csv_table_state.breadcrumb_height()) as u64
+}
 
-    (new_rows_offset, new_cols_offset)
+/// Filter rows by `matcher`, narrowing down the rows of a filter that is
+/// already active
+fn apply_filter(
+    matcher: Matcher,
+    config: &Arc<csv::CsvConfig>,
+    finder: &mut Option<find::Finder>,
+    filter_stack: &mut Vec<find::Finder>,
+    rows_view: &mut view::RowsView,
+) {
+    let new_finder = match finder.take() {
+        Some(f) if rows_view.is_filter() => {
+            let narrowed = f.narrow(config.clone(), matcher).unwrap();
+            filter_stack.push(f);
+            narrowed
+        }
+        _ => find::Finder::new(config.clone(), matcher).unwrap(),
+    };
+    *finder = Some(new_finder);
+    rows_view.set_rows_from(0).unwrap();
+    rows_view.set_filter(finder.as_ref().unwrap()).unwrap();
 }
 
 fn scroll_to_found_record(
@@ -51,62 +90,76 @@ fn scroll_to_found_record(
     rows_view: &mut view::RowsView,
     csv_table_state: &mut CsvTableState,
 ) {
-    let (new_rows_offset, new_cols_offset) =
-        get_offsets_to_make_visible(found_record.clone(), rows_view, csv_table_state);
-
-    if let Some(rows_offset) = new_rows_offset {
-        rows_view.set_rows_from(rows_offset).unwrap();
-        csv_table_state.set_rows_offset(rows_offset);
+    // TODO: row_index() should probably be u64
+    let row_index = found_record.row_index() as u64;
+    let view_offset = rows_view.view_offset(row_index);
+    if !rows_view.in_view(row_index) {
+        rows_view.unstick_from_bottom();
+        rows_view.set_rows_from(view_offset).unwrap();
+        csv_table_state.set_rows_offset(rows_view.rows_from());
     }
 
-    if let Some(cols_offset) = new_cols_offset {
-        csv_table_state.set_cols_offset(cols_offset);
+    // Move the cell cursor to the found cell, which scrolls it into view
+    rows_view.set_selected(view_offset.saturating_sub(rows_view.rows_from()));
+    // The first of the matching columns that isn't hidden
+    let columns = &csv_table_state.columns;
+    if let Some(&column) = found_record
+        .column_indices()
+        .iter()
+        .find(|&&c| !columns.is_hidden(c))
+    {
+        csv_table_state.set_selected_column(column as u64);
     }
 }
 
-struct SeekableFile {
+#[derive(Parser, Debug)]
+struct Args {
+    /// CSV filename, optionally compressed with gzip, zstd, bzip2 or xz. Use
+    /// "-" to read from stdin.
     filename: String,
-    inner_file: Option<NamedTempFile>,
-}
 
-impl SeekableFile {
-    fn new(filename: &str) -> Result<SeekableFile> {
-        let mut f = File::open(filename).context(format!("Failed to open file: {}", filename))?;
-
-        let mut inner_file = NamedTempFile::new()?;
-        let inner_file_res;
-
-        // If not seekable, it most likely is due to process substitution using
-        // pipe - write out to a temp file to make it seekable
-        if f.seek(SeekFrom::Start(0)).is_err() {
-            let mut buffer: Vec<u8> = vec![];
-            // TODO: could have read by chunks, yolo for now
-            f.read_to_end(&mut buffer)?;
-            inner_file.write(&buffer)?;
-            inner_file_res = Some(inner_file);
-        } else {
-            inner_file_res = None;
-        }
+    /// Field delimiter: a single character, "tab", or "auto" to guess from the
+    /// file extension and content (default)
+    #[clap(short, long)]
+    delimiter: Option<String>,
 
-        Ok(SeekableFile {
-            filename: filename.to_string(),
-            inner_file: inner_file_res,
-        })
-    }
+    /// Treat the first row as data instead of headers; columns are named 1, 2, 3...
+    #[clap(long)]
+    no_headers: bool,
 
-    fn filename(&self) -> &str {
-        if let Some(f) = &self.inner_file {
-            f.path().to_str().unwrap()
-        } else {
-            self.filename.as_str()
-        }
-    }
-}
+    /// Keep reading records appended to the file, like `tail -f`. The view
+    /// sticks to the bottom until scrolled up; press G to stick again.
+    #[clap(short, long)]
+    follow: bool,
 
-#[derive(Parser, Debug)]
-struct Args {
-    /// CSV filename
-    filename: String,
+    /// Save the row index to FILENAME.csvlens-idx (or the cache directory if
+    /// that is not writable), so that reopening the file is instant. A stale
+    /// index is extended if records were appended, or else rebuilt.
+    #[clap(long)]
+    index: bool,
+
+    /// Search and filter case insensitively. Ctrl-t in the prompt cycles
+    /// through case sensitive, insensitive and smart case.
+    #[clap(short, long)]
+    ignore_case: bool,
+
+    /// Search and filter case insensitively, unless the pattern contains an
+    /// uppercase character
+    #[clap(short = 'S', long, conflicts_with = "ignore-case")]
+    smart_case: bool,
+
+    /// Keep columns in view while scrolling horizontally: the first N
+    /// columns, or comma separated column names, which are moved to the front
+    #[clap(long, value_name = "COLUMNS")]
+    freeze: Option<String>,
+
+    /// Show numbers with thousands separators, e.g. 1,234,567
+    #[clap(long)]
+    thousands: bool,
+
+    /// Show decimal numbers with this many decimals
+    #[clap(long, value_name = "N")]
+    decimals: Option<usize>,
 
     /// Show stats for debugging
     #[clap(long)]
@@ -121,38 +174,83 @@ fn run_csvlens() -> Result<()> {
     let file = SeekableFile::new(args.filename.as_str())?;
     let filename = file.filename();
 
+    let delimiter = Delimiter::from_arg(&args.delimiter)?.resolve(filename, file.content_name());
+    let mut config = csv::CsvConfig::new(filename, delimiter, args.no_headers);
+    config.set_stream_status(file.stream_status());
+    config.set_follow(args.follow);
+    // Temp files backing streamed input don't outlive this run
+    config.set_persistent_index(args.index && file.stream_status().is_none());
+    // Column widths are measured as values are shown
+    config.set_value_format(ValueFormat {
+        thousands_separator: args.thousands,
+        decimals: args.decimals,
+    });
+    let config = Arc::new(config);
+
     // Some lines are reserved for plotting headers (3 lines for headers + 2 lines for status bar)
     let num_rows_not_visible = 5;
 
     // Number of rows that are visible in the current frame
     let num_rows = 50 - num_rows_not_visible;
-    let csvlens_reader =
-        csv::CsvLensReader::new(filename).context(format!("Failed to open file: {}", filename))?;
+    let csvlens_reader = csv::CsvLensReader::new(config.clone())
+        .context(format!("Failed to open file: {}", filename))?;
     let mut rows_view = view::RowsView::new(csvlens_reader, num_rows)?;
 
     let headers = rows_view.headers().clone();
 
+    if args.follow {
+        rows_view.handle_control(&Control::ScrollBottom)?;
+    }
+
+    let mut csv_table_state = CsvTableState::new(file.display_name().to_string(), headers.len());
+    if let Some(freeze) = &args.freeze {
+        csv_table_state.columns.freeze_from_arg(freeze, &headers)?;
+        // Start at the first column shown, which may have been moved
+        if let Some(&column) = csv_table_state.columns.order().first() {
+            csv_table_state.set_selected_column(column as u64);
+        }
+    }
+
+    csv_table_state.value_format = config.value_format();
+
     let stdout = io::stdout().into_raw_mode().unwrap();
     let stdout = AlternateScreen::from(stdout);
     let backend = TermionBackend::new(stdout);
     let mut terminal = Terminal::new(backend).unwrap();
 
-    let mut input_handler = InputHandler::new();
-    let mut csv_table_state = CsvTableState::new(filename.to_string(), headers.len());
+    let case = if args.ignore_case {
+        CaseMode::Insensitive
+    } else if args.smart_case {
+        CaseMode::Smart
+    } else {
+        CaseMode::Sensitive
+    };
+    let mut input_handler = InputHandler::new(MatchOptions {
+        case,
+        ..Default::default()
+    });
 
     let mut finder: Option<find::Finder> = None;
+    // Filters narrowed down by the current one, outermost first
+    let mut filter_stack: Vec<find::Finder> = vec![];
     let mut first_found_scrolled = false;
+    // Stats of the selected column, while the panel is open
+    let mut stats: Option<Stats> = None;
+    // Values of a column being counted, while the frequency view is open
+    let mut frequency: Option<Frequency> = None;
 
     loop {
         terminal
             .draw(|f| {
                 let size = f.size();
 
-                // TODO: check type of num_rows too big?
-                let frame_size_adjusted_num_rows =
-                    size.height.saturating_sub(num_rows_not_visible as u16) as u64;
                 rows_view
-                    .set_num_rows(frame_size_adjusted_num_rows)
+                    .set_num_rows(frame_num_rows(
+                        size,
+                        &csv_table_state,
+                        &headers,
+                        num_rows_not_visible,
+                    ))
                     .unwrap();
 
                 let rows = rows_view.rows();
@@ -166,6 +264,18 @@ fn run_csvlens() -> Result<()> {
 
         rows_view.handle_control(&control)?;
 
+        // Stats are of the filtered rows, so they need redoing if the filter
+        // might have changed
+        let open_stats = matches!(control, Control::ShowStats);
+        let filter_changed = matches!(
+            control,
+            Control::Find(..)
+                | Control::Filter(..)
+                | Control::FrequencySelect
+                | Control::PopFilter
+                | Control::BufferReset
+        );
+
         match control {
             Control::Quit => {
                 break;
@@ -174,15 +284,139 @@ fn run_csvlens() -> Result<()> {
                 csv_table_state.reset_buffer();
             }
             Control::ScrollLeft => {
-                let new_cols_offset = csv_table_state.cols_offset.saturating_sub(1);
-                csv_table_state.set_cols_offset(new_cols_offset);
+                let column = csv_table_state.selected_column as usize;
+                if let Some(c) = csv_table_state.columns.prev_visible(column) {
+                    csv_table_state.set_selected_column(c as u64);
+                }
             }
             Control::ScrollRight => {
-                if csv_table_state.has_more_cols_to_show() {
-                    let new_cols_offset = csv_table_state.cols_offset.saturating_add(1);
-                    csv_table_state.set_cols_offset(new_cols_offset);
+                let column = csv_table_state.selected_column as usize;
+                if let Some(c) = csv_table_state.columns.next_visible(column) {
+                    csv_table_state.set_selected_column(c as u64);
                 }
             }
+            Control::HideColumn => {
+                let column = csv_table_state.selected_column as usize;
+                let columns = &mut csv_table_state.columns;
+                // Select the column taking its place
+                let next = columns
+                    .next_visible(column)
+                    .or_else(|| columns.prev_visible(column));
+                if columns.hide(column) {
+                    if let Some(c) = next {
+                        csv_table_state.set_selected_column(c as u64);
+                    }
+                }
+            }
+            Control::ToggleFreeze => {
+                // Freeze the columns up to the selected one, or unfreeze
+                let column = csv_table_state.selected_column as usize;
+                let columns = &mut csv_table_state.columns;
+                match columns.position(column) {
+                    Some(i) if i >= columns.num_frozen() => columns.set_frozen(i + 1),
+                    _ => columns.set_frozen(0),
+                }
+            }
+            Control::ShowAllColumns => {
+                csv_table_state.columns.show_all();
+            }
+            Control::MoveColumnLeft => {
+                let column = csv_table_state.selected_column as usize;
+                csv_table_state.columns.move_left(column);
+            }
+            Control::MoveColumnRight => {
+                let column = csv_table_state.selected_column as usize;
+                csv_table_state.columns.move_right(column);
+            }
+            Control::WidenColumn => {
+                csv_table_state.resize_selected_column(COLUMN_WIDTH_STEP);
+            }
+            Control::NarrowColumn => {
+                csv_table_state.resize_selected_column(-COLUMN_WIDTH_STEP);
+            }
+            Control::ResetColumnWidth => {
+                let column = csv_table_state.selected_column as usize;
+                csv_table_state.columns.reset_width(column);
+            }
+            Control::ShowColumnPicker => {
+                let column = csv_table_state.selected_column as usize;
+                let columns = csv_table_state.columns.order();
+                let cursor = columns.iter().position(|&c| c == column).unwrap_or(0);
+                csv_table_state.column_picker = Some(ColumnPickerState::new(cursor));
+            }
+            Control::PickerUp | Control::PickerDown | Control::PickerToggle => {
+                let columns = &mut csv_table_state.columns;
+                if let Some(picker) = csv_table_state.column_picker.as_mut() {
+                    match control {
+                        Control::PickerUp => picker.cursor = picker.cursor.saturating_sub(1),
+                        Control::PickerDown => {
+                            let last = columns.order().len().saturating_sub(1);
+                            picker.cursor = min(picker.cursor + 1, last);
+                        }
+                        _ => columns.toggle(columns.order()[picker.cursor]),
+                    }
+                }
+                // The selected column may have just been hidden
+                let column = csv_table_state.selected_column as usize;
+                let column = csv_table_state.columns.nearest_visible(column);
+                csv_table_state.set_selected_column(column as u64);
+            }
+            Control::PickerClose => {
+                csv_table_state.column_picker = None;
+            }
+            Control::StatsClose => {
+                stats = None;
+            }
+            Control::ShowFrequency => {
+                let column = csv_table_state.selected_column as usize;
+                let f = Frequency::new(config.clone(), column, csv_table_state.filters.clone());
+                csv_table_state.frequency = Some(FrequencyViewState::new(f.column(), f.get()));
+                frequency = Some(f);
+            }
+            Control::FrequencyUp
+            | Control::FrequencyDown
+            | Control::FrequencyPageUp
+            | Control::FrequencyPageDown
+            | Control::FrequencyToggleOrder => {
+                if let Some(view) = csv_table_state.frequency.as_mut() {
+                    let page = rows_view.num_rows() as usize;
+                    match control {
+                        Control::FrequencyUp => view.cursor = view.cursor.saturating_sub(1),
+                        Control::FrequencyDown => view.cursor = view.cursor.saturating_add(1),
+                        Control::FrequencyPageUp => view.cursor = view.cursor.saturating_sub(page),
+                        Control::FrequencyPageDown => {
+                            view.cursor = view.cursor.saturating_add(page)
+                        }
+                        _ => {
+                            view.order = view.order.toggle();
+                            view.cursor = 0;
+                        }
+                    }
+                    // Clamped to the values counted when drawn
+                }
+            }
+            Control::FrequencySelect => {
+                if let Some(view) = csv_table_state.frequency.take() {
+                    if let Some(value) = view.selected() {
+                        let name = headers.get(view.column).map_or("", |h| h.as_str());
+                        match Matcher::exact_value(view.column, name, value) {
+                            Ok(matcher) => apply_filter(
+                                matcher,
+                                &config,
+                                &mut finder,
+                                &mut filter_stack,
+                                &mut rows_view,
+                            ),
+                            Err(e) => csv_table_state.set_error(e.to_string()),
+                        }
+                    }
+                }
+                frequency = None;
+            }
+            Control::FrequencyClose => {
+                csv_table_state.frequency = None;
+                frequency = None;
+            }
             Control::ScrollToNextFound if !rows_view.is_filter() => {
                 if let Some(fdr) = finder.as_mut() {
                     if let Some(found_record) = fdr.next() {
@@ -197,29 +431,106 @@ fn run_csvlens() -> Result<()> {
                     }
                 }
             }
-            Control::Find(s) => {
-                finder = Some(find::Finder::new(filename, s.as_str()).unwrap());
-                first_found_scrolled = false;
-                rows_view.reset_filter().unwrap();
+            Control::Find(s, options) => {
                 csv_table_state.reset_buffer();
+                match Matcher::parse(s.as_str(), options, &headers) {
+                    Ok(matcher) => {
+                        finder = Some(find::Finder::new(config.clone(), matcher).unwrap());
+                        filter_stack.clear();
+                        first_found_scrolled = false;
+                        rows_view.reset_filter().unwrap();
+                    }
+                    Err(e) => csv_table_state.set_error(e.to_string()),
+                }
             }
-            Control::Filter(s) => {
-                finder = Some(find::Finder::new(filename, s.as_str()).unwrap());
+            Control::Filter(s, options) => {
                 csv_table_state.reset_buffer();
-                rows_view.set_rows_from(0).unwrap();
-                rows_view.set_filter(finder.as_ref().unwrap()).unwrap();
+                match Matcher::parse(s.as_str(), options, &headers) {
+                    Ok(matcher) => apply_filter(
+                        matcher,
+                        &config,
+                        &mut finder,
+                        &mut filter_stack,
+                        &mut rows_view,
+                    ),
+                    Err(e) => csv_table_state.set_error(e.to_string()),
+                }
             }
             Control::BufferContent(buf) => {
-                csv_table_state.set_buffer(input_handler.mode(), buf.as_str());
+                csv_table_state.set_buffer(
+                    input_handler.mode(),
+                    buf.as_str(),
+                    input_handler.match_options(),
+                );
             }
             Control::BufferReset => {
                 csv_table_state.reset_buffer();
                 if finder.is_some() {
                     finder = None;
+                    filter_stack.clear();
                     csv_table_state.finder_state = FinderState::FinderInactive;
                     rows_view.reset_filter().unwrap();
                 }
             }
+            Control::ToggleSort => {
+                // Ascending, then descending, then back to file order
+                let column = csv_table_state.selected_column as usize;
+                let order = match rows_view.sorter() {
+                    Some(s) if s.column() == column => match s.order() {
+                        SortOrder::Ascending => Some(SortOrder::Descending),
+                        SortOrder::Descending => None,
+                    },
+                    _ => Some(SortOrder::Ascending),
+                };
+                let sorter = order.map(|order| Sorter::new(config.clone(), column, order));
+                rows_view.set_sorter(sorter)?;
+            }
+            Control::ToggleTransposed => {
+                // Keep the selected record in view, first in the transposed view
+                let selected_offset = rows_view.selected_offset();
+                csv_table_state.transposed = !csv_table_state.transposed;
+                let num_rows = frame_num_rows(
+                    terminal.size()?,
+                    &csv_table_state,
+                    &headers,
+                    num_rows_not_visible,
+                );
+                rows_view.set_num_rows(num_rows)?;
+                if let Some(offset) = selected_offset {
+                    rows_view.set_rows_from(offset)?;
+                    rows_view.set_selected(offset.saturating_sub(rows_view.rows_from()));
+                }
+            }
+            Control::ShowDetail => {
+                csv_table_state.detail = Some(DetailState::new());
+            }
+            Control::DetailScrollDown | Control::DetailScrollUp | Control::DetailToggle => {
+                if let Some(detail) = csv_table_state.detail.as_mut() {
+                    match control {
+                        Control::DetailScrollDown => {
+                            detail.scroll = detail.scroll.saturating_add(1)
+                        }
+                        Control::DetailScrollUp => detail.scroll = detail.scroll.saturating_sub(1),
+                        _ => detail.toggle(),
+                    }
+                }
+            }
+            Control::DetailClose => {
+                csv_table_state.detail = None;
+            }
+            Control::PopFilter if rows_view.is_filter() => {
+                // The outer filters kept finding in the background, so there
+                // is nothing to rescan
+                finder = filter_stack.pop();
+                rows_view.set_rows_from(0).unwrap();
+                match &finder {
+                    Some(f) => rows_view.set_filter(f).unwrap(),
+                    None => {
+                        csv_table_state.finder_state = FinderState::FinderInactive;
+                        rows_view.reset_filter().unwrap();
+                    }
+                }
+            }
             _ => {}
         }
 
@@ -259,16 +570,58 @@ fn run_csvlens() -> Result<()> {
         csv_table_state.set_rows_offset(rows_view.rows_from());
         csv_table_state.selected = rows_view.selected();
 
+        // new rows might have arrived if input is still being streamed in
+        rows_view.refresh_if_incomplete()?;
+        csv_table_state.following = rows_view.is_sticking_to_bottom();
+
         if let Some(n) = rows_view.get_total_line_numbers() {
-            csv_table_state.set_total_line_number(n);
+            csv_table_state.set_total_line_number(n, true);
         } else if let Some(n) = rows_view.get_total_line_numbers_approx() {
-            csv_table_state.set_total_line_number(n);
+            csv_table_state.set_total_line_number(n, !config.is_growing());
+        }
+
+        csv_table_state.index_progress = rows_view.get_index_progress();
+
+        if let Some(e) = file.stream_status().and_then(|s| s.error()) {
+            csv_table_state.set_stream_error(e);
+        }
+        if let Some(e) = rows_view.get_index_error() {
+            csv_table_state.set_stream_error(e);
         }
 
         if let Some(f) = &finder {
             // TODO: need to create a new finder every time?
             csv_table_state.finder_state = FinderState::from_finder(f, &rows_view);
         }
+        csv_table_state.sort = rows_view.sorter().map(|s| SortState {
+            column: s.column(),
+            order: s.order(),
+            done: s.done(),
+        });
+
+        csv_table_state.filters = match &finder {
+            Some(f) if rows_view.is_filter() => f.chain(),
+            _ => vec![],
+        };
+
+        let column = csv_table_state.selected_column as usize;
+        let stats_outdated = match &stats {
+            Some(s) => s.column() != column || filter_changed,
+            None => open_stats,
+        };
+        if stats_outdated {
+            let filters = csv_table_state.filters.clone();
+            stats = Some(Stats::new(config.clone(), column, filters));
+        }
+        csv_table_state.stats = stats.as_ref().map(|s| s.get());
+
+        if let (Some(f), Some(view)) = (&frequency, csv_table_state.frequency.as_mut()) {
+            view.table = f.get();
+        }
+
+        // Types and widths of the columns, from the first records and then
+        // from the rest of the file as it is indexed
+        csv_table_state.profile = rows_view.get_column_profile();
 
         //csv_table_state.debug = format!("{:?}", rows_view.rows_from());
     }
@@ -278,7 +631,7 @@ fn run_csvlens() -> Result<()> {
 
 fn main() {
     if let Err(e) = run_csvlens() {
-        println!("{}", e.to_string());
+        println!("{}", e);
         std::process::exit(1);
     }
 }
@@ -0,0 +1,534 @@
+use crate::expr::Expr;
+
+use anyhow::{anyhow, bail, Result};
+use csv::StringRecord;
+use regex::{Regex, RegexBuilder};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CaseMode {
+    #[default]
+    Sensitive,
+    Insensitive,
+    /// Insensitive unless the pattern contains an uppercase character, like
+    /// smartcase in vim
+    Smart,
+}
+
+impl CaseMode {
+    /// The next mode when toggling through them
+    pub fn next(&self) -> CaseMode {
+        match self {
+            CaseMode::Sensitive => CaseMode::Insensitive,
+            CaseMode::Insensitive => CaseMode::Smart,
+            CaseMode::Smart => CaseMode::Sensitive,
+        }
+    }
+}
+
+/// How the text typed for find and filter is interpreted
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MatchOptions {
+    /// Match the text as is instead of as a regular expression
+    pub literal: bool,
+    pub case: CaseMode,
+}
+
+impl MatchOptions {
+    /// Short description shown next to the prompt and in the status line
+    pub fn label(&self) -> Option<String> {
+        let mut labels = vec![];
+        if self.literal {
+            labels.push("literal");
+        }
+        match self.case {
+            CaseMode::Sensitive => {}
+            CaseMode::Insensitive => labels.push("ignore case"),
+            CaseMode::Smart => labels.push("smart case"),
+        }
+        if labels.is_empty() {
+            None
+        } else {
+            Some(labels.join(", "))
+        }
+    }
+}
+
+/// Column that matching is restricted to
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnScope {
+    pub index: usize,
+    pub name: String,
+    /// Whether the whole field has to match rather than a part of it
+    pub whole_field: bool,
+}
+
+/// Pattern or expression for find and filter, compiled once and shared by the
+/// background finder and the UI for highlighting
+#[derive(Clone, Debug)]
+pub struct Matcher {
+    pattern: String,
+    options: MatchOptions,
+    kind: MatcherKind,
+    /// Select records that don't match instead
+    inverted: bool,
+}
+
+#[derive(Clone, Debug)]
+enum MatcherKind {
+    Pattern {
+        regex: Regex,
+        column: Option<ColumnScope>,
+    },
+    Expression(Expr),
+}
+
+impl Matcher {
+    /// Parse a query typed in the find or filter prompt. Besides a pattern to
+    /// match in any column, it can be restricted to a single column:
+    ///
+    /// * `name=value`: the whole field equals (or fully matches) value
+    /// * `name~pattern`: the field contains pattern
+    /// * `#N:pattern`: same as above for the Nth column, counting from 1
+    ///
+    /// A leading `!` inverts the query to select the records that don't match.
+    ///
+    /// Column names are resolved against `headers`, ignoring case if there is
+    /// no exact match. If the name is not a column, the whole query is taken
+    /// as the pattern. Queries using comparisons, `AND`, `OR` and the like are
+    /// parsed as an [`Expr`] instead.
+    pub fn parse(query: &str, options: MatchOptions, headers: &[String]) -> Result<Matcher> {
+        if let Some(rest) = query.strip_prefix('!') {
+            let mut matcher = Matcher::parse_query(rest, options, headers)?;
+            matcher.inverted = true;
+            return Ok(matcher);
+        }
+        Matcher::parse_query(query, options, headers)
+    }
+
+    fn parse_query(query: &str, options: MatchOptions, headers: &[String]) -> Result<Matcher> {
+        if let Some(expr) = Expr::parse(query, options, headers)? {
+            return Ok(Matcher {
+                pattern: query.to_owned(),
+                options,
+                kind: MatcherKind::Expression(expr),
+                inverted: false,
+            });
+        }
+        if let Some(rest) = query.strip_prefix('#') {
+            if let Some((n, pattern)) = rest.split_once(':') {
+                if let Ok(n) = n.parse::<usize>() {
+                    if n == 0 || n > headers.len() {
+                        bail!("No column #{}, columns are #1 to #{}", n, headers.len());
+                    }
+                    let column = ColumnScope {
+                        index: n - 1,
+                        name: headers[n - 1].clone(),
+                        whole_field: false,
+                    };
+                    return Matcher::new_scoped(pattern, options, Some(column));
+                }
+            }
+        }
+        if let Some(i) = query.find(['=', '~']) {
+            let name = query[..i].trim();
+            if let Some(index) = find_column(name, headers) {
+                let column = ColumnScope {
+                    index,
+                    name: headers[index].clone(),
+                    whole_field: query[i..].starts_with('='),
+                };
+                return Matcher::new_scoped(query[i + 1..].trim(), options, Some(column));
+            }
+        }
+        Matcher::new(query, options)
+    }
+
+    pub fn new(pattern: &str, options: MatchOptions) -> Result<Matcher> {
+        Matcher::new_scoped(pattern, options, None)
+    }
+
+    /// Match fields in a column that are exactly `value`, e.g. picked from
+    /// the values in the column rather than typed
+    pub fn exact_value(column_index: usize, column_name: &str, value: &str) -> Result<Matcher> {
+        let column = ColumnScope {
+            index: column_index,
+            name: column_name.to_owned(),
+            whole_field: true,
+        };
+        let options = MatchOptions {
+            literal: true,
+            case: CaseMode::Sensitive,
+        };
+        Matcher::new_scoped(value, options, Some(column))
+    }
+
+    fn new_scoped(
+        pattern: &str,
+        options: MatchOptions,
+        column: Option<ColumnScope>,
+    ) -> Result<Matcher> {
+        let whole_field = column.as_ref().is_some_and(|c| c.whole_field);
+        let regex = build_regex(pattern, options, whole_field)?;
+        Ok(Matcher {
+            pattern: pattern.to_owned(),
+            options,
+            kind: MatcherKind::Pattern { regex, column },
+            inverted: false,
+        })
+    }
+
+    /// The pattern as typed by the user, without any column prefix or `!`, or
+    /// the whole expression
+    pub fn pattern(&self) -> &str {
+        self.pattern.as_str()
+    }
+
+    pub fn options(&self) -> MatchOptions {
+        self.options
+    }
+
+    pub fn column(&self) -> Option<&ColumnScope> {
+        match &self.kind {
+            MatcherKind::Pattern { column, .. } => column.as_ref(),
+            MatcherKind::Expression(_) => None,
+        }
+    }
+
+    pub fn is_expression(&self) -> bool {
+        matches!(self.kind, MatcherKind::Expression(_))
+    }
+
+    pub fn is_inverted(&self) -> bool {
+        self.inverted
+    }
+
+    fn is_in_scope(&self, column_index: usize) -> bool {
+        match self.column() {
+            Some(c) => c.index == column_index,
+            None => true,
+        }
+    }
+
+    /// Whether the field `s` in column `column_index` matches. Always false
+    /// for an expression, which needs the whole record. Not affected by
+    /// inversion.
+    pub fn is_match(&self, column_index: usize, s: &str) -> bool {
+        match &self.kind {
+            MatcherKind::Pattern { regex, .. } => {
+                self.is_in_scope(column_index) && regex.is_match(s)
+            }
+            MatcherKind::Expression(_) => false,
+        }
+    }
+
+    /// Indices of the columns in `record` that match. For an expression,
+    /// these are the columns it refers to if the record satisfies it. An
+    /// inverted matcher gives the columns it looks at if nothing matches.
+    pub fn matching_columns(&self, record: &StringRecord) -> Vec<usize> {
+        let columns = match &self.kind {
+            MatcherKind::Pattern { .. } => record
+                .iter()
+                .enumerate()
+                .filter(|(column_index, field)| self.is_match(*column_index, field))
+                .map(|(column_index, _)| column_index)
+                .collect(),
+            MatcherKind::Expression(expr) => {
+                if expr.is_match(record) {
+                    expr.columns()
+                } else {
+                    vec![]
+                }
+            }
+        };
+        if !self.inverted {
+            columns
+        } else if columns.is_empty() {
+            match &self.kind {
+                MatcherKind::Pattern { column, .. } => vec![column.as_ref().map_or(0, |c| c.index)],
+                MatcherKind::Expression(expr) => expr.columns(),
+            }
+        } else {
+            vec![]
+        }
+    }
+
+    /// Byte ranges of non-overlapping, non-empty matches in the field `s` in
+    /// column `column_index`. Expressions and inverted matchers don't
+    /// highlight anything.
+    pub fn find_ranges(&self, column_index: usize, s: &str) -> Vec<(usize, usize)> {
+        match &self.kind {
+            MatcherKind::Pattern { regex, .. }
+                if !self.inverted && self.is_in_scope(column_index) =>
+            {
+                regex
+                    .find_iter(s)
+                    .filter(|m| !m.as_str().is_empty())
+                    .map(|m| (m.start(), m.end()))
+                    .collect()
+            }
+            _ => vec![],
+        }
+    }
+}
+
+/// Compile a pattern typed by the user, matching either a part of the field
+/// or the `whole_field`
+pub(crate) fn build_regex(
+    pattern: &str,
+    options: MatchOptions,
+    whole_field: bool,
+) -> Result<Regex> {
+    let mut regex_pattern = if options.literal {
+        regex::escape(pattern)
+    } else {
+        pattern.to_owned()
+    };
+    if whole_field {
+        regex_pattern = format!("^(?:{})$", regex_pattern);
+    }
+    let case_insensitive = match options.case {
+        CaseMode::Sensitive => false,
+        CaseMode::Insensitive => true,
+        CaseMode::Smart => !has_uppercase(pattern, options.literal),
+    };
+    RegexBuilder::new(regex_pattern.as_str())
+        .case_insensitive(case_insensitive)
+        .build()
+        .map_err(|e| anyhow!("Invalid regex: {}", regex_error_reason(&e)))
+}
+
+/// The reason a regex failed to compile, on one line for the status bar.
+/// Syntax errors repeat the pattern with a caret before the reason.
+fn regex_error_reason(e: &regex::Error) -> String {
+    let message = e.to_string();
+    let reason = message.lines().last().unwrap_or_default();
+    reason.trim_start_matches("error: ").to_owned()
+}
+
+pub(crate) fn find_column(name: &str, headers: &[String]) -> Option<usize> {
+    if name.is_empty() {
+        return None;
+    }
+    headers.iter().position(|h| h == name).or_else(|| {
+        let name = name.to_lowercase();
+        headers.iter().position(|h| h.to_lowercase() == name)
+    })
+}
+
+/// Whether the pattern has an uppercase character to match. For a regex,
+/// escape sequences like `\D` and `\p{Lu}` don't count.
+fn has_uppercase(pattern: &str, literal: bool) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' && !literal {
+            match chars.next() {
+                Some('p') | Some('P') => {
+                    if chars.clone().next() == Some('{') {
+                        chars.by_ref().find(|&c| c == '}');
+                    } else {
+                        chars.next();
+                    }
+                }
+                _ => {}
+            }
+        } else if c.is_uppercase() {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regex() {
+        let m = Matcher::new(r"^ERR-\d+", MatchOptions::default()).unwrap();
+        assert!(m.is_match(0, "ERR-42: failed"));
+        assert!(!m.is_match(0, "no ERR-42"));
+        assert_eq!(m.find_ranges(0, "ERR-42: failed"), vec![(0, 6)]);
+
+        let m = Matcher::new("cat|dog", MatchOptions::default()).unwrap();
+        assert_eq!(m.find_ranges(0, "dog, cat"), vec![(0, 3), (5, 8)]);
+
+        let e = Matcher::new("(", MatchOptions::default()).err().unwrap();
+        assert_eq!(e.to_string(), "Invalid regex: unclosed group");
+    }
+
+    #[test]
+    fn test_literal() {
+        let options = MatchOptions {
+            literal: true,
+            ..Default::default()
+        };
+        let m = Matcher::new("a.b(", options).unwrap();
+        assert!(m.is_match(0, "xa.b(y"));
+        assert!(!m.is_match(0, "axb("));
+        assert_eq!(m.find_ranges(0, "a.b(a.b("), vec![(0, 4), (4, 8)]);
+    }
+
+    #[test]
+    fn test_empty_matches() {
+        let m = Matcher::new("x*", MatchOptions::default()).unwrap();
+        assert!(m.is_match(0, "abc"));
+        assert_eq!(m.find_ranges(0, "axxb"), vec![(1, 3)]);
+    }
+
+    fn case_options(case: CaseMode) -> MatchOptions {
+        MatchOptions {
+            case,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_ignore_case() {
+        let m = Matcher::new("london", case_options(CaseMode::Insensitive)).unwrap();
+        assert_eq!(m.find_ranges(0, "London, LONDON"), vec![(0, 6), (8, 14)]);
+        let m = Matcher::new("london", case_options(CaseMode::Sensitive)).unwrap();
+        assert!(!m.is_match(0, "London"));
+        // Unicode case folding
+        let m = Matcher::new("école", case_options(CaseMode::Insensitive)).unwrap();
+        assert_eq!(m.find_ranges(0, "ÉCOLE"), vec![(0, 6)]);
+        let m = Matcher::new("σ", case_options(CaseMode::Insensitive)).unwrap();
+        assert!(m.is_match(0, "Σ"));
+    }
+
+    #[test]
+    fn test_smart_case() {
+        let m = Matcher::new("london", case_options(CaseMode::Smart)).unwrap();
+        assert!(m.is_match(0, "LONDON"));
+        let m = Matcher::new("London", case_options(CaseMode::Smart)).unwrap();
+        assert!(m.is_match(0, "London"));
+        assert!(!m.is_match(0, "london"));
+        // Escape sequences are not uppercase characters to match
+        let m = Matcher::new(r"err\D\p{Lu}", case_options(CaseMode::Smart)).unwrap();
+        assert!(m.is_match(0, "ERR-A"));
+        let m = Matcher::new(r"\Wx", case_options(CaseMode::Smart)).unwrap();
+        assert!(m.is_match(0, "-X"));
+    }
+
+    #[test]
+    fn test_has_uppercase() {
+        assert!(has_uppercase("aB", false));
+        assert!(!has_uppercase(r"a\D", false));
+        assert!(has_uppercase(r"a\D", true));
+        assert!(!has_uppercase(r"\p{Greek}x", false));
+        assert!(!has_uppercase(r"\pLx", false));
+        assert!(has_uppercase(r"\p{Greek}X", false));
+    }
+
+    fn headers() -> Vec<String> {
+        vec!["City", "State", "Zip Code"]
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    fn parse(query: &str) -> Result<Matcher> {
+        Matcher::parse(query, MatchOptions::default(), &headers())
+    }
+
+    #[test]
+    fn test_parse_column() {
+        let m = parse("State=CA").unwrap();
+        assert_eq!(m.pattern(), "CA");
+        assert_eq!(
+            m.column(),
+            Some(&ColumnScope {
+                index: 1,
+                name: "State".to_owned(),
+                whole_field: true,
+            })
+        );
+        assert!(m.is_match(1, "CA"));
+        assert!(!m.is_match(1, "CAL"));
+        assert!(!m.is_match(0, "CA"));
+        assert_eq!(m.find_ranges(0, "CA"), vec![]);
+
+        let m = parse("city~york").unwrap();
+        assert_eq!(m.column().map(|c| c.index), Some(0));
+        assert!(m.is_match(0, "New york"));
+        assert!(!m.is_match(1, "york"));
+
+        let m = parse("State = CA ").unwrap();
+        assert_eq!(m.pattern(), "CA");
+        assert!(m.is_match(1, "CA"));
+
+        let m = parse("city ~ york").unwrap();
+        assert_eq!(m.pattern(), "york");
+        assert!(m.is_match(0, "New york"));
+
+        let m = parse("zip code~^9").unwrap();
+        assert_eq!(m.column().map(|c| c.index), Some(2));
+        assert_eq!(m.pattern(), "^9");
+
+        let m = parse("#2:A|B").unwrap();
+        assert_eq!(m.column().map(|c| c.index), Some(1));
+        assert!(m.is_match(1, "xBx"));
+        assert!(parse("#4:x").is_err());
+    }
+
+    #[test]
+    fn test_inverted() {
+        let m = parse("!HEALTHCHECK").unwrap();
+        assert!(m.is_inverted());
+        assert_eq!(m.pattern(), "HEALTHCHECK");
+        let record = StringRecord::from(vec!["GET", "/", "HEALTHCHECK"]);
+        assert!(m.matching_columns(&record).is_empty());
+        let record = StringRecord::from(vec!["GET", "/", "ok"]);
+        assert_eq!(m.matching_columns(&record), vec![0]);
+        assert!(m.find_ranges(2, "HEALTHCHECK").is_empty());
+
+        let m = parse("!State=CA").unwrap();
+        assert_eq!(m.column().map(|c| c.index), Some(1));
+        let record = StringRecord::from(vec!["Austin", "TX", "73301"]);
+        assert_eq!(m.matching_columns(&record), vec![1]);
+
+        let m = parse("!State = CA OR City = Austin").unwrap();
+        assert!(m.is_expression() && m.is_inverted());
+        assert!(m.matching_columns(&record).is_empty());
+        let record = StringRecord::from(vec!["Dallas", "TX", "75201"]);
+        assert_eq!(m.matching_columns(&record), vec![0, 1]);
+
+        assert!(!parse("[!]x").unwrap().is_inverted());
+    }
+
+    #[test]
+    fn test_exact_value() {
+        let m = Matcher::exact_value(1, "State", " C.A").unwrap();
+        assert!(m.is_match(1, " C.A"));
+        assert!(!m.is_match(1, "C.A"));
+        assert!(!m.is_match(1, " CxA"));
+        assert!(!m.is_match(0, " C.A"));
+        let m = Matcher::exact_value(1, "State", "").unwrap();
+        assert!(m.is_match(1, ""));
+        assert!(!m.is_match(1, "CA"));
+    }
+
+    #[test]
+    fn test_parse_expression() {
+        let m = parse("State = CA AND \"zip code\" ~ ^9").unwrap();
+        assert!(m.is_expression());
+        assert_eq!(m.pattern(), "State = CA AND \"zip code\" ~ ^9");
+        assert_eq!(m.column(), None);
+        let record = StringRecord::from(vec!["Fresno", "CA", "93650"]);
+        assert_eq!(m.matching_columns(&record), vec![1, 2]);
+        let record = StringRecord::from(vec!["Austin", "TX", "93650"]);
+        assert!(m.matching_columns(&record).is_empty());
+        assert!(m.find_ranges(1, "CA").is_empty());
+
+        assert!(parse("State = CA AND").is_err());
+        assert!(!parse("State=CA").unwrap().is_expression());
+    }
+
+    #[test]
+    fn test_parse_not_column() {
+        for query in ["Country=CA", "a=b", "=x", "#x:y", "x{2}~"] {
+            let m = parse(query).unwrap();
+            assert_eq!(m.column(), None);
+            assert_eq!(m.pattern(), query);
+        }
+    }
+}
@@ -0,0 +1,379 @@
+use crate::csv::CsvConfig;
+use crate::profile::Profiler;
+
+use anyhow::Result;
+use csv::{ByteRecord, Position, Reader};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Files smaller than this per available core are indexed with fewer threads
+pub const MIN_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Positions are collected at least this many records apart (same as the
+/// minimum interval of the position table)
+const COLLECT_INTERVAL: u64 = 100;
+
+/// Number of record positions kept at the start of each chunk, to find where
+/// the speculative parse of a chunk lines up with the previous one
+const NUM_SYNC_CANDIDATES: usize = 64;
+
+/// How often (in records) parsing progress is reported
+const PROGRESS_INTERVAL: u64 = 1000;
+
+/// Progress of building the index, shared with the UI
+#[derive(Debug)]
+pub struct IndexProgress {
+    base_records: u64,
+    bytes_total: u64,
+    bytes_parsed: AtomicU64,
+    records_parsed: AtomicU64,
+}
+
+impl IndexProgress {
+    /// `base_records` had already been indexed before the remaining
+    /// `bytes_total` bytes
+    pub fn new(base_records: u64, bytes_total: u64) -> IndexProgress {
+        IndexProgress {
+            base_records,
+            bytes_total,
+            bytes_parsed: AtomicU64::new(0),
+            records_parsed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn fraction(&self) -> f64 {
+        if self.bytes_total == 0 {
+            return 1.0;
+        }
+        let parsed = self.bytes_parsed.load(Ordering::Relaxed);
+        (parsed as f64 / self.bytes_total as f64).min(1.0)
+    }
+
+    /// Total number of records extrapolated from those parsed so far
+    pub fn estimate_records(&self) -> Option<usize> {
+        let bytes_parsed = self.bytes_parsed.load(Ordering::Relaxed);
+        if bytes_parsed == 0 {
+            return None;
+        }
+        let records = self.records_parsed.load(Ordering::Relaxed) as f64;
+        let estimate = records * self.bytes_total as f64 / bytes_parsed as f64;
+        Some(self.base_records as usize + estimate as usize)
+    }
+
+    fn add(&self, bytes: u64, records: u64) {
+        self.bytes_parsed.fetch_add(bytes, Ordering::Relaxed);
+        self.records_parsed.fetch_add(records, Ordering::Relaxed);
+    }
+}
+
+/// Records found while parsing a byte range of the file
+struct Chunk {
+    /// Positions of the first few records, candidates to line up with the end
+    /// of the previous chunk
+    sync_candidates: Vec<Position>,
+    /// The records at `sync_candidates`, which only count from where the
+    /// chunk lines up
+    sync_records: Vec<ByteRecord>,
+    /// Positions every COLLECT_INTERVAL records, counted from the start of
+    /// the chunk
+    positions: Vec<Position>,
+    /// Profile of a sample of the records after `sync_records`
+    profiler: Profiler,
+    /// Position of the first record at or after the end of the range
+    end: Position,
+}
+
+impl Chunk {
+    /// Profile of the records from sync candidate `i` on
+    fn profiler_from(&self, i: usize) -> Profiler {
+        let mut profiler = Profiler::new(self.profiler.format());
+        for record in self.sync_records.iter().skip(i) {
+            profiler.add(record);
+        }
+        profiler.merge(&self.profiler);
+        profiler
+    }
+}
+
+/// Index all records from `start` to the end of the file. The file is split
+/// into `num_chunks` byte ranges that are parsed in parallel, each starting
+/// from the first line in the range. That guess is wrong if the line break is
+/// within a quoted field, so each chunk is only accepted once the parse of
+/// the previous one ends on one of its record positions, or else parsed again
+/// from there.
+///
+/// `on_positions` is called with positions of records (numbered from the
+/// start of the file) in order, as chunks are accepted. Returns the position
+/// at the end of the file, and the profile of the columns over a sample of
+/// the records.
+pub fn build<F>(
+    config: Arc<CsvConfig>,
+    start: Position,
+    num_chunks: usize,
+    progress: Arc<IndexProgress>,
+    mut on_positions: F,
+) -> Result<(Position, Profiler)>
+where
+    F: FnMut(Vec<Position>),
+{
+    let file_size = File::open(config.filename())?.metadata()?.len();
+    let range_size = file_size.saturating_sub(start.byte());
+    let num_chunks = (num_chunks as u64).clamp(1, range_size.max(1));
+    let chunk_size = range_size / num_chunks;
+    let boundaries: Vec<u64> = (0..=num_chunks)
+        .map(|i| {
+            if i == num_chunks {
+                file_size
+            } else {
+                start.byte() + i * chunk_size
+            }
+        })
+        .collect();
+
+    let handles: Vec<thread::JoinHandle<Result<Chunk>>> = boundaries
+        .windows(2)
+        .enumerate()
+        .map(|(i, range)| {
+            let config = config.clone();
+            let progress = progress.clone();
+            let first = if i == 0 { Some(start.clone()) } else { None };
+            let (from, to) = (range[0], range[1]);
+            thread::spawn(move || {
+                let chunk_start = match first {
+                    Some(pos) => pos,
+                    None => {
+                        let mut pos = Position::new();
+                        pos.set_byte(next_line_start(config.filename(), from, to)?);
+                        pos
+                    }
+                };
+                parse_chunk(&config, chunk_start, to, Some(&progress))
+            })
+        })
+        .collect();
+
+    let mut expected = start;
+    let mut profiler = Profiler::new(config.value_format());
+    for (i, handle) in handles.into_iter().enumerate() {
+        let chunk = handle.join().unwrap()?;
+        let positions = if i == 0 {
+            expected = chunk.end.clone();
+            profiler.merge(&chunk.profiler_from(0));
+            chunk.positions
+        } else {
+            match align(&chunk, &expected) {
+                Some((sync_index, positions, end)) => {
+                    expected = end;
+                    profiler.merge(&chunk.profiler_from(sync_index));
+                    positions
+                }
+                None => {
+                    // The guessed start was within a quoted field
+                    let chunk = parse_chunk(&config, expected, boundaries[i + 1], None)?;
+                    expected = chunk.end.clone();
+                    profiler.merge(&chunk.profiler_from(0));
+                    chunk.positions
+                }
+            }
+        };
+        on_positions(positions);
+    }
+
+    Ok((expected, profiler))
+}
+
+/// Renumber the positions of a speculatively parsed chunk to follow
+/// `expected`, the end of the previous chunk, if they line up. Returns the
+/// index of the sync candidate they line up at as well.
+fn align(chunk: &Chunk, expected: &Position) -> Option<(usize, Vec<Position>, Position)> {
+    let sync_index = chunk
+        .sync_candidates
+        .iter()
+        .position(|p| p.byte() == expected.byte())?;
+    let sync = &chunk.sync_candidates[sync_index];
+    let renumber = |p: &Position| {
+        let mut pos = p.clone();
+        pos.set_line(expected.line() + p.line() - sync.line());
+        pos.set_record(expected.record() + p.record() - sync.record());
+        pos
+    };
+    let positions = chunk
+        .positions
+        .iter()
+        .filter(|p| p.record() >= sync.record())
+        .map(renumber)
+        .collect();
+    Some((sync_index, positions, renumber(&chunk.end)))
+}
+
+/// Byte offset of the first line that starts within `from..to`, or `to` if
+/// there is none
+fn next_line_start(filename: &str, from: u64, to: u64) -> Result<u64> {
+    let mut file = File::open(filename)?;
+    // Also look at the byte before, which might end the previous line
+    let mut offset = from.saturating_sub(1);
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buffer = vec![0; 64 * 1024];
+    while offset < to {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        if let Some(i) = buffer[..n].iter().position(|&b| b == b'\n') {
+            return Ok((offset + i as u64 + 1).max(from).min(to));
+        }
+        offset += n as u64;
+    }
+    Ok(to)
+}
+
+/// Parse records starting at `start`, until the first record at or after
+/// byte `to`
+fn parse_chunk(
+    config: &CsvConfig,
+    start: Position,
+    to: u64,
+    progress: Option<&IndexProgress>,
+) -> Result<Chunk> {
+    let mut reader: Reader<File> = config
+        .reader_builder()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(config.filename())?;
+    reader.seek_raw(SeekFrom::Start(start.byte()), start.clone())?;
+
+    let mut sync_candidates = vec![];
+    let mut sync_records = vec![];
+    let mut positions = vec![];
+    let mut profiler = Profiler::new(config.value_format());
+    let mut record = ByteRecord::new();
+    let mut num_records = 0;
+    let mut reported = (start.byte(), 0);
+    let end = loop {
+        let pos = reader.position().clone();
+        if pos.byte() >= to {
+            break pos;
+        }
+        if sync_candidates.len() < NUM_SYNC_CANDIDATES {
+            sync_candidates.push(pos.clone());
+        }
+        match reader.read_byte_record(&mut record) {
+            Ok(true) => {}
+            Ok(false) => break pos,
+            // Keep going past malformed records, they still count as rows
+            Err(_) if reader.position().byte() > pos.byte() => record.clear(),
+            Err(_) => break pos,
+        }
+        if sync_records.len() < NUM_SYNC_CANDIDATES {
+            sync_records.push(record.clone());
+        } else {
+            profiler.add_sampled(num_records, &record);
+        }
+        if num_records % COLLECT_INTERVAL == 0 {
+            positions.push(pos);
+        }
+        num_records += 1;
+        if let Some(progress) = progress {
+            if num_records % PROGRESS_INTERVAL == 0 {
+                let byte = reader.position().byte();
+                progress.add(byte - reported.0, num_records - reported.1);
+                reported = (byte, num_records);
+            }
+        }
+    };
+    if let Some(progress) = progress {
+        progress.add(to.saturating_sub(reported.0), num_records - reported.1);
+    }
+
+    Ok(Chunk {
+        sync_candidates,
+        sync_records,
+        positions,
+        profiler,
+        end,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::RecordScanner;
+    use crate::csv::ScanResult;
+    use std::io::Write;
+
+    fn build_positions(
+        config: Arc<CsvConfig>,
+        num_chunks: usize,
+    ) -> (Vec<Position>, Position, Profiler) {
+        let start = RecordScanner::new(config.clone())
+            .unwrap()
+            .position()
+            .clone();
+        let progress = Arc::new(IndexProgress::new(0, 0));
+        let mut positions = vec![];
+        let (end, profiler) =
+            build(config, start, num_chunks, progress, |p| positions.extend(p)).unwrap();
+        (positions, end, profiler)
+    }
+
+    fn scan_positions(config: Arc<CsvConfig>) -> (Vec<Position>, Profiler) {
+        let mut profiler = Profiler::new(config.value_format());
+        let mut scanner = RecordScanner::new(config).unwrap();
+        let mut positions = vec![];
+        while let ScanResult::Record(pos, record) = scanner.next() {
+            profiler.add_sampled(positions.len() as u64, &record);
+            positions.push(pos);
+        }
+        (positions, profiler)
+    }
+
+    fn assert_same_as_scan(config: Arc<CsvConfig>) {
+        let (all, all_profiler) = scan_positions(config.clone());
+        for num_chunks in [1, 2, 3, 7, 50] {
+            let (positions, end, profiler) = build_positions(config.clone(), num_chunks);
+            assert_eq!(end.record(), all.len() as u64 + config.header_offset());
+            // Each chunk is sampled from its own start
+            if num_chunks == 1 {
+                assert_eq!(profiler, all_profiler);
+            } else {
+                assert_eq!(
+                    profiler.profile(),
+                    all_profiler.profile(),
+                    "{} chunks",
+                    num_chunks
+                );
+            }
+            for pos in positions.iter() {
+                let i = (pos.record() - config.header_offset()) as usize;
+                assert_eq!(pos, &all[i], "{} chunks", num_chunks);
+            }
+        }
+    }
+
+    #[test]
+    fn test_simple() {
+        let config = Arc::new(CsvConfig::new("tests/data/simple.csv", b',', false));
+        assert_same_as_scan(config);
+    }
+
+    #[test]
+    fn test_quoted_newlines() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "a,b").unwrap();
+        for i in 0..1000 {
+            if i % 3 == 0 {
+                writeln!(file, "{},\"multi\nline\n{}\"", i, i).unwrap();
+            } else if i == 500 {
+                // one record that changes the type of the column
+                writeln!(file, "x,{}", i).unwrap();
+            } else {
+                writeln!(file, "{},\"x\r\ny\"\r", i).unwrap();
+            }
+        }
+        let config = Arc::new(CsvConfig::new(file.path().to_str().unwrap(), b',', false));
+        assert_same_as_scan(config);
+    }
+}
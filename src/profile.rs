@@ -0,0 +1,323 @@
+use crate::types::{ColumnType, ValueFormat};
+
+use csv::ByteRecord;
+use std::collections::BTreeMap;
+use unicode_width::UnicodeWidthStr;
+
+/// Records looked at before the first types and widths are published, so
+/// that they show up before the whole file is indexed
+pub const SAMPLE_SIZE: u64 = 1000;
+
+/// After the first SAMPLE_SIZE records, one in this many is profiled, which
+/// is plenty to tell types and widths apart and keeps indexing fast
+const SAMPLE_INTERVAL: u64 = 256;
+
+/// Values wider than this are counted as this wide, they are cut off anyway
+const MAX_WIDTH: usize = 255;
+
+/// Share of the values that fit in a column at its automatic width, so that a
+/// few long values don't make the column wide for all the others
+const WIDTH_PERCENTILE: f64 = 0.95;
+
+/// Types and widths of all columns, from the records sampled as far as the
+/// file has been indexed
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ColumnProfile {
+    pub types: Vec<ColumnType>,
+    /// Display width that most values fit in; empty before any records have
+    /// been seen
+    pub widths: Vec<u16>,
+}
+
+/// Gathers the types of the columns and how wide their values are as shown
+/// with `format`, record by record from the ones sampled while indexing.
+/// Profiles of consecutive parts of the file can be merged.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Profiler {
+    format: ValueFormat,
+    types: Vec<ColumnType>,
+    histograms: Vec<WidthHistogram>,
+    num_records: u64,
+}
+
+impl Profiler {
+    pub fn new(format: ValueFormat) -> Profiler {
+        Profiler {
+            format,
+            types: vec![],
+            histograms: vec![],
+            num_records: 0,
+        }
+    }
+
+    pub fn format(&self) -> ValueFormat {
+        self.format
+    }
+
+    pub fn num_records(&self) -> u64 {
+        self.num_records
+    }
+
+    pub fn add(&mut self, record: &ByteRecord) {
+        self.add_n(record, 1);
+    }
+
+    /// Add the record `i` records into a scan if it is sampled: all of the
+    /// first ones, then one in SAMPLE_INTERVAL standing in for the ones left
+    /// out, so that the start of the file doesn't count for more
+    pub fn add_sampled(&mut self, i: u64, record: &ByteRecord) {
+        if i < SAMPLE_SIZE {
+            self.add_n(record, 1);
+        } else if i.is_multiple_of(SAMPLE_INTERVAL) {
+            self.add_n(record, SAMPLE_INTERVAL);
+        }
+    }
+
+    /// Add a record that stands for `n` records
+    fn add_n(&mut self, record: &ByteRecord, n: u64) {
+        // Records may have more fields than the ones before
+        if record.len() > self.types.len() {
+            self.types.resize(record.len(), ColumnType::Empty);
+            self.histograms.resize(record.len(), WidthHistogram::new());
+        }
+        let columns = self.types.iter_mut().zip(self.histograms.iter_mut());
+        for ((t, histogram), field) in columns.zip(record.iter()) {
+            let value = String::from_utf8_lossy(field);
+            if *t != ColumnType::Text {
+                *t = t.merge(ColumnType::of(&value));
+            }
+            let formatted = match *t {
+                // Shown as they are
+                ColumnType::Text | ColumnType::Empty | ColumnType::Boolean => None,
+                _ => self.format.format(&value, *t),
+            };
+            let width = match formatted {
+                Some(formatted) => formatted.width(),
+                None if field.is_ascii() => ascii_width(field),
+                None => value.width(),
+            };
+            histogram.add_n(width, n);
+        }
+        self.num_records += n;
+    }
+
+    /// Add the records profiled by `other`
+    pub fn merge(&mut self, other: &Profiler) {
+        if other.types.len() > self.types.len() {
+            self.types.resize(other.types.len(), ColumnType::Empty);
+            self.histograms
+                .resize(other.types.len(), WidthHistogram::new());
+        }
+        for (t, &other_t) in self.types.iter_mut().zip(other.types.iter()) {
+            *t = t.merge(other_t);
+        }
+        for (h, other_h) in self.histograms.iter_mut().zip(other.histograms.iter()) {
+            h.merge(other_h);
+        }
+        self.num_records += other.num_records;
+    }
+
+    pub fn profile(&self) -> ColumnProfile {
+        ColumnProfile {
+            types: self.types.clone(),
+            widths: self
+                .histograms
+                .iter()
+                .map(|h| h.percentile(WIDTH_PERCENTILE))
+                .collect(),
+        }
+    }
+
+    /// Type of each column, with the number of values of each width
+    pub fn columns(&self) -> Vec<(ColumnType, BTreeMap<u16, u64>)> {
+        self.types
+            .iter()
+            .zip(self.histograms.iter())
+            .map(|(&t, h)| (t, h.counts()))
+            .collect()
+    }
+
+    /// Profile as saved from `columns()`
+    pub fn from_columns(
+        format: ValueFormat,
+        num_records: u64,
+        columns: Vec<(ColumnType, BTreeMap<u16, u64>)>,
+    ) -> Profiler {
+        let mut profiler = Profiler::new(format);
+        for (t, counts) in columns {
+            let mut histogram = WidthHistogram::new();
+            for (width, count) in counts {
+                histogram.add_n(width as usize, count);
+            }
+            profiler.types.push(t);
+            profiler.histograms.push(histogram);
+        }
+        profiler.num_records = num_records;
+        profiler
+    }
+}
+
+/// Display width of ASCII text, where control characters take no space
+fn ascii_width(text: &[u8]) -> usize {
+    text.iter().filter(|b| !b.is_ascii_control()).count()
+}
+
+/// Counts of values by display width
+#[derive(Clone, Debug, PartialEq)]
+struct WidthHistogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+impl WidthHistogram {
+    fn new() -> WidthHistogram {
+        WidthHistogram {
+            counts: vec![0; MAX_WIDTH + 1],
+            total: 0,
+        }
+    }
+
+    fn add_n(&mut self, width: usize, n: u64) {
+        self.counts[width.min(MAX_WIDTH)] += n;
+        self.total += n;
+    }
+
+    fn merge(&mut self, other: &WidthHistogram) {
+        for (c, &other_c) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *c += other_c;
+        }
+        self.total += other.total;
+    }
+
+    /// Counts of the widths that occur
+    fn counts(&self) -> BTreeMap<u16, u64> {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &c)| c > 0)
+            .map(|(w, &c)| (w as u16, c))
+            .collect()
+    }
+
+    /// Smallest width that at least `p` of the values fit in
+    fn percentile(&self, p: f64) -> u16 {
+        let target = (self.total as f64 * p).ceil() as u64;
+        let mut seen = 0;
+        for (width, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return width as u16;
+            }
+        }
+        MAX_WIDTH as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile_of(filename: &str, format: ValueFormat) -> Profiler {
+        let mut reader = csv::Reader::from_path(filename).unwrap();
+        let mut profiler = Profiler::new(format);
+        for record in reader.byte_records() {
+            profiler.add(&record.unwrap());
+        }
+        profiler
+    }
+
+    #[test]
+    fn test_profile() {
+        use ColumnType::*;
+        let profiler = profile_of("tests/data/cities.csv", ValueFormat::default());
+        let profile = profiler.profile();
+        assert_eq!(profiler.num_records(), 128);
+        assert_eq!(
+            profile.types,
+            vec![Integer, Integer, Integer, Text, Integer, Integer, Integer, Text, Text, Text]
+        );
+        // the few longest city names don't count
+        assert_eq!(profile.widths, vec![2, 2, 2, 1, 3, 2, 2, 1, 14, 2]);
+    }
+
+    #[test]
+    fn test_profile_display_width() {
+        let profile = profile_of("tests/data/unicode_emoji.csv", ValueFormat::default()).profile();
+        assert_eq!(profile.widths, vec![1, 8, 6]);
+        let profile = profile_of("tests/data/unicode_cjk.csv", ValueFormat::default()).profile();
+        assert_eq!(profile.widths, vec![1, 8, 43]);
+    }
+
+    #[test]
+    fn test_merge() {
+        let format = ValueFormat {
+            thousands_separator: true,
+            decimals: None,
+        };
+        let records: Vec<ByteRecord> = ["1,2022/01/01", "22,x", "1234567,2022-01-02,extra"]
+            .iter()
+            .map(|r| ByteRecord::from(r.split(',').collect::<Vec<&str>>()))
+            .collect();
+        let mut all = Profiler::new(format);
+        records.iter().for_each(|r| all.add(r));
+        let mut first = Profiler::new(format);
+        first.add(&records[0]);
+        let mut rest = Profiler::new(format);
+        rest.add(&records[1]);
+        rest.add(&records[2]);
+        first.merge(&rest);
+        assert_eq!(first, all);
+
+        use ColumnType::*;
+        let profile = all.profile();
+        assert_eq!(profile.types, vec![Integer, Text, Text]);
+        // as shown: 1,234,567
+        assert_eq!(profile.widths, vec![9, 10, 5]);
+
+        let restored = Profiler::from_columns(format, all.num_records(), all.columns());
+        assert_eq!(restored, all);
+    }
+
+    #[test]
+    fn test_ascii_width() {
+        let all: Vec<u8> = (0..128).collect();
+        for b in all.iter() {
+            let text = [*b];
+            assert_eq!(
+                ascii_width(&text),
+                std::str::from_utf8(&text).unwrap().width()
+            );
+        }
+        assert_eq!(
+            ascii_width(&all),
+            std::str::from_utf8(&all).unwrap().width()
+        );
+    }
+
+    #[test]
+    fn test_add_sampled() {
+        let mut profiler = Profiler::new(ValueFormat::default());
+        let mut sampled = Profiler::new(ValueFormat::default());
+        let num_records = SAMPLE_SIZE + 100 * SAMPLE_INTERVAL;
+        for i in 0..num_records {
+            let record = ByteRecord::from(vec![if i < SAMPLE_SIZE { "a" } else { "abc" }]);
+            profiler.add(&record);
+            sampled.add_sampled(i, &record);
+        }
+        assert_eq!(sampled.num_records(), num_records);
+        assert_eq!(sampled.profile(), profiler.profile());
+        assert_eq!(sampled.profile().widths, vec![3]);
+    }
+
+    #[test]
+    fn test_width_percentile() {
+        let mut histogram = WidthHistogram::new();
+        for width in 1..=100 {
+            histogram.add_n(width, 1);
+        }
+        histogram.add_n(1000, 1);
+        assert_eq!(histogram.percentile(0.5), 51);
+        assert_eq!(histogram.percentile(0.95), 96);
+        assert_eq!(histogram.percentile(1.0), MAX_WIDTH as u16);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::csv::Row;
+
+use csv::Position;
+
+/// Number of position table segments with decoded rows kept around
+const CACHE_NUM_BLOCKS: usize = 16;
+
+/// Decoded rows kept per segment. Segments of huge files are much larger,
+/// in which case only the part around the rows requested last is kept.
+pub const MAX_ROWS_PER_BLOCK: usize = 1000;
+
+/// Consecutive rows decoded from a segment of the position table
+#[derive(Debug)]
+pub struct RowBlock {
+    /// Row index of the first row in `rows`
+    pub first_row: u64,
+    pub rows: Vec<Row>,
+    /// Position of the row right after the last one in `rows`
+    pub next_pos: Position,
+}
+
+impl RowBlock {
+    pub fn end_row(&self) -> u64 {
+        self.first_row + self.rows.len() as u64
+    }
+
+    pub fn get(&self, index: u64) -> Option<&Row> {
+        if index < self.first_row {
+            return None;
+        }
+        self.rows.get((index - self.first_row) as usize)
+    }
+
+    /// Insert rows starting at `first_row` in front, which must end right
+    /// before the first row of the block. If that makes the block too large,
+    /// it is replaced by these rows instead.
+    pub fn prepend(&mut self, first_row: u64, mut rows: Vec<Row>, next_pos: Position) {
+        if rows.len() + self.rows.len() > MAX_ROWS_PER_BLOCK {
+            self.rows = rows;
+            self.next_pos = next_pos;
+        } else {
+            rows.append(&mut self.rows);
+            self.rows = rows;
+        }
+        self.first_row = first_row;
+    }
+
+    /// Append rows following the last one, dropping rows from the front if
+    /// the block gets too large
+    pub fn extend(&mut self, rows: Vec<Row>, next_pos: Position) {
+        self.rows.extend(rows);
+        self.next_pos = next_pos;
+        if self.rows.len() > MAX_ROWS_PER_BLOCK {
+            let excess = self.rows.len() - MAX_ROWS_PER_BLOCK;
+            self.rows.drain(..excess);
+            self.first_row += excess as u64;
+        }
+    }
+}
+
+/// Least recently used cache of decoded rows, keyed by the first row index of
+/// the position table segment they are in
+pub struct RowCache {
+    // Most recently used last. Small enough that a linear scan is fine.
+    blocks: Vec<(u64, RowBlock)>,
+}
+
+impl RowCache {
+    pub fn new() -> RowCache {
+        RowCache { blocks: vec![] }
+    }
+
+    pub fn get(&mut self, segment: u64) -> Option<&mut RowBlock> {
+        let i = self.blocks.iter().position(|(k, _)| *k == segment)?;
+        let entry = self.blocks.remove(i);
+        self.blocks.push(entry);
+        self.blocks.last_mut().map(|(_, block)| block)
+    }
+
+    pub fn insert(&mut self, segment: u64, block: RowBlock) {
+        self.blocks.retain(|(k, _)| *k != segment);
+        if self.blocks.len() >= CACHE_NUM_BLOCKS {
+            self.blocks.remove(0);
+        }
+        self.blocks.push((segment, block));
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(first_row: u64, len: u64) -> RowBlock {
+        let rows = (first_row..first_row + len)
+            .map(|i| Row::new(i as usize + 1, vec![]))
+            .collect();
+        RowBlock {
+            first_row,
+            rows,
+            next_pos: Position::new(),
+        }
+    }
+
+    #[test]
+    fn test_block_extend() {
+        let mut b = block(10, 5);
+        assert_eq!(b.get(9), None);
+        assert_eq!(b.get(14).map(|r| r.record_num), Some(15));
+        assert_eq!(b.get(15), None);
+
+        let rows = block(15, MAX_ROWS_PER_BLOCK as u64).rows;
+        b.extend(rows, Position::new());
+        assert_eq!(b.first_row, 15);
+        assert_eq!(b.end_row(), 15 + MAX_ROWS_PER_BLOCK as u64);
+    }
+
+    #[test]
+    fn test_lru() {
+        let mut cache = RowCache::new();
+        for i in 0..CACHE_NUM_BLOCKS as u64 {
+            cache.insert(i * 100, block(i * 100, 1));
+        }
+        // Touch the oldest so that the second oldest gets evicted instead
+        assert!(cache.get(0).is_some());
+        cache.insert(10000, block(10000, 1));
+        assert!(cache.get(0).is_some());
+        assert!(cache.get(100).is_none());
+        assert!(cache.get(10000).is_some());
+    }
+}
@@ -0,0 +1,317 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+use tempfile::NamedTempFile;
+
+/// Filename that stands for stdin
+const STDIN_FILENAME: &str = "-";
+
+/// Size of each read when copying input to the backing temp file
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+impl Compression {
+    /// Longest magic number among supported formats: bzip2's stream header
+    /// followed by the magic of the first block
+    const MAGIC_LEN: usize = 10;
+
+    fn from_magic(magic: &[u8]) -> Option<Compression> {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else if is_bzip2(magic) {
+            Some(Compression::Bzip2)
+        } else if magic.starts_with(&[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00]) {
+            Some(Compression::Xz)
+        } else {
+            None
+        }
+    }
+
+    fn extensions(&self) -> &[&str] {
+        match self {
+            Compression::Gzip => &["gz", "gzip"],
+            Compression::Zstd => &["zst", "zstd"],
+            Compression::Bzip2 => &["bz2", "bzip2"],
+            Compression::Xz => &["xz"],
+        }
+    }
+
+    fn decoder(&self, reader: Box<dyn Read + Send>) -> Result<Box<dyn Read + Send>> {
+        let decoder: Box<dyn Read + Send> = match self {
+            Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+            Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(reader)),
+            Compression::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(reader)),
+        };
+        Ok(decoder)
+    }
+}
+
+/// "BZh", the block size as a digit, then the magic of the first block or of
+/// the end of an empty stream. "BZh" alone could well start a csv header.
+fn is_bzip2(magic: &[u8]) -> bool {
+    const BLOCK_MAGIC: [u8; 6] = [0x31, 0x41, 0x59, 0x26, 0x53, 0x59];
+    const END_MAGIC: [u8; 6] = [0x17, 0x72, 0x45, 0x38, 0x50, 0x90];
+    magic.len() >= 10
+        && magic.starts_with(b"BZh")
+        && (b'1'..=b'9').contains(&magic[3])
+        && (magic[4..10] == BLOCK_MAGIC || magic[4..10] == END_MAGIC)
+}
+
+/// Progress of copying input into the backing temp file, which is done in
+/// the background so that the available prefix can be viewed right away.
+#[derive(Clone, Debug)]
+pub struct StreamStatus {
+    inner: Arc<Mutex<StreamState>>,
+}
+
+#[derive(Debug)]
+struct StreamState {
+    has_first_line: bool,
+    done: bool,
+    error: Option<String>,
+}
+
+impl StreamStatus {
+    pub fn new() -> StreamStatus {
+        let state = StreamState {
+            has_first_line: false,
+            done: false,
+            error: None,
+        };
+        StreamStatus {
+            inner: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.inner.lock().unwrap().done
+    }
+
+    pub fn set_done(&self) {
+        self.inner.lock().unwrap().done = true;
+    }
+
+    pub fn error(&self) -> Option<String> {
+        self.inner.lock().unwrap().error.clone()
+    }
+
+    fn has_first_line(&self) -> bool {
+        let m = self.inner.lock().unwrap();
+        m.has_first_line || m.done
+    }
+
+    fn wait_first_line(&self) {
+        while !self.has_first_line() {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+
+    #[cfg(test)]
+    pub fn wait_done(&self) {
+        while !self.is_done() {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+}
+
+/// A file that can be opened by path and seeked, as required for indexing.
+/// Input that is not seekable (e.g. stdin or process substitution) or
+/// compressed is copied to a temp file by a background thread.
+pub struct SeekableFile {
+    filename: String,
+    inner_file: Option<NamedTempFile>,
+    compression: Option<Compression>,
+    stream_status: Option<StreamStatus>,
+}
+
+impl SeekableFile {
+    /// Open `filename`, or stdin if it is "-"
+    pub fn new(filename: &str) -> Result<SeekableFile> {
+        let (mut input, is_seekable): (Box<dyn Read + Send>, bool) = if filename == STDIN_FILENAME {
+            (Box::new(io::stdin()), false)
+        } else {
+            let mut f =
+                File::open(filename).context(format!("Failed to open file: {}", filename))?;
+            let is_seekable = f.seek(SeekFrom::Start(0)).is_ok();
+            (Box::new(f), is_seekable)
+        };
+
+        // Sniff compression from the first bytes. These are consumed from the
+        // input, so they are chained back in front of the rest below.
+        let mut magic = vec![];
+        (&mut input)
+            .take(Compression::MAGIC_LEN as u64)
+            .read_to_end(&mut magic)?;
+        let compression = Compression::from_magic(&magic);
+
+        if compression.is_none() && is_seekable {
+            return Ok(SeekableFile {
+                filename: filename.to_string(),
+                inner_file: None,
+                compression,
+                stream_status: None,
+            });
+        }
+
+        // If not seekable, it most likely is due to a pipe - write out to a
+        // temp file to make it seekable
+        let inner_file = NamedTempFile::new()?;
+        let output = inner_file.as_file().try_clone()?;
+        let input: Box<dyn Read + Send> = Box::new(Cursor::new(magic).chain(input));
+        let input = match compression {
+            Some(c) => c.decoder(input)?,
+            None => input,
+        };
+        let stream_status = StreamStatus::new();
+        spawn_copy(input, output, stream_status.clone());
+
+        // Headers are read right away, so wait for at least that much
+        stream_status.wait_first_line();
+
+        Ok(SeekableFile {
+            filename: filename.to_string(),
+            inner_file: Some(inner_file),
+            compression,
+            stream_status: Some(stream_status),
+        })
+    }
+
+    /// Path of the (decompressed) file that should be parsed
+    pub fn filename(&self) -> &str {
+        if let Some(f) = &self.inner_file {
+            f.path().to_str().unwrap()
+        } else {
+            self.filename.as_str()
+        }
+    }
+
+    /// Name of the file as given by the user
+    pub fn display_name(&self) -> &str {
+        if self.filename == STDIN_FILENAME {
+            "<stdin>"
+        } else {
+            self.filename.as_str()
+        }
+    }
+
+    /// Name of the file with any compression extension removed, e.g.
+    /// "data.tsv" for "data.tsv.gz". Useful to guess the content type.
+    pub fn content_name(&self) -> &str {
+        if let Some(c) = self.compression {
+            let path = Path::new(self.filename.as_str());
+            let has_extension = path
+                .extension()
+                .and_then(|x| x.to_str())
+                .map(|x| c.extensions().contains(&x.to_lowercase().as_str()))
+                .unwrap_or(false);
+            if has_extension {
+                if let Some(stem) = self.filename.rfind('.') {
+                    return &self.filename[..stem];
+                }
+            }
+        }
+        self.filename.as_str()
+    }
+
+    /// Status of the background copy, if the input is being streamed into a
+    /// temp file
+    pub fn stream_status(&self) -> Option<StreamStatus> {
+        self.stream_status.clone()
+    }
+}
+
+fn spawn_copy(mut input: Box<dyn Read + Send>, mut output: File, status: StreamStatus) {
+    thread::spawn(move || {
+        let mut buffer = vec![0; COPY_CHUNK_SIZE];
+        loop {
+            let n = match input.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    status.inner.lock().unwrap().error = Some(e.to_string());
+                    break;
+                }
+            };
+            if let Err(e) = output.write_all(&buffer[..n]) {
+                status.inner.lock().unwrap().error = Some(e.to_string());
+                break;
+            }
+            let mut m = status.inner.lock().unwrap();
+            if !m.has_first_line && buffer[..n].contains(&b'\n') {
+                m.has_first_line = true;
+            }
+        }
+        status.set_done();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn assert_same_content(file: &SeekableFile, expected_filename: &str) {
+        let content = fs::read(file.filename()).unwrap();
+        let expected = fs::read(expected_filename).unwrap();
+        assert_eq!(content, expected);
+    }
+
+    #[test]
+    fn test_plain() {
+        let file = SeekableFile::new("tests/data/cities.csv").unwrap();
+        assert_eq!(file.filename(), "tests/data/cities.csv");
+        assert_eq!(file.content_name(), "tests/data/cities.csv");
+        assert!(file.stream_status().is_none());
+    }
+
+    #[test]
+    fn test_compressed() {
+        for extension in ["gz", "zst", "bz2", "xz"] {
+            let filename = format!("tests/data/cities.csv.{}", extension);
+            let file = SeekableFile::new(filename.as_str()).unwrap();
+            assert_ne!(file.filename(), filename.as_str());
+            assert_eq!(file.display_name(), filename.as_str());
+            assert_eq!(file.content_name(), "tests/data/cities.csv");
+            file.stream_status().unwrap().wait_done();
+            assert_same_content(&file, "tests/data/cities.csv");
+        }
+    }
+
+    #[test]
+    fn test_header_like_magic() {
+        let file = SeekableFile::new("tests/data/bzh_header.csv").unwrap();
+        assert_eq!(file.filename(), "tests/data/bzh_header.csv");
+        assert!(file.stream_status().is_none());
+
+        assert_eq!(Compression::from_magic(b"BZhash,id\n"), None);
+        assert_eq!(Compression::from_magic(b"BZh9,a,b\n1"), None);
+        let bzip2 = fs::read("tests/data/cities.csv.bz2").unwrap();
+        assert_eq!(
+            Compression::from_magic(&bzip2[..Compression::MAGIC_LEN]),
+            Some(Compression::Bzip2)
+        );
+    }
+
+    #[test]
+    fn test_compressed_tsv() {
+        let file = SeekableFile::new("tests/data/cities.tsv.gz").unwrap();
+        assert_eq!(file.content_name(), "tests/data/cities.tsv");
+        file.stream_status().unwrap().wait_done();
+        assert_same_content(&file, "tests/data/cities.tsv");
+    }
+}
@@ -0,0 +1,541 @@
+use crate::csv::{CsvConfig, FilteredScan, FilteredScanner};
+
+use anyhow::Result;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Approximate memory used for sort keys before they are sorted and spilled
+/// to a temporary file as a run
+const RUN_SIZE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Rough per-entry overhead of a sort key on top of its text
+const ENTRY_OVERHEAD_BYTES: usize = 48;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+/// Sorts rows by a column in the background, producing the row indices in
+/// sorted order. Numbers sort numerically before any text, and rows with
+/// equal keys keep their file order.
+pub struct Sorter {
+    internal: Arc<Mutex<SorterInternalState>>,
+    column: usize,
+    order: SortOrder,
+}
+
+impl Sorter {
+    pub fn new(config: Arc<CsvConfig>, column: usize, order: SortOrder) -> Sorter {
+        let internal = SorterInternalState::init(config, column, order, RUN_SIZE_BYTES);
+        Sorter {
+            internal,
+            column,
+            order,
+        }
+    }
+
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    pub fn order(&self) -> SortOrder {
+        self.order
+    }
+
+    pub fn done(&self) -> bool {
+        self.internal.lock().unwrap().sorted.is_some()
+    }
+
+    /// Number of sorted rows, once done
+    pub fn count(&self) -> Option<usize> {
+        let m = self.internal.lock().unwrap();
+        m.sorted.as_ref().map(|s| s.len())
+    }
+
+    /// Row indices at positions `offset..offset + num_rows` of the sorted
+    /// order, once done
+    pub fn get_subset(&self, offset: usize, num_rows: usize) -> Option<Vec<u64>> {
+        let m = self.internal.lock().unwrap();
+        m.sorted.as_ref().map(|s| s.get(offset, num_rows))
+    }
+
+    /// Put `rows` in the sorted order, once done. Returns whether they were
+    /// sorted.
+    pub fn sort_rows(&self, rows: &mut [u64]) -> bool {
+        let m = self.internal.lock().unwrap();
+        match &m.sorted {
+            Some(s) => {
+                rows.sort_by_cached_key(|&row| s.rank(row));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Position of row `row_index` in the sorted order, once done
+    pub fn position_of(&self, row_index: u64) -> Option<u64> {
+        let m = self.internal.lock().unwrap();
+        m.sorted.as_ref().and_then(|s| s.rank(row_index))
+    }
+}
+
+impl Drop for Sorter {
+    fn drop(&mut self) {
+        self.internal.lock().unwrap().should_terminate = true;
+    }
+}
+
+struct SorterInternalState {
+    sorted: Option<SortedRows>,
+    should_terminate: bool,
+}
+
+impl SorterInternalState {
+    fn init(
+        config: Arc<CsvConfig>,
+        column: usize,
+        order: SortOrder,
+        run_size: usize,
+    ) -> Arc<Mutex<SorterInternalState>> {
+        let internal = SorterInternalState {
+            sorted: None,
+            should_terminate: false,
+        };
+        let m_state = Arc::new(Mutex::new(internal));
+        let _m = m_state.clone();
+        let _handle = thread::spawn(move || {
+            // Errors (e.g. no space for temp files) just leave it unsorted
+            if let Ok(Some(sorted)) = sort_rows(config, column, order, run_size, &_m) {
+                _m.lock().unwrap().sorted = Some(sorted);
+            }
+        });
+        m_state
+    }
+}
+
+/// Scan all records and sort them, returning `None` if terminated early
+fn sort_rows(
+    config: Arc<CsvConfig>,
+    column: usize,
+    order: SortOrder,
+    run_size: usize,
+    m_state: &Mutex<SorterInternalState>,
+) -> Result<Option<SortedRows>> {
+    let header_offset = config.header_offset();
+    let scanner = FilteredScanner::new(config, vec![])?;
+    let mut entries: Vec<Entry> = vec![];
+    let mut entries_size = 0;
+    let mut runs: Vec<File> = vec![];
+    for scanned in scanner {
+        let (pos, record) = match scanned {
+            FilteredScan::Record(pos, record) => (pos, record),
+            FilteredScan::Waiting => {
+                if m_state.lock().unwrap().should_terminate {
+                    return Ok(None);
+                }
+                continue;
+            }
+            FilteredScan::Reset => {
+                entries.clear();
+                entries_size = 0;
+                runs.clear();
+                continue;
+            }
+            FilteredScan::FilteredOut => continue,
+        };
+        let row = pos.record() - header_offset;
+        let entry = Entry {
+            key: SortKey::new(record.get(column).unwrap_or_default().as_bytes()),
+            row,
+        };
+        entries_size += entry.size();
+        entries.push(entry);
+        if entries_size >= run_size {
+            if m_state.lock().unwrap().should_terminate {
+                return Ok(None);
+            }
+            sort_entries(&mut entries, order);
+            runs.push(write_run(&entries)?);
+            entries.clear();
+            entries_size = 0;
+        }
+    }
+    sort_entries(&mut entries, order);
+    if runs.is_empty() {
+        let rows = entries.iter().map(|e| e.row).collect();
+        return Ok(Some(SortedRows::from_memory(rows)));
+    }
+    runs.push(write_run(&entries)?);
+    drop(entries);
+    merge_runs(runs, order, run_size).map(Some)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum SortKey {
+    Number(f64),
+    Text(Vec<u8>),
+}
+
+impl SortKey {
+    pub(crate) fn new(field: &[u8]) -> SortKey {
+        // Rule out "inf", "NaN" and the like, which parse as floats
+        let number = std::str::from_utf8(field)
+            .ok()
+            .filter(|s| s.bytes().any(|b| b.is_ascii_digit()))
+            .and_then(|s| s.trim().parse::<f64>().ok());
+        match number {
+            Some(n) => SortKey::Number(n),
+            None => SortKey::Text(field.to_vec()),
+        }
+    }
+
+    pub(crate) fn cmp(&self, other: &SortKey) -> Ordering {
+        match (self, other) {
+            (SortKey::Number(a), SortKey::Number(b)) => a.total_cmp(b),
+            (SortKey::Number(_), SortKey::Text(_)) => Ordering::Less,
+            (SortKey::Text(_), SortKey::Number(_)) => Ordering::Greater,
+            (SortKey::Text(a), SortKey::Text(b)) => a.cmp(b),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    key: SortKey,
+    row: u64,
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        match &self.key {
+            SortKey::Number(_) => ENTRY_OVERHEAD_BYTES,
+            SortKey::Text(t) => ENTRY_OVERHEAD_BYTES + t.len(),
+        }
+    }
+
+    /// Order by key, breaking ties by file order regardless of the direction
+    fn cmp(&self, other: &Entry, order: SortOrder) -> Ordering {
+        let ordering = match order {
+            SortOrder::Ascending => self.key.cmp(&other.key),
+            SortOrder::Descending => other.key.cmp(&self.key),
+        };
+        ordering.then(self.row.cmp(&other.row))
+    }
+
+    fn write(&self, w: &mut impl Write) -> Result<()> {
+        w.write_all(&self.row.to_le_bytes())?;
+        match &self.key {
+            SortKey::Number(n) => {
+                w.write_all(&[0])?;
+                w.write_all(&n.to_bits().to_le_bytes())?;
+            }
+            SortKey::Text(t) => {
+                w.write_all(&[1])?;
+                w.write_all(&(t.len() as u64).to_le_bytes())?;
+                w.write_all(t)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read(r: &mut impl Read) -> Result<Entry> {
+        let read_u64 = |r: &mut dyn Read| -> Result<u64> {
+            let mut buf = [0; 8];
+            r.read_exact(&mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        };
+        let row = read_u64(r)?;
+        let mut tag = [0];
+        r.read_exact(&mut tag)?;
+        let key = if tag[0] == 0 {
+            SortKey::Number(f64::from_bits(read_u64(r)?))
+        } else {
+            let mut t = vec![0; read_u64(r)? as usize];
+            r.read_exact(&mut t)?;
+            SortKey::Text(t)
+        };
+        Ok(Entry { key, row })
+    }
+}
+
+fn sort_entries(entries: &mut [Entry], order: SortOrder) {
+    entries.sort_unstable_by(|a, b| a.cmp(b, order));
+}
+
+/// Write sorted entries to a temporary file, preceded by their count
+fn write_run(entries: &[Entry]) -> Result<File> {
+    let mut w = BufWriter::new(tempfile::tempfile()?);
+    w.write_all(&(entries.len() as u64).to_le_bytes())?;
+    for entry in entries {
+        entry.write(&mut w)?;
+    }
+    let mut file = w.into_inner()?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+struct Run {
+    reader: BufReader<File>,
+    remaining: u64,
+}
+
+impl Run {
+    fn new(file: File) -> Result<Run> {
+        let mut reader = BufReader::new(file);
+        let mut buf = [0; 8];
+        reader.read_exact(&mut buf)?;
+        Ok(Run {
+            reader,
+            remaining: u64::from_le_bytes(buf),
+        })
+    }
+
+    fn next(&mut self) -> Result<Option<Entry>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        Entry::read(&mut self.reader).map(Some)
+    }
+}
+
+/// Entry at the head of a run, ordered for a min-heap merge
+struct HeapItem {
+    entry: Entry,
+    run: usize,
+    order: SortOrder,
+}
+
+impl PartialEq for HeapItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapItem {}
+
+impl PartialOrd for HeapItem {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeapItem {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.entry.cmp(&other.entry, self.order)
+    }
+}
+
+/// Merge sorted runs into a temporary file of row indices
+fn merge_runs(files: Vec<File>, order: SortOrder, run_size: usize) -> Result<SortedRows> {
+    let mut runs = files
+        .into_iter()
+        .map(Run::new)
+        .collect::<Result<Vec<_>>>()?;
+    let mut heap = BinaryHeap::new();
+    for (i, run) in runs.iter_mut().enumerate() {
+        if let Some(entry) = run.next()? {
+            heap.push(Reverse(HeapItem {
+                entry,
+                run: i,
+                order,
+            }));
+        }
+    }
+    let mut w = BufWriter::new(tempfile::tempfile()?);
+    let mut len = 0;
+    while let Some(Reverse(item)) = heap.pop() {
+        w.write_all(&item.entry.row.to_le_bytes())?;
+        len += 1;
+        if let Some(entry) = runs[item.run].next()? {
+            heap.push(Reverse(HeapItem {
+                entry,
+                run: item.run,
+                order,
+            }));
+        }
+    }
+    let rows = w.into_inner()?;
+    let ranks = invert_on_disk(&rows, len, run_size)?;
+    Ok(SortedRows {
+        len,
+        rows: RowTable::File(rows),
+        ranks: RowTable::File(ranks),
+    })
+}
+
+/// Write the position of each row in the sorted order to a temporary file,
+/// one window of rows at a time to stay within `run_size` bytes of memory
+fn invert_on_disk(rows: &File, len: usize, run_size: usize) -> Result<File> {
+    let mut w = BufWriter::new(tempfile::tempfile()?);
+    let window = (run_size / 8).max(1);
+    let mut ranks = vec![];
+    for lo in (0..len).step_by(window) {
+        let hi = (lo + window).min(len);
+        ranks.clear();
+        ranks.resize(hi - lo, 0u64);
+        let mut reader = BufReader::new(rows.try_clone()?);
+        reader.seek(SeekFrom::Start(0))?;
+        let mut buf = [0; 8];
+        for rank in 0..len {
+            reader.read_exact(&mut buf)?;
+            let row = u64::from_le_bytes(buf) as usize;
+            if row >= lo && row < hi {
+                ranks[row - lo] = rank as u64;
+            }
+        }
+        for rank in &ranks {
+            w.write_all(&rank.to_le_bytes())?;
+        }
+    }
+    Ok(w.into_inner()?)
+}
+
+/// Table of `u64`, kept in a temporary file if too large for memory
+enum RowTable {
+    Memory(Vec<u64>),
+    File(File),
+}
+
+impl RowTable {
+    fn get(&self, start: usize, end: usize) -> Vec<u64> {
+        match self {
+            RowTable::Memory(values) => values[start..end].to_vec(),
+            RowTable::File(file) => {
+                let mut buf = vec![0; (end - start) * 8];
+                if file.read_exact_at(&mut buf, start as u64 * 8).is_err() {
+                    return vec![];
+                }
+                buf.chunks_exact(8)
+                    .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Row indices in sorted order, and the position of each row in that order
+struct SortedRows {
+    len: usize,
+    rows: RowTable,
+    ranks: RowTable,
+}
+
+impl SortedRows {
+    fn from_memory(rows: Vec<u64>) -> SortedRows {
+        let mut ranks = vec![0; rows.len()];
+        for (rank, &row) in rows.iter().enumerate() {
+            if let Some(r) = ranks.get_mut(row as usize) {
+                *r = rank as u64;
+            }
+        }
+        SortedRows {
+            len: rows.len(),
+            rows: RowTable::Memory(rows),
+            ranks: RowTable::Memory(ranks),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn get(&self, offset: usize, num_rows: usize) -> Vec<u64> {
+        let start = offset.min(self.len);
+        let end = offset.saturating_add(num_rows).min(self.len);
+        self.rows.get(start, end)
+    }
+
+    fn rank(&self, row: u64) -> Option<u64> {
+        let row = row as usize;
+        if row >= self.len {
+            return None;
+        }
+        self.ranks.get(row, row + 1).first().cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::wait_for;
+
+    fn sorted(path: &str, column: usize, order: SortOrder, run_size: usize) -> Vec<u64> {
+        let config = Arc::new(CsvConfig::new(path, b',', false));
+        let m_state = SorterInternalState::init(config, column, order, run_size);
+        let rows = wait_for(|| {
+            let m = m_state.lock().unwrap();
+            m.sorted.as_ref().map(|s| s.get(0, usize::MAX))
+        });
+        let m = m_state.lock().unwrap();
+        let s = m.sorted.as_ref().unwrap();
+        for (rank, &row) in rows.iter().enumerate() {
+            assert_eq!(s.rank(row), Some(rank as u64));
+        }
+        assert_eq!(s.rank(rows.len() as u64), None);
+        rows
+    }
+
+    fn expected(path: &str, column: usize, order: SortOrder) -> Vec<u64> {
+        let mut reader = csv::Reader::from_path(path).unwrap();
+        let mut entries: Vec<Entry> = reader
+            .byte_records()
+            .enumerate()
+            .map(|(i, r)| Entry {
+                key: SortKey::new(r.unwrap().get(column).unwrap()),
+                row: i as u64,
+            })
+            .collect();
+        // A stable sort to check against
+        entries.sort_by(|a, b| match order {
+            SortOrder::Ascending => a.key.cmp(&b.key),
+            SortOrder::Descending => b.key.cmp(&a.key),
+        });
+        entries.iter().map(|e| e.row).collect()
+    }
+
+    #[test]
+    fn test_sort_key() {
+        let mut keys: Vec<SortKey> = ["b", "10", "", "inf", "9.5", "-1", "NaN", "a", "1e3"]
+            .iter()
+            .map(|s| SortKey::new(s.as_bytes()))
+            .collect();
+        keys.sort_by(|a, b| a.cmp(b));
+        let expected: Vec<SortKey> = ["-1", "9.5", "10", "1e3", "", "NaN", "a", "b", "inf"]
+            .iter()
+            .map(|s| SortKey::new(s.as_bytes()))
+            .collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_sort_in_memory() {
+        let path = "tests/data/cities.csv";
+        let rows = sorted(path, 0, SortOrder::Ascending, RUN_SIZE_BYTES);
+        assert_eq!(rows, expected(path, 0, SortOrder::Ascending));
+        // Ties keep file order
+        assert_eq!(&rows[..3], &[16, 52, 84]);
+        let rows = sorted(path, 8, SortOrder::Descending, RUN_SIZE_BYTES);
+        assert_eq!(rows, expected(path, 8, SortOrder::Descending));
+    }
+
+    #[test]
+    fn test_sort_spilled() {
+        let path = "tests/data/cities.csv";
+        for column in [0, 9] {
+            for order in [SortOrder::Ascending, SortOrder::Descending] {
+                // A few entries per run
+                let rows = sorted(path, column, order, ENTRY_OVERHEAD_BYTES * 5);
+                assert_eq!(rows, expected(path, column, order));
+            }
+        }
+    }
+}
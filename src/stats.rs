@@ -0,0 +1,487 @@
+use crate::csv::{CsvConfig, FilteredScan, FilteredScanner};
+use crate::expr::{parse_date, parse_number, Date};
+use crate::matcher::Matcher;
+use crate::types::ColumnType;
+
+use anyhow::Result;
+use std::cmp::{min, Ordering};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{self, Instant};
+
+/// Distinct values counted exactly, along with how often each occurs, before
+/// switching to an estimate
+const MAX_EXACT_DISTINCT: usize = 1_000_000;
+
+/// Approximate memory for the values counted exactly
+pub(crate) const MAX_COUNTS_BYTES: usize = 64 * 1024 * 1024;
+
+/// Rough per-entry overhead of a counted value on top of its text
+pub(crate) const ENTRY_OVERHEAD_BYTES: usize = 48;
+
+/// Numbers kept for percentiles, beyond which a uniform sample is kept
+const MAX_NUMBERS: usize = 4_000_000;
+
+const NUM_TOP_VALUES: usize = 10;
+
+/// How often the stats shown are updated while scanning
+const UPDATE_INTERVAL: time::Duration = time::Duration::from_millis(300);
+
+/// Values taken as missing, besides blank ones
+pub(crate) const NULL_VALUES: [&str; 5] = ["NULL", "null", "NA", "N/A", "\\N"];
+
+const PERCENTILES: [u32; 5] = [25, 50, 75, 90, 99];
+
+/// Stats of a column, as far as it has been scanned
+#[derive(Clone, Debug)]
+pub struct ColumnStats {
+    pub column: usize,
+    pub rows: u64,
+    /// Blank or null values
+    pub empty: u64,
+    /// Type of all the other values, as marked in the header
+    pub kind: ColumnType,
+    pub distinct: u64,
+    /// Minimum and maximum by the type of the values
+    pub min: Option<String>,
+    pub max: Option<String>,
+    pub mean: Option<f64>,
+    /// Percentiles of numbers, once done
+    pub percentiles: Vec<(u32, f64)>,
+    /// Most frequent values, with their counts
+    pub top: Vec<(String, u64)>,
+    /// Whether the distinct count or percentiles are estimates
+    pub approximate: bool,
+    /// Whether values first seen after there were too many distinct ones to
+    /// count were left out of the most frequent
+    pub top_approximate: bool,
+    /// Fraction of the file scanned
+    pub progress: f64,
+    pub done: bool,
+}
+
+/// Computes the stats of a column in the background, over all rows or the
+/// rows selected by every one of `filters`
+pub struct Stats {
+    internal: Arc<Mutex<StatsInternalState>>,
+    column: usize,
+}
+
+impl Stats {
+    pub fn new(config: Arc<CsvConfig>, column: usize, filters: Vec<Matcher>) -> Stats {
+        let internal = StatsInternalState::init(config, column, filters, MAX_EXACT_DISTINCT);
+        Stats { internal, column }
+    }
+
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    pub fn get(&self) -> ColumnStats {
+        self.internal.lock().unwrap().stats.clone()
+    }
+}
+
+impl Drop for Stats {
+    fn drop(&mut self) {
+        self.internal.lock().unwrap().should_terminate = true;
+    }
+}
+
+struct StatsInternalState {
+    stats: ColumnStats,
+    should_terminate: bool,
+}
+
+impl StatsInternalState {
+    fn init(
+        config: Arc<CsvConfig>,
+        column: usize,
+        filters: Vec<Matcher>,
+        max_distinct: usize,
+    ) -> Arc<Mutex<StatsInternalState>> {
+        let internal = StatsInternalState {
+            stats: Accumulator::new(column, max_distinct).stats(0.0, false),
+            should_terminate: false,
+        };
+        let m_state = Arc::new(Mutex::new(internal));
+        let _m = m_state.clone();
+        let _handle = thread::spawn(move || {
+            // Errors reading the file just end the scan early
+            let stats = scan(config, column, filters, max_distinct, &_m).unwrap_or(None);
+            let mut m = _m.lock().unwrap();
+            m.stats.done = true;
+            if let Some(stats) = stats {
+                m.stats = stats;
+            }
+        });
+        m_state
+    }
+}
+
+/// Scan all records, publishing stats as they are gathered, and return the
+/// final stats or `None` if terminated early
+fn scan(
+    config: Arc<CsvConfig>,
+    column: usize,
+    filters: Vec<Matcher>,
+    max_distinct: usize,
+    m_state: &Mutex<StatsInternalState>,
+) -> Result<Option<ColumnStats>> {
+    let mut scanner = FilteredScanner::new(config, filters)?;
+    let mut acc = Accumulator::new(column, max_distinct);
+    let mut last_update = Instant::now();
+    while let Some(scanned) = scanner.next() {
+        match scanned {
+            FilteredScan::Record(_, record) => acc.add(record.get(column).unwrap_or_default()),
+            FilteredScan::Reset => acc = Accumulator::new(column, max_distinct),
+            FilteredScan::FilteredOut | FilteredScan::Waiting => {}
+        }
+        if last_update.elapsed() >= UPDATE_INTERVAL {
+            let stats = acc.stats(scanner.progress(), false);
+            let mut m = m_state.lock().unwrap();
+            if m.should_terminate {
+                return Ok(None);
+            }
+            m.stats = stats;
+            last_update = Instant::now();
+        }
+    }
+    Ok(Some(acc.stats(1.0, true)))
+}
+
+struct Accumulator {
+    column: usize,
+    max_distinct: usize,
+    rows: u64,
+    empty: u64,
+    kind: ColumnType,
+    numbers: u64,
+    counts: HashMap<String, u64>,
+    counts_bytes: usize,
+    /// Estimates distinct values once there are too many to count exactly
+    hll: Option<HyperLogLog>,
+    min_number: Option<(f64, String)>,
+    max_number: Option<(f64, String)>,
+    min_date: Option<(Date, String)>,
+    max_date: Option<(Date, String)>,
+    min_text: Option<String>,
+    max_text: Option<String>,
+    sum: f64,
+    /// All numbers, or a uniform sample of them
+    sample: Vec<f64>,
+    rng: u64,
+}
+
+impl Accumulator {
+    fn new(column: usize, max_distinct: usize) -> Accumulator {
+        Accumulator {
+            column,
+            max_distinct,
+            rows: 0,
+            empty: 0,
+            kind: ColumnType::Empty,
+            numbers: 0,
+            counts: HashMap::new(),
+            counts_bytes: 0,
+            hll: None,
+            min_number: None,
+            max_number: None,
+            min_date: None,
+            max_date: None,
+            min_text: None,
+            max_text: None,
+            sum: 0.0,
+            sample: vec![],
+            rng: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    fn add(&mut self, value: &str) {
+        self.rows += 1;
+        let trimmed = value.trim();
+        if trimmed.is_empty() || NULL_VALUES.contains(&trimmed) {
+            self.empty += 1;
+            return;
+        }
+        self.count(value);
+        self.kind = self.kind.merge(ColumnType::of(trimmed));
+        if let Some(n) = parse_number(trimmed) {
+            self.numbers += 1;
+            self.sum += n;
+            update_extreme(&mut self.min_number, n, value, Ordering::Less);
+            update_extreme(&mut self.max_number, n, value, Ordering::Greater);
+            self.sample_number(n);
+        } else if let Some(d) = parse_date(trimmed) {
+            update_extreme(&mut self.min_date, d, value, Ordering::Less);
+            update_extreme(&mut self.max_date, d, value, Ordering::Greater);
+        }
+        if self.min_text.as_deref().is_none_or(|t| value < t) {
+            self.min_text = Some(value.to_owned());
+        }
+        if self.max_text.as_deref().is_none_or(|t| value > t) {
+            self.max_text = Some(value.to_owned());
+        }
+    }
+
+    fn count(&mut self, value: &str) {
+        if let Some(c) = self.counts.get_mut(value) {
+            *c += 1;
+        } else if self.hll.is_none() {
+            if self.counts.len() >= self.max_distinct || self.counts_bytes >= MAX_COUNTS_BYTES {
+                // Only values seen so far are counted from here on
+                let mut hll = HyperLogLog::new();
+                self.counts.keys().for_each(|k| hll.insert(k));
+                self.hll = Some(hll);
+            } else {
+                self.counts.insert(value.to_owned(), 1);
+                self.counts_bytes += value.len() + ENTRY_OVERHEAD_BYTES;
+            }
+        }
+        if let Some(hll) = self.hll.as_mut() {
+            hll.insert(value);
+        }
+    }
+
+    /// Reservoir sampling once there are too many numbers to keep
+    fn sample_number(&mut self, n: f64) {
+        if self.sample.len() < MAX_NUMBERS {
+            self.sample.push(n);
+            return;
+        }
+        // xorshift
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let i = (self.rng % self.numbers) as usize;
+        if i < MAX_NUMBERS {
+            self.sample[i] = n;
+        }
+    }
+
+    /// Percentiles are only worked out when `done`, as they take sorting all
+    /// the numbers
+    fn stats(&self, progress: f64, done: bool) -> ColumnStats {
+        let kind = self.kind;
+        let (min, max) = if kind.is_numeric() {
+            (
+                self.min_number.as_ref().map(|(_, s)| s.clone()),
+                self.max_number.as_ref().map(|(_, s)| s.clone()),
+            )
+        } else if kind.is_date() {
+            (
+                self.min_date.as_ref().map(|(_, s)| s.clone()),
+                self.max_date.as_ref().map(|(_, s)| s.clone()),
+            )
+        } else {
+            (self.min_text.clone(), self.max_text.clone())
+        };
+        let mut mean = None;
+        let mut percentiles = vec![];
+        if kind.is_numeric() {
+            mean = Some(self.sum / self.numbers as f64);
+            if done {
+                let mut sorted = self.sample.clone();
+                sorted.sort_by(|a, b| a.total_cmp(b));
+                percentiles = PERCENTILES
+                    .iter()
+                    .map(|&p| (p, percentile(&sorted, p)))
+                    .collect();
+            }
+        }
+        let distinct = match &self.hll {
+            Some(hll) => hll.estimate(),
+            None => self.counts.len() as u64,
+        };
+        ColumnStats {
+            column: self.column,
+            rows: self.rows,
+            empty: self.empty,
+            kind,
+            distinct,
+            min,
+            max,
+            mean,
+            percentiles,
+            top: self.top_values(),
+            approximate: self.hll.is_some() || (self.sample.len() as u64) < self.numbers,
+            top_approximate: self.hll.is_some(),
+            progress: progress.min(1.0),
+            done,
+        }
+    }
+
+    /// Most frequent values first, then in order of the values
+    fn top_values(&self) -> Vec<(String, u64)> {
+        let cmp = |a: &(&String, &u64), b: &(&String, &u64)| b.1.cmp(a.1).then(a.0.cmp(b.0));
+        let mut counts: Vec<(&String, &u64)> = self.counts.iter().collect();
+        let n = min(NUM_TOP_VALUES, counts.len());
+        if n < counts.len() {
+            counts.select_nth_unstable_by(n, cmp);
+            counts.truncate(n);
+        }
+        counts.sort_by(cmp);
+        counts.into_iter().map(|(v, &c)| (v.clone(), c)).collect()
+    }
+}
+
+/// Keep the value with the extreme key in the direction of `wanted`
+fn update_extreme<K: PartialOrd>(
+    extreme: &mut Option<(K, String)>,
+    key: K,
+    value: &str,
+    wanted: Ordering,
+) {
+    let replace = match extreme {
+        Some((k, _)) => key.partial_cmp(k) == Some(wanted),
+        None => true,
+    };
+    if replace {
+        *extreme = Some((key, value.to_owned()));
+    }
+}
+
+/// Nearest-rank percentile of sorted numbers
+fn percentile(sorted: &[f64], p: u32) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let rank = (p as f64 / 100.0 * (sorted.len() - 1) as f64).round() as usize;
+    sorted[rank]
+}
+
+/// Estimates the number of distinct values in fixed memory, within about 1%
+struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    const BITS: u32 = 14;
+
+    fn new() -> HyperLogLog {
+        HyperLogLog {
+            registers: vec![0; 1 << Self::BITS],
+        }
+    }
+
+    fn insert(&mut self, value: &str) {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+        let index = (hash >> (64 - Self::BITS)) as usize;
+        let rank = ((hash << Self::BITS).leading_zeros() + 1).min(64 - Self::BITS + 1) as u8;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        // Linear counting is more accurate for small cardinalities
+        if estimate <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::{wait_for, CsvLensReader};
+    use crate::matcher::MatchOptions;
+
+    fn column_stats(column: usize, filters: Vec<Matcher>, max_distinct: usize) -> ColumnStats {
+        let config = Arc::new(CsvConfig::new("tests/data/cities.csv", b',', false));
+        let m_state = StatsInternalState::init(config, column, filters, max_distinct);
+        wait_for(|| Some(m_state.lock().unwrap().stats.clone()).filter(|s| s.done))
+    }
+
+    #[test]
+    fn test_numbers() {
+        let stats = column_stats(0, vec![], MAX_EXACT_DISTINCT);
+        assert_eq!(stats.rows, 128);
+        assert_eq!(stats.empty, 0);
+        assert_eq!(stats.kind, ColumnType::Integer);
+        assert_eq!(stats.distinct, 25);
+        assert_eq!(stats.min.as_deref(), Some("26"));
+        assert_eq!(stats.max.as_deref(), Some("50"));
+        assert!((stats.mean.unwrap() - 38.8203125).abs() < 1e-9);
+        assert_eq!(
+            stats.percentiles,
+            vec![(25, 35.0), (50, 39.0), (75, 42.0), (90, 45.0), (99, 49.0)]
+        );
+        assert_eq!(
+            stats.top[..3],
+            [
+                ("41".to_owned(), 12),
+                ("38".to_owned(), 11),
+                ("39".to_owned(), 10)
+            ]
+        );
+        assert!(!stats.approximate);
+        assert!(!stats.top_approximate);
+    }
+
+    #[test]
+    fn test_text_filtered() {
+        let config = Arc::new(CsvConfig::new("tests/data/cities.csv", b',', false));
+        let headers = CsvLensReader::new(config).unwrap().headers;
+        let filter = Matcher::parse("LatD >= 45", MatchOptions::default(), &headers).unwrap();
+        let stats = column_stats(9, vec![filter], MAX_EXACT_DISTINCT);
+        assert_eq!(stats.rows, 14);
+        assert_eq!(stats.kind, ColumnType::Text);
+        assert_eq!(stats.min.as_deref(), Some("BC"));
+        assert_eq!(stats.max.as_deref(), Some("WI"));
+        assert_eq!(stats.mean, None);
+        assert!(stats.percentiles.is_empty());
+    }
+
+    #[test]
+    fn test_kind_as_in_header() {
+        let stats_of = |values: &[&str]| {
+            let mut acc = Accumulator::new(0, MAX_EXACT_DISTINCT);
+            values.iter().for_each(|v| acc.add(v));
+            acc.stats(1.0, true)
+        };
+        let stats = stats_of(&["1.5", "inf", "2"]);
+        assert_eq!(stats.kind, ColumnType::Text);
+        assert_eq!(stats.mean, None);
+        assert_eq!(stats.min.as_deref(), Some("1.5"));
+        assert_eq!(stats.max.as_deref(), Some("inf"));
+        assert_eq!(stats_of(&["true", "false", "NA"]).kind, ColumnType::Boolean);
+        assert_eq!(stats_of(&["1", "2.5", ""]).kind, ColumnType::Float);
+        let stats = stats_of(&["2020-03-01", "2019-12-31 10:00:00"]);
+        assert_eq!(stats.kind, ColumnType::DateTime);
+        assert_eq!(stats.min.as_deref(), Some("2019-12-31 10:00:00"));
+        assert_eq!(stats.max.as_deref(), Some("2020-03-01"));
+    }
+
+    #[test]
+    fn test_approximate_distinct() {
+        let stats = column_stats(8, vec![], 20);
+        assert_eq!(stats.rows, 128);
+        assert!(stats.approximate);
+        assert!(stats.top_approximate);
+        assert!((115..=125).contains(&stats.distinct));
+        assert_eq!(stats.top.len(), NUM_TOP_VALUES);
+    }
+
+    #[test]
+    fn test_hyperloglog() {
+        let mut hll = HyperLogLog::new();
+        for i in 0..200_000 {
+            hll.insert(&format!("value {}", i % 100_000));
+        }
+        let estimate = hll.estimate() as f64;
+        assert!((estimate - 100_000.0).abs() / 100_000.0 < 0.03);
+    }
+}
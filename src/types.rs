@@ -0,0 +1,310 @@
+use crate::expr::{parse_date, parse_number};
+use crate::stats::NULL_VALUES;
+
+use std::iter;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColumnType {
+    /// Nothing but blank or null values seen so far
+    Empty,
+    Integer,
+    Float,
+    Boolean,
+    Date,
+    /// Dates, some of them with a time
+    DateTime,
+    Text,
+}
+
+impl ColumnType {
+    /// Type of a single value
+    pub fn of(value: &str) -> ColumnType {
+        let value = value.trim();
+        if value.is_empty() || NULL_VALUES.contains(&value) {
+            ColumnType::Empty
+        } else if value.parse::<i64>().is_ok() {
+            ColumnType::Integer
+        } else if is_float(value) {
+            ColumnType::Float
+        } else if is_boolean(value) {
+            ColumnType::Boolean
+        } else if parse_date(value).is_some() {
+            if has_time(value) {
+                ColumnType::DateTime
+            } else {
+                ColumnType::Date
+            }
+        } else {
+            ColumnType::Text
+        }
+    }
+
+    /// Type of a column with values of both types
+    pub fn merge(self, other: ColumnType) -> ColumnType {
+        use ColumnType::*;
+        match (self, other) {
+            (a, b) if a == b => a,
+            (Empty, t) | (t, Empty) => t,
+            (Integer, Float) | (Float, Integer) => Float,
+            (Date, DateTime) | (DateTime, Date) => DateTime,
+            _ => Text,
+        }
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        matches!(self, ColumnType::Integer | ColumnType::Float)
+    }
+
+    pub fn is_date(&self) -> bool {
+        matches!(self, ColumnType::Date | ColumnType::DateTime)
+    }
+
+    /// Shown in the stats panel
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColumnType::Empty => "Empty",
+            ColumnType::Integer => "Integer",
+            ColumnType::Float => "Float",
+            ColumnType::Boolean => "Boolean",
+            ColumnType::Date => "Date",
+            ColumnType::DateTime => "Date and time",
+            ColumnType::Text => "Text",
+        }
+    }
+
+    /// Shown after the column name in the header
+    pub fn marker(&self) -> &'static str {
+        match self {
+            ColumnType::Integer => " #",
+            ColumnType::Float => " #.#",
+            ColumnType::Boolean => " ?",
+            ColumnType::Date | ColumnType::DateTime => " @",
+            ColumnType::Empty | ColumnType::Text => "",
+        }
+    }
+}
+
+fn is_float(value: &str) -> bool {
+    // Rule out "inf", "NaN" and the like, which parse as floats
+    value.bytes().any(|b| b.is_ascii_digit()) && parse_number(value).is_some()
+}
+
+fn is_boolean(value: &str) -> bool {
+    ["true", "false", "yes", "no"]
+        .iter()
+        .any(|b| value.eq_ignore_ascii_case(b))
+}
+
+fn has_time(value: &str) -> bool {
+    value.contains(':')
+}
+
+/// How values are shown according to the type of their column
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ValueFormat {
+    /// Group the digits of numbers by thousands
+    pub thousands_separator: bool,
+    /// Show decimal numbers with this many decimals
+    pub decimals: Option<usize>,
+}
+
+impl ValueFormat {
+    /// The value as shown in a column of type `column_type`, or `None` to
+    /// show it as is
+    pub fn format(&self, value: &str, column_type: ColumnType) -> Option<String> {
+        let trimmed = value.trim();
+        match column_type {
+            ColumnType::Integer | ColumnType::Float => self.format_number(trimmed, column_type),
+            ColumnType::Date | ColumnType::DateTime => normalize_date(trimmed, column_type),
+            _ => None,
+        }
+    }
+
+    fn format_number(&self, value: &str, column_type: ColumnType) -> Option<String> {
+        let decimals = self.decimals.filter(|_| column_type == ColumnType::Float);
+        if decimals.is_none() && !self.thousands_separator {
+            return None;
+        }
+        // Leave exponents and such alone
+        if !value
+            .bytes()
+            .all(|b| b.is_ascii_digit() || b == b'-' || b == b'+' || b == b'.')
+        {
+            return None;
+        }
+        let mut s = match decimals {
+            Some(decimals) => round_decimals(value, decimals)?,
+            None => value.to_owned(),
+        };
+        if self.thousands_separator {
+            s = group_thousands(&s);
+        }
+        if s == value {
+            None
+        } else {
+            Some(s)
+        }
+    }
+}
+
+/// Round a plain decimal number to `decimals` places, half away from zero,
+/// working on the digits so that long numbers keep all of them
+fn round_decimals(value: &str, decimals: usize) -> Option<String> {
+    let (sign, unsigned) = match value.as_bytes().first() {
+        Some(b'-') => ("-", &value[1..]),
+        Some(b'+') => ("", &value[1..]),
+        _ => ("", value),
+    };
+    let (int_part, frac_part) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+    if int_part.is_empty() && frac_part.is_empty()
+        || !int_part
+            .bytes()
+            .chain(frac_part.bytes())
+            .all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+    let int_part = if int_part.is_empty() { "0" } else { int_part };
+    let mut digits: Vec<u8> = int_part
+        .bytes()
+        .chain(frac_part.bytes().chain(iter::repeat(b'0')).take(decimals))
+        .collect();
+    if frac_part
+        .as_bytes()
+        .get(decimals)
+        .is_some_and(|&b| b >= b'5')
+    {
+        match digits.iter().rposition(|&b| b != b'9') {
+            Some(i) => {
+                digits[i] += 1;
+                digits[i + 1..].iter_mut().for_each(|b| *b = b'0');
+            }
+            None => {
+                digits.iter_mut().for_each(|b| *b = b'0');
+                digits.insert(0, b'1');
+            }
+        }
+    }
+    let (int_digits, frac_digits) = digits.split_at(digits.len() - decimals);
+    let mut rounded = format!("{}{}", sign, String::from_utf8_lossy(int_digits));
+    if decimals > 0 {
+        rounded.push('.');
+        rounded.push_str(&String::from_utf8_lossy(frac_digits));
+    }
+    Some(rounded)
+}
+
+/// Insert commas between groups of three digits before the decimal point
+fn group_thousands(number: &str) -> String {
+    let digits_start = number
+        .find(|c: char| c.is_ascii_digit())
+        .unwrap_or(number.len());
+    let digits_end = number.find('.').unwrap_or(number.len());
+    let (sign, rest) = number.split_at(digits_start);
+    let (int_part, frac_part) = rest.split_at(digits_end.max(digits_start) - digits_start);
+    let mut grouped = String::with_capacity(number.len() + int_part.len() / 3);
+    for (i, c) in int_part.chars().enumerate() {
+        if i > 0 && (int_part.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    format!("{}{}{}", sign, grouped, frac_part)
+}
+
+/// Dates as `2022-01-31`, with the time as `12:30:00` in columns with times,
+/// keeping fractions of a second
+fn normalize_date(value: &str, column_type: ColumnType) -> Option<String> {
+    let (year, month, day, hour, minute, second) = parse_date(value)?;
+    let mut s = format!("{:04}-{:02}-{:02}", year, month, day);
+    if column_type == ColumnType::DateTime {
+        s.push_str(&format!(" {:02}:{:02}:{:02}", hour, minute, second));
+        if has_time(value) {
+            if let Some(i) = value.rfind('.').filter(|&i| i > value.rfind(':').unwrap()) {
+                s.push_str(&value[i..]);
+            }
+        }
+    }
+    if s == value {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_types() {
+        assert_eq!(ColumnType::of(" 42 "), ColumnType::Integer);
+        assert_eq!(ColumnType::of("-1.5"), ColumnType::Float);
+        assert_eq!(ColumnType::of("1e3"), ColumnType::Float);
+        assert_eq!(ColumnType::of("inf"), ColumnType::Text);
+        assert_eq!(ColumnType::of("FALSE"), ColumnType::Boolean);
+        assert_eq!(ColumnType::of("2022/01/31"), ColumnType::Date);
+        assert_eq!(ColumnType::of("2022-01-31 00:00"), ColumnType::DateTime);
+        assert_eq!(ColumnType::of("N/A"), ColumnType::Empty);
+        assert_eq!(ColumnType::of("abc"), ColumnType::Text);
+
+        use ColumnType::*;
+        assert_eq!(Empty.merge(Integer), Integer);
+        assert_eq!(Integer.merge(Float), Float);
+        assert_eq!(DateTime.merge(Date), DateTime);
+        assert_eq!(Integer.merge(Boolean), Text);
+        assert_eq!(Float.merge(Date), Text);
+    }
+
+    #[test]
+    fn test_format() {
+        let format = ValueFormat {
+            thousands_separator: true,
+            decimals: Some(2),
+        };
+        assert_eq!(
+            format.format("1234567", ColumnType::Integer).as_deref(),
+            Some("1,234,567")
+        );
+        assert_eq!(
+            format.format("-1234.5", ColumnType::Float).as_deref(),
+            Some("-1,234.50")
+        );
+        // more digits than a float holds
+        assert_eq!(
+            format
+                .format("12345678901234567890.125", ColumnType::Float)
+                .as_deref(),
+            Some("12,345,678,901,234,567,890.13")
+        );
+        assert_eq!(
+            format.format("-99.995", ColumnType::Float).as_deref(),
+            Some("-100.00")
+        );
+        assert_eq!(
+            format.format(".5", ColumnType::Float).as_deref(),
+            Some("0.50")
+        );
+        assert_eq!(format.format("1.2.3", ColumnType::Float), None);
+        assert_eq!(format.format("123", ColumnType::Integer), None);
+        assert_eq!(format.format("1e5", ColumnType::Float), None);
+        assert_eq!(format.format("1234", ColumnType::Text), None);
+
+        let format = ValueFormat::default();
+        assert_eq!(format.format("1234.5", ColumnType::Float), None);
+        assert_eq!(
+            format.format("2022/1/31", ColumnType::Date).as_deref(),
+            Some("2022-01-31")
+        );
+        assert_eq!(
+            format.format("2022-01-31", ColumnType::DateTime).as_deref(),
+            Some("2022-01-31 00:00:00")
+        );
+        assert_eq!(
+            format
+                .format("2022-01-31T12:30:05.250", ColumnType::DateTime)
+                .as_deref(),
+            Some("2022-01-31 12:30:05.250")
+        );
+    }
+}
@@ -1,16 +1,26 @@
+use crate::columns::Columns;
 use crate::csv::Row;
 use crate::find;
+use crate::frequency::{FrequencyOrder, FrequencyTable};
 use crate::input::InputMode;
+use crate::matcher::{MatchOptions, Matcher};
+use crate::profile::ColumnProfile;
+use crate::sort::SortOrder;
+use crate::stats::ColumnStats;
+use crate::types::{ColumnType, ValueFormat};
 use crate::view;
 use tui::buffer::Buffer;
-use tui::layout::Rect;
+use tui::layout::{Margin, Rect};
 use tui::style::{Color, Modifier, Style};
 use tui::symbols::line;
-use tui::text::{Span, Spans};
-use tui::widgets::Widget;
+use tui::text::{Span, Spans, Text};
 use tui::widgets::{Block, Borders, StatefulWidget};
+use tui::widgets::{Clear, Paragraph, Widget};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-use std::cmp::min;
+use std::cmp::{max, min};
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct CsvTable<'a> {
@@ -29,22 +39,39 @@ impl<'a> CsvTable<'a> {
 }
 
 impl<'a> CsvTable<'a> {
-    fn get_column_widths(&self, area_width: u16) -> Vec<u16> {
+    /// Widths of the given columns, in the same order: set by hand, or else
+    /// fitting most values in the file as they are shown. Until the file has
+    /// been sampled, the rows on screen are fitted instead.
+    fn get_column_widths(
+        &self,
+        state: &CsvTableState,
+        header: &[String],
+        columns: &[usize],
+        area_width: u16,
+    ) -> Vec<u16> {
         let mut column_widths = Vec::new();
-        for s in self.header.iter() {
-            column_widths.push(s.len() as u16);
-        }
-        for row in self.rows.iter() {
-            for (i, value) in row.fields.iter().enumerate() {
-                let v = column_widths.get_mut(i).unwrap();
-                let value_len = value.len() as u16;
-                if *v < value_len {
-                    *v = value_len;
+        for &col_index in columns.iter() {
+            if let Some(width) = state.columns.width(col_index) {
+                column_widths.push(width);
+                continue;
+            }
+            let mut width = header.get(col_index).map_or(0, |s| s.width() as u16);
+            match state.profile.widths.get(col_index) {
+                Some(&values_width) => width = max(width, values_width),
+                None => {
+                    for row in self.rows.iter() {
+                        let value_len = row.fields.get(col_index).map_or(0, |v| {
+                            state
+                                .format_value(col_index, v)
+                                .map_or(v.width(), |f| f.width()) as u16
+                        });
+                        width = max(width, value_len);
+                    }
                 }
             }
+            column_widths.push(width + 4);
         }
         for w in column_widths.iter_mut() {
-            *w += 4;
             *w = min(*w, (area_width as f32 * 0.8) as u16);
         }
         column_widths
@@ -130,6 +157,27 @@ impl<'a> CsvTable<'a> {
         buf.get_mut(section_width - 1, y_first_record + area.height)
             .set_symbol(line::HORIZONTAL_UP);
 
+        // Divider between the frozen and the scrolled columns
+        if let Some(x) = state.frozen_divider_x {
+            if x < area.right() {
+                let style = Style::default().fg(Color::Rgb(64, 64, 64));
+                buf.get_mut(x, 0)
+                    .set_style(style)
+                    .set_symbol(line::HORIZONTAL_DOWN);
+                for y in 1..y_first_record + area.height {
+                    buf.get_mut(x, y)
+                        .set_style(style)
+                        .set_symbol(line::VERTICAL);
+                }
+                buf.get_mut(x, y_first_record - 1)
+                    .set_style(style)
+                    .set_symbol(line::CROSS);
+                buf.get_mut(x, y_first_record + area.height)
+                    .set_style(style)
+                    .set_symbol(line::HORIZONTAL_UP);
+            }
+        }
+
         // Vertical line after last rendered column
         // TODO: refactor
         let col_ending_pos_x = state.col_ending_pos_x;
@@ -150,6 +198,7 @@ impl<'a> CsvTable<'a> {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn render_row(
         &self,
         buf: &mut Buffer,
@@ -165,15 +214,20 @@ impl<'a> CsvTable<'a> {
     ) {
         let mut x_offset_header = x;
         let mut remaining_width = area.width.saturating_sub(x);
-        let cols_offset = state.cols_offset as usize;
+        // Frozen columns first, then the scrolled ones
+        let num_frozen = state.columns.num_frozen();
+        let cols_offset = max(state.cols_offset as usize, num_frozen);
         // TODO: seems strange that these have to be set every row
         let mut has_more_cols_to_show = false;
         let mut col_ending_pos_x = 0;
         let mut num_cols_rendered = 0;
-        for (col_index, (hname, &hlen)) in row.iter().zip(column_widths).enumerate() {
-            if col_index < cols_offset {
+        let mut frozen_divider_x = None;
+        let columns = state.columns.visible();
+        for (pos, (&col_index, &hlen)) in columns.iter().zip(column_widths).enumerate() {
+            if pos >= num_frozen && pos < cols_offset {
                 continue;
             }
+            let hname = row.get(col_index).map_or("", |v| v.as_str());
             let effective_width = min(remaining_width, hlen);
             let mut style = Style::default();
             if is_header {
@@ -184,38 +238,30 @@ impl<'a> CsvTable<'a> {
                     .fg(Color::Rgb(255, 200, 0))
                     .add_modifier(Modifier::BOLD);
             }
-            match &state.finder_state {
-                FinderState::FinderActive(active) if (*hname).contains(active.target.as_str()) => {
-                    let mut highlight_style = style.fg(Color::Rgb(200, 0, 0));
-                    if let Some(hl) = &active.found_record {
-                        if let Some(row_index) = row_index {
-                            // TODO: vec::contains slow or does it even matter?
-                            if row_index == hl.row_index()
-                                && hl.column_indices().contains(&col_index)
-                            {
-                                highlight_style = highlight_style.bg(Color::LightYellow);
-                            }
-                        }
-                    }
-                    let p_span = Span::styled(active.target.as_str(), highlight_style);
-                    let splitted = (*hname).split(active.target.as_str());
-                    let mut spans = vec![];
-                    for part in splitted {
-                        let span = Span::styled(part, style);
-                        spans.push(span);
-                        spans.push(p_span.clone());
-                    }
-                    spans.pop();
-                    self.set_spans(buf, &spans, x_offset_header, y, effective_width);
-                }
-                _ => {
-                    let span = Span::styled((*hname).as_str(), style);
-                    self.set_spans(buf, &vec![span], x_offset_header, y, effective_width);
-                }
+            if is_selected && col_index as u64 == state.selected_column {
+                style = style.add_modifier(Modifier::REVERSED);
+            }
+            let spans = if is_header {
+                highlighted_spans(state, hname, col_index, row_index, style)
+            } else {
+                cell_spans(state, hname, col_index, row_index, style)
             };
+            // Numbers line up on the right, within the space before the next
+            // column
+            let mut x_value = x_offset_header;
+            if !is_header && state.column_type(col_index).is_numeric() {
+                let value_len: usize = spans.iter().map(|s| s.content.width()).sum();
+                x_value += hlen.saturating_sub(4).saturating_sub(value_len as u16);
+            }
+            let effective_width = effective_width.saturating_sub(x_value - x_offset_header);
+            self.set_spans(buf, &spans, x_value, y, effective_width);
             x_offset_header += hlen;
             col_ending_pos_x = x_offset_header;
             num_cols_rendered += 1;
+            if pos + 1 == num_frozen {
+                // In the middle of the space reserved after the column
+                frozen_divider_x = Some(x_offset_header.saturating_sub(2));
+            }
             if remaining_width < hlen {
                 has_more_cols_to_show = true;
                 break;
@@ -225,25 +271,27 @@ impl<'a> CsvTable<'a> {
         state.set_num_cols_rendered(num_cols_rendered);
         state.set_more_cols_to_show(has_more_cols_to_show);
         state.col_ending_pos_x = col_ending_pos_x;
+        state.frozen_divider_x = frozen_divider_x;
     }
 
     fn set_spans(&self, buf: &mut Buffer, spans: &[Span], x: u16, y: u16, width: u16) {
         // TODO: make constant?
         let suffix = "…";
-        let suffix_len = suffix.chars().count();
+        let suffix_width = suffix.width();
 
         // Reserve some space before the next column (same number used in get_column_widths)
-        let mut remaining_width = width.saturating_sub(4);
+        let mut remaining_width = width.saturating_sub(4) as usize;
 
         // Pack as many spans as possible until hitting width limit
         let mut cur_spans = vec![];
         for span in spans {
-            if span.content.len() <= remaining_width.into() {
+            let span_width = span.content.width();
+            if span_width <= remaining_width {
                 cur_spans.push(span.clone());
-                remaining_width = remaining_width.saturating_sub(span.content.len() as u16);
+                remaining_width -= span_width;
             } else {
                 let truncated_content =
-                    &span.content[..remaining_width.saturating_sub(suffix_len as u16) as usize];
+                    truncate_to_width(&span.content, remaining_width.saturating_sub(suffix_width));
                 let truncated_span = Span::styled(truncated_content, span.style);
                 cur_spans.push(truncated_span);
                 cur_spans.push(Span::raw(suffix));
@@ -256,29 +304,405 @@ impl<'a> CsvTable<'a> {
         buf.set_spans(x, y, &spans, width);
     }
 
+    /// Records side by side, one field per line with the field names on the
+    /// left, for tables too wide to scroll through column by column
+    fn render_transposed(
+        &self,
+        buf: &mut Buffer,
+        state: &mut CsvTableState,
+        header: &[String],
+        area: Rect,
+        y_header: u16,
+    ) {
+        let layout = TransposedLayout::new(header, area.width);
+        state.scroll_to_selected_field(area.height);
+        let fields_offset = state.cols_offset as usize;
+        let columns = state.columns.visible();
+
+        let name_style = Style::default().add_modifier(Modifier::BOLD);
+        for (y, &col_index) in (area.y..area.bottom()).zip(columns.iter().skip(fields_offset)) {
+            let name = header.get(col_index).map_or("", |h| h.as_str());
+            let span = Span::styled(name, name_style);
+            self.set_spans(buf, &[span], area.x, y, layout.name_width);
+        }
+
+        let x_first_record = layout.x_records();
+        let mut x = x_first_record;
+        for (i, row) in self.rows.iter().enumerate() {
+            if x >= area.right() {
+                break;
+            }
+            let is_selected = state.selected == Some(i as u64);
+            let mut style = Style::default();
+            if is_selected {
+                style = style
+                    .fg(Color::Rgb(255, 200, 0))
+                    .add_modifier(Modifier::BOLD);
+            }
+            let width = min(layout.record_width, area.right() - x);
+            let label = Span::styled(
+                format!("Row {}", row.record_num),
+                style.add_modifier(Modifier::BOLD),
+            );
+            self.set_spans(buf, &[label], x, y_header, width);
+
+            for (y, &col_index) in (area.y..area.bottom()).zip(columns.iter().skip(fields_offset)) {
+                let value = row.fields.get(col_index).map_or("", |v| v.as_str());
+                let mut cell_style = style;
+                if is_selected && col_index as u64 == state.selected_column {
+                    cell_style = cell_style.add_modifier(Modifier::REVERSED);
+                }
+                let spans = cell_spans(
+                    state,
+                    value,
+                    col_index,
+                    Some(row.record_num - 1),
+                    cell_style,
+                );
+                self.set_spans(buf, &spans, x, y, width);
+            }
+            x = x.saturating_add(layout.record_width);
+        }
+
+        state.borders_state = Some(BordersState {
+            x_row_separator: layout.x_separator(),
+            y_first_record: area.y,
+        });
+        state.set_more_cols_to_show(false);
+        state.col_ending_pos_x = x;
+    }
+
+    /// Popup over the table with the selected cell or record in full,
+    /// wrapped and keeping line breaks
+    fn render_detail(&self, area: Rect, buf: &mut Buffer, state: &mut CsvTableState) {
+        let row = match self.rows.get(state.selected.unwrap_or(0) as usize) {
+            Some(row) => row,
+            None => return,
+        };
+        let column = state.selected_column as usize;
+        let detail = state.detail.as_mut().unwrap();
+        let (title, text) = match detail.kind {
+            DetailKind::Cell => {
+                let name = self.header.get(column).map_or("", |h| h.as_str());
+                let value = row.fields.get(column).map_or("", |v| v.as_str());
+                (
+                    format!("{} (row {})", name, row.record_num),
+                    Text::raw(value),
+                )
+            }
+            DetailKind::Record => {
+                let name_width = self.header.iter().map(|h| h.width()).max().unwrap_or(0);
+                let mut lines = vec![];
+                for (name, value) in self.header.iter().zip(row.fields.iter()) {
+                    let mut value_lines = value.split('\n');
+                    let padding = " ".repeat(name_width - name.width());
+                    lines.push(Spans::from(vec![
+                        Span::styled(
+                            format!("{}{}: ", padding, name),
+                            Style::default().add_modifier(Modifier::BOLD),
+                        ),
+                        Span::raw(value_lines.next().unwrap_or("")),
+                    ]));
+                    for line in value_lines {
+                        lines.push(Spans::from(vec![
+                            Span::raw(" ".repeat(name_width + 2)),
+                            Span::raw(line),
+                        ]));
+                    }
+                }
+                (format!("Row {}", row.record_num), Text::from(lines))
+            }
+        };
+        let popup_area = area.inner(&Margin {
+            horizontal: area.width / 10,
+            vertical: area.height / 10,
+        });
+        // Wrapped here rather than by the paragraph, so as not to scroll past
+        // the last line as shown
+        let inner_width = max(1, popup_area.width.saturating_sub(2) as usize);
+        let lines = wrap_text(text, inner_width);
+        detail.scroll = min(detail.scroll, lines.len().saturating_sub(1) as u16);
+        Clear.render(popup_area, buf);
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Rgb(128, 128, 128)))
+            .title(Span::styled(
+                format!(" {} ", title),
+                Style::default().add_modifier(Modifier::BOLD),
+            ));
+        Paragraph::new(Text::from(lines))
+            .block(block)
+            .scroll((detail.scroll, 0))
+            .render(popup_area, buf);
+    }
+
+    fn render_column_picker(&self, area: Rect, buf: &mut Buffer, state: &mut CsvTableState) {
+        let popup_area = area.inner(&Margin {
+            horizontal: area.width / 4,
+            vertical: area.height / 10,
+        });
+        let height = popup_area.height.saturating_sub(2) as usize;
+        let picker = state.column_picker.as_mut().unwrap();
+        // Keep the cursor in view
+        if picker.cursor < picker.offset {
+            picker.offset = picker.cursor;
+        } else if picker.cursor >= picker.offset + height {
+            picker.offset = (picker.cursor + 1).saturating_sub(height);
+        }
+
+        let mut lines = vec![];
+        for (i, &col_index) in state.columns.order().iter().enumerate() {
+            let checkbox = if state.columns.is_hidden(col_index) {
+                "[ ]"
+            } else {
+                "[x]"
+            };
+            let name = self.header.get(col_index).map_or("", |h| h.as_str());
+            let mut style = Style::default();
+            if i == picker.cursor {
+                style = style.add_modifier(Modifier::REVERSED);
+            }
+            lines.push(Spans::from(Span::styled(
+                format!("{} {}", checkbox, name),
+                style,
+            )));
+        }
+
+        Clear.render(popup_area, buf);
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Rgb(128, 128, 128)))
+            .title(Span::styled(
+                " Columns (Space: show/hide) ",
+                Style::default().add_modifier(Modifier::BOLD),
+            ));
+        Paragraph::new(Text::from(lines))
+            .block(block)
+            .scroll((picker.offset as u16, 0))
+            .render(popup_area, buf);
+    }
+
+    fn render_stats(&self, area: Rect, buf: &mut Buffer, stats: &ColumnStats, is_filtered: bool) {
+        let label_style = Style::default().add_modifier(Modifier::BOLD);
+        let line = |label: &str, value: String| {
+            Spans::from(vec![
+                Span::styled(format!("{:<15}", label), label_style),
+                Span::raw(value),
+            ])
+        };
+        let share = |n: u64| {
+            if stats.rows == 0 {
+                "".to_owned()
+            } else {
+                format!(" ({:.1}%)", n as f64 / stats.rows as f64 * 100.0)
+            }
+        };
+
+        let mut rows_str = stats.rows.to_string();
+        if !stats.done {
+            rows_str += format!(" [Scanning {:.0}%]", stats.progress * 100.0).as_str();
+        }
+        let estimate = if stats.approximate { "~" } else { "" };
+        let mut lines = vec![
+            line("Rows", rows_str),
+            line("Type", stats.kind.name().to_owned()),
+            line(
+                "Empty or null",
+                format!("{}{}", stats.empty, share(stats.empty)),
+            ),
+            line("Distinct", format!("{}{}", estimate, stats.distinct)),
+        ];
+        if let (Some(min), Some(max)) = (&stats.min, &stats.max) {
+            lines.push(line("Min", escape_control_chars(min)));
+            lines.push(line("Max", escape_control_chars(max)));
+        }
+        if let Some(mean) = stats.mean {
+            lines.push(line("Mean", format_number(mean)));
+            let percentiles = if stats.done {
+                let values: Vec<String> = stats
+                    .percentiles
+                    .iter()
+                    .map(|(p, v)| format!("{}%: {}{}", p, estimate, format_number(*v)))
+                    .collect();
+                values.join("  ")
+            } else {
+                "…".to_owned()
+            };
+            lines.push(line("Percentiles", percentiles));
+        }
+        if !stats.top.is_empty() {
+            lines.push(Spans::from(""));
+            // Values only seen once there were too many to count are missing
+            let heading = if stats.top_approximate {
+                "Most frequent (approximate)"
+            } else {
+                "Most frequent"
+            };
+            lines.push(Spans::from(Span::styled(heading, label_style)));
+            let values: Vec<String> = stats
+                .top
+                .iter()
+                .map(|(v, _)| escape_control_chars(v))
+                .collect();
+            let value_width = values.iter().map(|v| v.width()).max().unwrap_or(0);
+            for (value, (_, count)) in values.iter().zip(stats.top.iter()) {
+                lines.push(Spans::from(format!(
+                    "  {}  {}{}{}",
+                    pad_to_width(value, value_width),
+                    if stats.top_approximate { "~" } else { "" },
+                    count,
+                    share(*count),
+                )));
+            }
+        }
+
+        let name = self.header.get(stats.column).map_or("", |h| h.as_str());
+        let title = if is_filtered {
+            format!(" Stats: {} (filtered rows) ", name)
+        } else {
+            format!(" Stats: {} ", name)
+        };
+        let popup_area = area.inner(&Margin {
+            horizontal: area.width / 6,
+            vertical: area.height / 10,
+        });
+        Clear.render(popup_area, buf);
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Rgb(128, 128, 128)))
+            .title(Span::styled(title, label_style));
+        Paragraph::new(Text::from(lines))
+            .block(block)
+            .render(popup_area, buf);
+    }
+
+    fn render_frequency(&self, area: Rect, buf: &mut Buffer, state: &mut CsvTableState) {
+        let popup_area = area.inner(&Margin {
+            horizontal: area.width / 6,
+            vertical: area.height / 10,
+        });
+        let height = popup_area.height.saturating_sub(2) as usize;
+        let is_filtered = !state.filters.is_empty();
+        let view = state.frequency.as_mut().unwrap();
+        let table = &view.table;
+        // Keep the cursor in view
+        view.cursor = min(view.cursor, table.len().saturating_sub(1));
+        if view.cursor < view.offset {
+            view.offset = view.cursor;
+        } else if view.cursor >= view.offset + height {
+            view.offset = (view.cursor + 1).saturating_sub(height);
+        }
+
+        let shown: Vec<(String, u64)> = (view.offset..view.offset + height)
+            .filter_map(|i| table.get(i, view.order))
+            .map(|(v, c)| {
+                let v = if v.is_empty() {
+                    "(empty)".to_owned()
+                } else {
+                    escape_control_chars(v)
+                };
+                (v, *c)
+            })
+            .collect();
+        let count_width = table
+            .get(0, FrequencyOrder::Count)
+            .map_or(1, |(_, c)| c.to_string().len());
+        let value_width = min(
+            shown.iter().map(|(v, _)| v.width()).max().unwrap_or(0),
+            (popup_area.width as usize).saturating_sub(count_width + 14),
+        );
+        let mut lines = vec![];
+        for (i, (value, count)) in shown.iter().enumerate() {
+            let mut style = Style::default();
+            if view.offset + i == view.cursor {
+                style = style.add_modifier(Modifier::REVERSED);
+            }
+            let value = truncate_to_width(value, value_width);
+            let share = if table.total > 0 {
+                *count as f64 / table.total as f64 * 100.0
+            } else {
+                0.0
+            };
+            lines.push(Spans::from(Span::styled(
+                format!(
+                    "{}  {:>count_width$}  {:>5.1}%",
+                    pad_to_width(value, value_width),
+                    count,
+                    share,
+                    count_width = count_width,
+                ),
+                style,
+            )));
+        }
+
+        let name = self.header.get(view.column).map_or("", |h| h.as_str());
+        let order = match view.order {
+            FrequencyOrder::Count => "by count",
+            FrequencyOrder::Value => "by value",
+        };
+        let mut title = format!(
+            " Values of {}: {} in {} rows, {}",
+            name,
+            table.len(),
+            table.total,
+            order
+        );
+        if is_filtered {
+            title += ", filtered rows";
+        }
+        if table.truncated {
+            title += ", too many to count all";
+        }
+        if !table.done {
+            title += format!(" [Counting {:.0}%]", table.progress * 100.0).as_str();
+        }
+        title += " ";
+
+        Clear.render(popup_area, buf);
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Rgb(128, 128, 128)))
+            .title(Span::styled(
+                title,
+                Style::default().add_modifier(Modifier::BOLD),
+            ));
+        Paragraph::new(Text::from(lines))
+            .block(block)
+            .render(popup_area, buf);
+    }
+
     fn render_status(&self, area: Rect, buf: &mut Buffer, state: &mut CsvTableState) {
         // Content of status line (separator already plotted elsewhere)
         let style = Style::default().fg(Color::Rgb(128, 128, 128));
         let mut content: String;
-        if let BufferState::Enabled(buffer_mode, buf) = &state.buffer_content {
+        if let BufferState::Enabled(buffer_mode, buf, options) = &state.buffer_content {
             content = buf.to_owned();
+            let label = match options.label() {
+                Some(label) => format!(" [{}]", label),
+                None => "".to_owned(),
+            };
             match buffer_mode {
                 InputMode::GotoLine => {
                     content = format!("Go to line: {}", content);
                 }
                 InputMode::Find => {
-                    content = format!("Find: {}", content);
+                    content = format!("Find{}: {}", label, content);
                 }
                 InputMode::Filter => {
-                    content = format!("Filter: {}", content);
+                    content = format!("Filter{}: {}", label, content);
                 }
                 _ => {}
             }
         } else {
             content = state.filename.to_string();
 
-            let total_str = if state.total_line_number.is_some() {
-                format!("{}", state.total_line_number.unwrap())
+            let total_str = if let Some(n) = state.total_line_number {
+                let plus_marker = if state.total_line_number_exact {
+                    ""
+                } else {
+                    "+"
+                };
+                format!("{}{}", n, plus_marker)
             } else {
                 "?".to_owned()
             };
@@ -292,19 +716,56 @@ impl<'a> CsvTable<'a> {
                 Some(row) => row.record_num.to_string(),
                 _ => "-".to_owned(),
             };
+            let col_pos = state.columns.position(state.selected_column as usize);
+            let col_str = col_pos.map_or("-".to_owned(), |i| (i + 1).to_string());
+            let num_hidden = state.columns.num_hidden();
+            let hidden_str = if num_hidden > 0 {
+                format!(" ({} hidden)", num_hidden)
+            } else {
+                "".to_owned()
+            };
             content += format!(
-                " [Row {}/{}, Col {}/{}]",
+                " [Row {}/{}, Col {}/{}{}]",
                 row_num,
                 total_str,
-                state.cols_offset + 1,
-                state.total_cols,
+                col_str,
+                state.total_cols - num_hidden,
+                hidden_str,
             )
             .as_str();
 
+            let column = state.selected_column as usize;
+            if let (Some(name), Some(row)) = (self.header.get(column), current_row) {
+                let value = row.fields.get(column).map_or("", |v| v.as_str());
+                content += format!(" [{}: {}]", name, escape_control_chars(value)).as_str();
+            }
+
             if let FinderState::FinderActive(s) = &state.finder_state {
                 content += format!(" {}", s.status_line()).as_str();
             }
 
+            if let Some(sort) = &state.sort {
+                if !sort.done {
+                    content += " [Sorting...]";
+                }
+            }
+
+            if state.following {
+                content += " [Following]";
+            }
+
+            if let Some(p) = state.index_progress {
+                content += format!(" [Indexing {:.0}%]", p * 100.0).as_str();
+            }
+
+            if let Some(e) = &state.error {
+                content += format!(" [{}]", e).as_str();
+            }
+
+            if let Some(e) = &state.stream_error {
+                content += format!(" [Read error: {}]", e).as_str();
+            }
+
             if let Some(elapsed) = state.elapsed {
                 content += format!(" [{}ms]", elapsed).as_str();
             }
@@ -315,6 +776,13 @@ impl<'a> CsvTable<'a> {
         }
         let span = Span::styled(content, style);
         buf.set_span(area.x, area.bottom().saturating_sub(1), &span, area.width);
+
+        if !state.filters.is_empty() {
+            let breadcrumb: Vec<String> = state.filters.iter().map(describe_matcher).collect();
+            let content = format!("Filters: {}", breadcrumb.join(" » "));
+            let span = Span::styled(content, style);
+            buf.set_span(area.x, area.bottom().saturating_sub(2), &span, area.width);
+        }
     }
 }
 
@@ -328,8 +796,17 @@ impl<'a> StatefulWidget for CsvTable<'a> {
             return;
         }
 
-        let status_height = 2;
-        let column_widths = self.get_column_widths(area.width);
+        let status_height = 2 + state.breadcrumb_height();
+        // Header with the column types and the sort direction marked
+        let mut header = self.header.clone();
+        for (col_index, h) in header.iter_mut().enumerate() {
+            h.push_str(state.column_type(col_index).marker());
+        }
+        if let Some(sort) = &state.sort {
+            if let Some(h) = header.get_mut(sort.column) {
+                h.push_str(sort.marker());
+            }
+        }
         let (y_header, y_first_record) = self.render_header_borders(buf, area);
 
         // row area: including row numbers and row content
@@ -342,44 +819,59 @@ impl<'a> StatefulWidget for CsvTable<'a> {
                 .saturating_sub(status_height),
         );
 
-        let row_num_section_width = self.render_row_numbers(buf, state, rows_area, self.rows);
-
-        self.render_row(
-            buf,
-            state,
-            &column_widths,
-            rows_area,
-            row_num_section_width,
-            y_header,
-            true,
-            &self.header,
-            None,
-            false,
-        );
-
-        let mut y_offset = y_first_record;
-        for (i, row) in self.rows.iter().enumerate() {
-            let is_selected;
-            if let Some(selected_row) = state.selected {
-                is_selected = i as u64 == selected_row;
-            } else {
-                is_selected = false;
+        if state.transposed {
+            state.frozen_divider_x = None;
+            self.render_transposed(buf, state, &header, rows_area, y_header);
+        } else {
+            let columns = state.columns.visible();
+            let column_widths = self.get_column_widths(state, &header, &columns, area.width);
+            for (&col_index, &width) in columns.iter().zip(column_widths.iter()) {
+                state.column_widths[col_index] = width;
             }
+            state.max_column_width = (area.width as f32 * 0.8) as u16;
+            let row_num_section_width = self.render_row_numbers(buf, state, rows_area, self.rows);
+            state.scroll_to_selected_column(
+                &column_widths,
+                rows_area.width.saturating_sub(row_num_section_width),
+            );
+
             self.render_row(
                 buf,
                 state,
                 &column_widths,
                 rows_area,
                 row_num_section_width,
-                y_offset,
+                y_header,
+                true,
+                &header,
+                None,
                 false,
-                &row.fields,
-                Some(row.record_num - 1),
-                is_selected,
             );
-            y_offset += 1;
-            if y_offset >= rows_area.bottom() {
-                break;
+
+            let mut y_offset = y_first_record;
+            for (i, row) in self.rows.iter().enumerate() {
+                let is_selected;
+                if let Some(selected_row) = state.selected {
+                    is_selected = i as u64 == selected_row;
+                } else {
+                    is_selected = false;
+                }
+                self.render_row(
+                    buf,
+                    state,
+                    &column_widths,
+                    rows_area,
+                    row_num_section_width,
+                    y_offset,
+                    false,
+                    &row.fields,
+                    Some(row.record_num - 1),
+                    is_selected,
+                );
+                y_offset += 1;
+                if y_offset >= rows_area.bottom() {
+                    break;
+                }
             }
         }
 
@@ -392,12 +884,160 @@ impl<'a> StatefulWidget for CsvTable<'a> {
         self.render_status(status_area, buf, state);
 
         self.render_other_borders(buf, rows_area, state);
+
+        if state.detail.is_some() {
+            self.render_detail(area, buf, state);
+        }
+
+        if state.column_picker.is_some() {
+            self.render_column_picker(area, buf, state);
+        }
+
+        if let Some(stats) = &state.stats {
+            self.render_stats(area, buf, stats, !state.filters.is_empty());
+        }
+
+        if state.frequency.is_some() {
+            self.render_frequency(area, buf, state);
+        }
+    }
+}
+
+pub struct SortState {
+    pub column: usize,
+    pub order: SortOrder,
+    /// Whether the rows are sorted yet
+    pub done: bool,
+}
+
+impl SortState {
+    /// Marker shown after the header of the sorted column
+    fn marker(&self) -> &'static str {
+        match self.order {
+            SortOrder::Ascending => " ▲",
+            SortOrder::Descending => " ▼",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DetailKind {
+    Cell,
+    Record,
+}
+
+/// Popup showing the selected cell or record in full
+pub struct DetailState {
+    pub kind: DetailKind,
+    /// Lines scrolled down
+    pub scroll: u16,
+}
+
+impl DetailState {
+    pub fn new() -> DetailState {
+        DetailState {
+            kind: DetailKind::Cell,
+            scroll: 0,
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.kind = match self.kind {
+            DetailKind::Cell => DetailKind::Record,
+            DetailKind::Record => DetailKind::Cell,
+        };
+        self.scroll = 0;
+    }
+}
+
+/// How the transposed view splits the width between the field names and the
+/// records shown side by side
+pub struct TransposedLayout {
+    name_width: u16,
+    record_width: u16,
+    pub num_records: u64,
+}
+
+impl TransposedLayout {
+    /// Narrowest a record is shown before fewer records are put side by side
+    const MIN_RECORD_WIDTH: u16 = 30;
+
+    pub fn new(header: &[String], area_width: u16) -> Self {
+        let max_name_len = header.iter().map(|h| h.width()).max().unwrap_or(0) as u16;
+        let name_width = min(
+            max_name_len.saturating_add(4),
+            (area_width as f32 * 0.4) as u16,
+        );
+        let mut layout = TransposedLayout {
+            name_width,
+            record_width: 0,
+            num_records: 1,
+        };
+        let records_width = area_width.saturating_sub(layout.x_records());
+        layout.num_records = max(1, records_width / Self::MIN_RECORD_WIDTH) as u64;
+        layout.record_width = records_width / layout.num_records as u16;
+        layout
+    }
+
+    /// Position just past the separator after the field names
+    fn x_separator(&self) -> u16 {
+        self.name_width + 1
+    }
+
+    fn x_records(&self) -> u16 {
+        self.x_separator() + 2
+    }
+}
+
+/// Popup listing all columns in display order, with checkboxes for the
+/// visible ones
+pub struct ColumnPickerState {
+    /// Highlighted column, as an index in display order
+    pub cursor: usize,
+    /// First line shown
+    offset: usize,
+}
+
+impl ColumnPickerState {
+    pub fn new(cursor: usize) -> ColumnPickerState {
+        ColumnPickerState { cursor, offset: 0 }
+    }
+}
+
+/// Distinct values of a column with their counts, one of which can be picked
+/// to filter by
+pub struct FrequencyViewState {
+    pub column: usize,
+    pub table: Arc<FrequencyTable>,
+    pub order: FrequencyOrder,
+    /// Position of the highlighted value in the current order
+    pub cursor: usize,
+    /// First line shown
+    offset: usize,
+}
+
+impl FrequencyViewState {
+    pub fn new(column: usize, table: Arc<FrequencyTable>) -> FrequencyViewState {
+        FrequencyViewState {
+            column,
+            table,
+            order: FrequencyOrder::Count,
+            cursor: 0,
+            offset: 0,
+        }
+    }
+
+    /// The highlighted value
+    pub fn selected(&self) -> Option<&str> {
+        self.table
+            .get(self.cursor, self.order)
+            .map(|(v, _)| v.as_str())
     }
 }
 
 pub enum BufferState {
     Disabled,
-    Enabled(InputMode, String),
+    Enabled(InputMode, String, MatchOptions),
 }
 
 pub enum FinderState {
@@ -416,7 +1056,7 @@ pub struct FinderActiveState {
     find_complete: bool,
     total_found: u64,
     cursor_index: Option<u64>,
-    target: String,
+    matcher: Matcher,
     found_record: Option<find::FoundRecord>,
     selected_offset: Option<u64>,
     is_filter: bool,
@@ -428,7 +1068,7 @@ impl FinderActiveState {
             find_complete: finder.done(),
             total_found: finder.count() as u64,
             cursor_index: finder.cursor().map(|x| x as u64),
-            target: finder.target(),
+            matcher: finder.matcher(),
             found_record: finder.current(),
             selected_offset: rows_view.selected_offset(),
             is_filter: rows_view.is_filter(),
@@ -467,10 +1107,180 @@ impl FinderActiveState {
             line = format!("{}/{}{}", cursor_str, self.total_found, plus_marker,);
         }
         let action = if self.is_filter { "Filter" } else { "Find" };
-        format!("[{} \"{}\": {}]", action, self.target, line)
+        format!("[{} {}: {}]", action, describe_matcher(&self.matcher), line)
+    }
+}
+
+/// Spans of a cell value as shown, formatted according to the type of its
+/// column unless there are matches of the active finder to highlight
+fn cell_spans<'b>(
+    state: &CsvTableState,
+    value: &'b str,
+    col_index: usize,
+    row_index: Option<usize>,
+    style: Style,
+) -> Vec<Span<'b>> {
+    if let FinderState::FinderActive(active) = &state.finder_state {
+        if !active.matcher.find_ranges(col_index, value).is_empty() {
+            return highlighted_spans(state, value, col_index, row_index, style);
+        }
+    }
+    match state.format_value(col_index, value) {
+        Some(formatted) => vec![Span::styled(formatted, style)],
+        None => vec![Span::styled(value, style)],
+    }
+}
+
+/// Spans of a cell value with the matches of the active finder highlighted,
+/// more so in the current found record
+fn highlighted_spans<'b>(
+    state: &CsvTableState,
+    value: &'b str,
+    col_index: usize,
+    row_index: Option<usize>,
+    style: Style,
+) -> Vec<Span<'b>> {
+    let active = match &state.finder_state {
+        FinderState::FinderActive(active) => active,
+        _ => return vec![Span::styled(value, style)],
+    };
+    let match_ranges = active.matcher.find_ranges(col_index, value);
+    if match_ranges.is_empty() {
+        return vec![Span::styled(value, style)];
+    }
+    let mut highlight_style = style.fg(Color::Rgb(200, 0, 0));
+    if let Some(hl) = &active.found_record {
+        if let Some(row_index) = row_index {
+            // TODO: vec::contains slow or does it even matter?
+            if row_index == hl.row_index() && hl.column_indices().contains(&col_index) {
+                highlight_style = highlight_style.bg(Color::LightYellow);
+            }
+        }
+    }
+    let mut spans = vec![];
+    let mut last_end = 0;
+    for (start, end) in match_ranges {
+        spans.push(Span::styled(&value[last_end..start], style));
+        spans.push(Span::styled(&value[start..end], highlight_style));
+        last_end = end;
     }
+    spans.push(Span::styled(&value[last_end..], style));
+    spans
 }
 
+/// Number with up to 4 decimals, without trailing zeros
+fn format_number(n: f64) -> String {
+    let s = format!("{:.4}", n);
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.').to_owned()
+    } else {
+        s
+    }
+}
+
+/// Longest start of `s` that takes up at most `width` terminal cells, without
+/// splitting wide characters or a character from its combining marks
+fn truncate_to_width(s: &str, width: usize) -> &str {
+    let mut used = 0;
+    for (i, g) in s.grapheme_indices(true) {
+        used += g.width();
+        if used > width {
+            return &s[..i];
+        }
+    }
+    s
+}
+
+/// Lines of `text` wrapped after spaces to take up at most `width` terminal
+/// cells, breaking words only when they are wider than that, and without
+/// splitting wide characters or a character from its combining marks
+fn wrap_text(text: Text, width: usize) -> Vec<Spans<'static>> {
+    let mut wrapped = vec![];
+    for line in text.lines {
+        let graphemes: Vec<(&str, Style)> = line
+            .0
+            .iter()
+            .flat_map(|span| span.content.graphemes(true).map(move |g| (g, span.style)))
+            .collect();
+        let mut start = 0;
+        let mut used = 0;
+        // Where the current line can be broken, after a space
+        let mut break_at = None;
+        for (i, &(g, _)) in graphemes.iter().enumerate() {
+            if used > 0 && used + g.width() > width {
+                let end = break_at.unwrap_or(i);
+                wrapped.push(styled_spans(&graphemes[start..end]));
+                used = graphemes[end..i].iter().map(|(g, _)| g.width()).sum();
+                start = end;
+                break_at = None;
+            }
+            used += g.width();
+            if g.chars().all(char::is_whitespace) {
+                break_at = Some(i + 1);
+            }
+        }
+        wrapped.push(styled_spans(&graphemes[start..]));
+    }
+    wrapped
+}
+
+/// Spans of graphemes, one for each run in the same style
+fn styled_spans(graphemes: &[(&str, Style)]) -> Spans<'static> {
+    let mut spans: Vec<Span> = vec![];
+    for &(g, style) in graphemes {
+        match spans.last_mut() {
+            Some(span) if span.style == style => span.content.to_mut().push_str(g),
+            _ => spans.push(Span::styled(g.to_owned(), style)),
+        }
+    }
+    Spans::from(spans)
+}
+
+/// `s` followed by spaces to fill `width` terminal cells
+fn pad_to_width(s: &str, width: usize) -> String {
+    let padding = width.saturating_sub(s.width());
+    format!("{}{}", s, " ".repeat(padding))
+}
+
+/// Make line breaks and tabs in a value visible on a single line
+fn escape_control_chars(value: &str) -> String {
+    value
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t")
+}
+
+/// Short description of what a matcher looks for, e.g. `"CA" in State (whole
+/// field)`
+fn describe_matcher(matcher: &Matcher) -> String {
+    let mut labels: Vec<String> = matcher.options().label().into_iter().collect();
+    let mut column_str = "".to_owned();
+    if let Some(column) = matcher.column() {
+        column_str = format!(" in {}", column.name);
+        if column.whole_field {
+            labels.push("whole field".to_owned());
+        }
+    }
+    let label = if labels.is_empty() {
+        "".to_owned()
+    } else {
+        format!(" ({})", labels.join(", "))
+    };
+    let mut target = if matcher.is_expression() {
+        format!("where {}", matcher.pattern())
+    } else {
+        format!("\"{}\"", matcher.pattern())
+    };
+    if matcher.is_inverted() {
+        target = format!("not {}", target);
+    }
+    format!("{}{}{}", target, column_str, label)
+}
+
+/// Narrowest a column can be made, with room for a character and the
+/// ellipsis besides the space before the next column
+const MIN_COLUMN_WIDTH: u16 = 6;
+
 struct BordersState {
     x_row_separator: u16,
     y_first_record: u16,
@@ -480,18 +1290,45 @@ pub struct CsvTableState {
     // TODO: types appropriate?
     pub rows_offset: u64,
     pub cols_offset: u64,
+    /// Column of the cell cursor in the selected row
+    pub selected_column: u64,
+    /// Hidden columns and the order of the others
+    pub columns: Columns,
     pub num_cols_rendered: u64,
     pub more_cols_to_show: bool,
     filename: String,
     total_line_number: Option<usize>,
+    total_line_number_exact: bool,
     total_cols: usize,
     pub elapsed: Option<f64>,
+    stream_error: Option<String>,
+    error: Option<String>,
+    pub index_progress: Option<f64>,
+    /// Stacked filters, outermost first, shown as a breadcrumb
+    pub filters: Vec<Matcher>,
+    pub sort: Option<SortState>,
+    pub detail: Option<DetailState>,
+    pub column_picker: Option<ColumnPickerState>,
+    /// Stats of the selected column, shown in a panel
+    pub stats: Option<ColumnStats>,
+    pub frequency: Option<FrequencyViewState>,
+    /// Show records side by side with one field per line
+    pub transposed: bool,
+    /// Inferred types and widths of all columns, as far as known
+    pub profile: ColumnProfile,
+    /// Widths of the columns as last shown
+    column_widths: Vec<u16>,
+    max_column_width: u16,
+    pub value_format: ValueFormat,
     buffer_content: BufferState,
     pub finder_state: FinderState,
     borders_state: Option<BordersState>,
     // TODO: should probably be with BordersState
     col_ending_pos_x: u16,
+    /// Where the divider after the frozen columns is drawn, if any
+    frozen_divider_x: Option<u16>,
     pub selected: Option<u64>,
+    pub following: bool,
     pub debug: String,
 }
 
@@ -500,27 +1337,133 @@ impl CsvTableState {
         Self {
             rows_offset: 0,
             cols_offset: 0,
+            selected_column: 0,
+            columns: Columns::new(total_cols),
             num_cols_rendered: 0,
             more_cols_to_show: true,
             filename,
             total_line_number: None,
+            total_line_number_exact: true,
             total_cols,
             elapsed: None,
+            stream_error: None,
+            error: None,
+            index_progress: None,
+            filters: vec![],
+            sort: None,
+            detail: None,
+            column_picker: None,
+            stats: None,
+            frequency: None,
+            transposed: false,
+            profile: ColumnProfile::default(),
+            column_widths: vec![0; total_cols],
+            max_column_width: 0,
+            value_format: ValueFormat::default(),
             buffer_content: BufferState::Disabled,
             finder_state: FinderState::FinderInactive,
             borders_state: None,
             col_ending_pos_x: 0,
+            frozen_divider_x: None,
             selected: None,
+            following: false,
             debug: "".into(),
         }
     }
 
+    /// Lines taken by the breadcrumb of stacked filters above the status line
+    pub fn breadcrumb_height(&self) -> u16 {
+        if self.filters.is_empty() {
+            0
+        } else {
+            1
+        }
+    }
+
     pub fn set_rows_offset(&mut self, offset: u64) {
         self.rows_offset = offset;
     }
 
-    pub fn set_cols_offset(&mut self, offset: u64) {
-        self.cols_offset = offset;
+    pub fn set_selected_column(&mut self, column: u64) {
+        self.selected_column = min(column, (self.total_cols as u64).saturating_sub(1));
+    }
+
+    fn column_type(&self, column: usize) -> ColumnType {
+        self.profile
+            .types
+            .get(column)
+            .copied()
+            .unwrap_or(ColumnType::Empty)
+    }
+
+    /// A value as shown in its column, if formatted differently
+    fn format_value(&self, column: usize, value: &str) -> Option<String> {
+        self.value_format.format(value, self.column_type(column))
+    }
+
+    /// Make the selected column wider or narrower than shown, until its width
+    /// is reset
+    pub fn resize_selected_column(&mut self, delta: i16) {
+        let column = self.selected_column as usize;
+        let current = match self.column_widths.get(column) {
+            Some(&w) if w > 0 => w,
+            // Not shown yet
+            _ => return,
+        };
+        let max_width = max(self.max_column_width, MIN_COLUMN_WIDTH);
+        let width =
+            (current as i32 + delta as i32).clamp(MIN_COLUMN_WIDTH as i32, max_width as i32);
+        self.columns.set_width(column, width as u16);
+    }
+
+    /// Position of the selected column among the visible columns
+    fn selected_position(&self) -> usize {
+        self.columns
+            .position(self.selected_column as usize)
+            .unwrap_or(0)
+    }
+
+    /// Scroll horizontally only as far as needed for the selected column to
+    /// fit in `width`, given the widths of all columns
+    fn scroll_to_selected_column(&mut self, column_widths: &[u16], width: u16) {
+        // Frozen columns are always shown and take from the width
+        let num_frozen = self.columns.num_frozen();
+        let frozen_width: u16 = column_widths.iter().take(num_frozen).sum();
+        let width = width.saturating_sub(frozen_width);
+        if (self.cols_offset as usize) < num_frozen {
+            self.cols_offset = num_frozen as u64;
+        }
+        let selected = self.selected_position();
+        if selected < num_frozen {
+            return;
+        }
+        if selected < self.cols_offset as usize {
+            self.cols_offset = selected as u64;
+            return;
+        }
+        while (self.cols_offset as usize) < selected {
+            let start = self.cols_offset as usize;
+            let end = min(selected + 1, column_widths.len());
+            let used: u32 = column_widths[start.min(end)..end]
+                .iter()
+                .map(|&w| w as u32)
+                .sum();
+            if used <= width as u32 {
+                break;
+            }
+            self.cols_offset += 1;
+        }
+    }
+
+    /// Scroll the fields of the transposed view only as far as needed for the
+    /// selected one to fit in `height` lines
+    fn scroll_to_selected_field(&mut self, height: u16) {
+        let selected = self.selected_position() as u64;
+        if selected < self.cols_offset {
+            self.cols_offset = selected;
+        } else if selected >= self.cols_offset.saturating_add(height as u64) {
+            self.cols_offset = selected.saturating_add(1).saturating_sub(height as u64);
+        }
     }
 
     fn set_more_cols_to_show(&mut self, value: bool) {
@@ -535,15 +1478,342 @@ impl CsvTableState {
         self.num_cols_rendered = n;
     }
 
-    pub fn set_total_line_number(&mut self, n: usize) {
+    /// `exact` should be false if the total is a lower bound that could still
+    /// grow, e.g. while the input is being streamed in
+    pub fn set_total_line_number(&mut self, n: usize, exact: bool) {
         self.total_line_number = Some(n);
+        self.total_line_number_exact = exact;
+    }
+
+    pub fn set_stream_error(&mut self, e: String) {
+        self.stream_error = Some(e);
     }
 
-    pub fn set_buffer(&mut self, mode: InputMode, buf: &str) {
-        self.buffer_content = BufferState::Enabled(mode, buf.to_string());
+    /// Show an error in the status line until something is typed in a prompt
+    pub fn set_error(&mut self, e: String) {
+        self.error = Some(e);
+    }
+
+    pub fn set_buffer(&mut self, mode: InputMode, buf: &str, options: MatchOptions) {
+        self.error = None;
+        self.buffer_content = BufferState::Enabled(mode, buf.to_string(), options);
     }
 
     pub fn reset_buffer(&mut self) {
         self.buffer_content = BufferState::Disabled;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::{wait_for, CsvConfig, CsvLensReader};
+    use crate::input::Control;
+    use tui::buffer::Cell;
+
+    /// Lines of the table as rendered into an area of the given size, with
+    /// the cells covered by wide characters left out
+    fn render_lines(filename: &str, width: u16, height: u16) -> Vec<String> {
+        render_lines_with(filename, width, height, |_| ()).0
+    }
+
+    /// Same as `render_lines`, with the state set up by `setup` first, and
+    /// the state as left by rendering
+    fn render_lines_with(
+        filename: &str,
+        width: u16,
+        height: u16,
+        setup: impl FnOnce(&mut CsvTableState),
+    ) -> (Vec<String>, CsvTableState) {
+        let mut state = new_state(filename);
+        setup(&mut state);
+        let buf = render_state(filename, width, height, &mut state);
+        (buffer_lines(&buf), state)
+    }
+
+    fn new_state(filename: &str) -> CsvTableState {
+        let config = Arc::new(CsvConfig::new(filename, b',', false));
+        let reader = CsvLensReader::new(config).unwrap();
+        CsvTableState::new(filename.to_owned(), reader.headers.len())
+    }
+
+    /// The first records rendered with `state` into an area of the given size
+    fn render_state(filename: &str, width: u16, height: u16, state: &mut CsvTableState) -> Buffer {
+        let config = Arc::new(CsvConfig::new(filename, b',', false));
+        let mut reader = CsvLensReader::new(config).unwrap();
+        let rows = reader.get_rows(0, 10).unwrap();
+        let area = Rect::new(0, 0, width, height);
+        let mut buf = Buffer::empty(area);
+        CsvTable::new(&reader.headers, &rows).render(area, &mut buf, state);
+        buf
+    }
+
+    /// Lines of `buf`, with the cells covered by wide characters left out
+    fn buffer_lines(buf: &Buffer) -> Vec<String> {
+        let area = buf.area;
+        (area.top()..area.bottom())
+            .map(|y| {
+                let mut line = String::new();
+                let mut x = area.left();
+                while x < area.right() {
+                    let symbol = &buf.get(x, y).symbol;
+                    line.push_str(symbol);
+                    x += max(1, symbol.width() as u16);
+                }
+                line.trim_end().to_owned()
+            })
+            .collect()
+    }
+
+    /// Text of line `y` of `buf` in the cells picked by `f`
+    fn text_where(buf: &Buffer, y: u16, f: impl Fn(&Cell) -> bool) -> String {
+        let area = buf.area;
+        (area.left()..area.right())
+            .map(|x| buf.get(x, y))
+            .filter(|cell| f(cell))
+            .map(|cell| cell.symbol.as_str())
+            .collect::<String>()
+            .trim()
+            .to_owned()
+    }
+
+    /// Text of line `y` of `buf` drawn reversed, e.g. the cell under the cursor
+    fn reversed_text(buf: &Buffer, y: u16) -> String {
+        text_where(buf, y, |cell| cell.modifier.contains(Modifier::REVERSED))
+    }
+
+    #[test]
+    fn test_truncate_to_width() {
+        assert_eq!(truncate_to_width("abcdef", 3), "abc");
+        assert_eq!(truncate_to_width("東京都", 5), "東京");
+        assert_eq!(truncate_to_width("東京都", 6), "東京都");
+        // accents stay with their letter
+        assert_eq!(truncate_to_width("cre\u{300}me", 3), "cre\u{300}");
+        assert_eq!(truncate_to_width("\u{1F355}x", 1), "");
+        assert_eq!(pad_to_width("東京", 6), "東京  ");
+        assert_eq!(pad_to_width("Zoe\u{308}", 4), "Zoe\u{308} ");
+    }
+
+    #[test]
+    fn test_wrap_text() {
+        let lines_of = |text: Text, width: usize| -> Vec<String> {
+            wrap_text(text, width)
+                .iter()
+                .map(|spans| spans.0.iter().map(|s| s.content.as_ref()).collect())
+                .collect()
+        };
+        assert_eq!(
+            lines_of(Text::raw("ab東京\ncd"), 3),
+            ["ab", "東", "京", "cd"]
+        );
+        // Words wider than the line are broken
+        assert_eq!(
+            lines_of(Text::raw("a cre\u{300}me brûlée"), 4),
+            ["a ", "cre\u{300}m", "e ", "brûl", "ée"]
+        );
+        // Spaces are kept, at the end of the line they fit in
+        assert_eq!(
+            lines_of(Text::raw("one two  three"), 8),
+            ["one two ", " three"]
+        );
+        assert!(lines_of(Text::raw(""), 4).is_empty());
+
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let text = Text::from(Spans::from(vec![
+            Span::styled("Name: ", bold),
+            Span::raw("Youngstown"),
+        ]));
+        let wrapped = wrap_text(text, 10);
+        assert_eq!(wrapped.len(), 2);
+        assert_eq!(wrapped[0].0, [Span::styled("Name: ", bold)]);
+        assert_eq!(wrapped[1].0, [Span::raw("Youngstown")]);
+    }
+
+    #[test]
+    fn test_render_cjk() {
+        let lines = render_lines("tests/data/unicode_cjk.csv", 50, 8);
+        assert_eq!(
+            lines[1..6],
+            [
+                "      id    名前        都市",
+                "───┬──────────────────────────────────────────────",
+                "1  │  1     山田太郎    東京",
+                "2  │  2     李小龍      香港",
+                "3  │  3     김민준      서울특별시 종로구 세…",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_combining() {
+        let lines = render_lines("tests/data/unicode_combining.csv", 50, 8);
+        assert_eq!(lines[1], "      id    name    note");
+        assert_eq!(
+            lines[3],
+            "1  │  1     Cafe\u{301}    cre\u{300}me bru\u{302}le\u{301}e"
+        );
+        assert_eq!(
+            lines[4],
+            format!("2  │  2     Zoe\u{308}     {}…", "n\u{303}".repeat(25))
+        );
+    }
+
+    #[test]
+    fn test_render_emoji() {
+        let lines = render_lines("tests/data/unicode_emoji.csv", 50, 8);
+        assert_eq!(
+            lines[1..6],
+            [
+                "      id    food        rating",
+                "───┬──────────────────────────────┬───────────────",
+                "1  │  1     \u{1F355} pizza    \u{1F44D}\u{1F44D}\u{1F44D}    │",
+                "2  │  2     \u{1F363} sushi    \u{1F44D}        │",
+                "3  │  3     \u{1F1EF}\u{1F1F5} ramen    \u{1F44D}\u{1F44D}      │",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_detail_scroll_wrapped() {
+        // 43 columns wide, wrapped into 3 lines of a popup 22 columns wide
+        // inside
+        let (lines, state) = render_lines_with("tests/data/unicode_cjk.csv", 30, 10, |state| {
+            state.selected = Some(2);
+            state.selected_column = 2;
+            state.detail = Some(DetailState {
+                kind: DetailKind::Cell,
+                scroll: 100,
+            });
+        });
+        assert_eq!(state.detail.unwrap().scroll, 2);
+        assert!(lines[2].contains("│정부서울청사 "), "{:?}", lines);
+    }
+
+    /// Move the cell cursor one visible column right, or left, as the arrow
+    /// keys do
+    fn move_cursor(state: &mut CsvTableState, right: bool) {
+        let column = state.selected_column as usize;
+        let next = if right {
+            state.columns.next_visible(column)
+        } else {
+            state.columns.prev_visible(column)
+        };
+        state.set_selected_column(next.unwrap() as u64);
+    }
+
+    #[test]
+    fn test_cell_cursor_moves() {
+        let filename = "tests/data/cities.csv";
+        let mut state = new_state(filename);
+        state.selected = Some(1);
+        let buf = render_state(filename, 60, 8, &mut state);
+        // Record 2 is on the fifth line
+        assert_eq!(reversed_text(&buf, 4), "42");
+
+        move_cursor(&mut state, true);
+        move_cursor(&mut state, true);
+        let buf = render_state(filename, 60, 8, &mut state);
+        assert_eq!(reversed_text(&buf, 4), "48");
+        assert_eq!(reversed_text(&buf, 3), "");
+
+        move_cursor(&mut state, false);
+        let buf = render_state(filename, 60, 8, &mut state);
+        assert_eq!(reversed_text(&buf, 4), "52");
+    }
+
+    #[test]
+    fn test_cell_cursor_scrolls() {
+        let filename = "tests/data/cities.csv";
+        let mut state = new_state(filename);
+        state.selected = Some(0);
+        let mut offsets = vec![];
+        for right in [true, false] {
+            for _ in 0..9 {
+                move_cursor(&mut state, right);
+                render_state(filename, 60, 8, &mut state);
+                offsets.push(state.cols_offset);
+            }
+        }
+        // LatD to LonM fit, LonS is cut off at the edge
+        assert_eq!(offsets[..9], [0, 0, 0, 0, 0, 1, 1, 4, 5]);
+        // Back from State, scrolling once LonD is out of view
+        assert_eq!(offsets[9..], [5, 5, 5, 5, 4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn test_cell_cursor_status() {
+        let (lines, _) = render_lines_with("tests/data/cities.csv", 80, 8, |state| {
+            state.selected = Some(0);
+            state.selected_column = 8;
+            state.columns.set_width(8, MIN_COLUMN_WIDTH);
+        });
+        assert!(lines[3].ends_with(" W     Y…    OH"), "{:?}", lines);
+        assert_eq!(
+            lines[7],
+            "tests/data/cities.csv [Row 1/?, Col 9/10] [City: Youngstown]"
+        );
+    }
+
+    #[test]
+    fn test_transposed() {
+        let filename = "tests/data/cities.csv";
+        let config = Arc::new(CsvConfig::new(filename, b',', false));
+        let reader = CsvLensReader::new(config.clone()).unwrap();
+        let headers = reader.headers.clone();
+        let layout = TransposedLayout::new(&headers, 80);
+        assert_eq!(layout.num_records, 2);
+        let mut rows_view = view::RowsView::new(reader, layout.num_records).unwrap();
+        rows_view.set_selected(0);
+        let matcher = Matcher::parse("Yankton", MatchOptions::default(), &headers).unwrap();
+        let finder = find::Finder::new(config, matcher).unwrap();
+        wait_for(|| Some(()).filter(|_| finder.done()));
+        let mut state = CsvTableState::new(filename.to_owned(), headers.len());
+        state.transposed = true;
+        let mut render = |rows_view: &view::RowsView| {
+            state.selected = rows_view.selected();
+            state.finder_state = FinderState::from_finder(&finder, rows_view);
+            let area = Rect::new(0, 0, 80, 16);
+            let mut buf = Buffer::empty(area);
+            CsvTable::new(&headers, rows_view.rows()).render(area, &mut buf, &mut state);
+            buf
+        };
+
+        let buf = render(&rows_view);
+        let lines = buffer_lines(&buf);
+        assert_eq!(
+            lines[1..5],
+            [
+                "            Row 1                             Row 2",
+                "─────────┬──────────────────────────────────────────────────────────────────────",
+                "LatD     │  41                                42",
+                "LatM     │  5                                 52",
+            ]
+        );
+        assert_eq!(
+            lines[11],
+            "City     │  Youngstown                        Yankton"
+        );
+        assert_eq!(reversed_text(&buf, 3), "41");
+        let found_style = |cell: &Cell| cell.fg == Color::Rgb(200, 0, 0);
+        assert_eq!(text_where(&buf, 11, found_style), "Yankton");
+
+        // j selects the next record, then scrolls to the one after
+        rows_view.handle_control(&Control::ScrollDown).unwrap();
+        let buf = render(&rows_view);
+        assert_eq!(reversed_text(&buf, 3), "42");
+        rows_view.handle_control(&Control::ScrollDown).unwrap();
+        let buf = render(&rows_view);
+        let lines = buffer_lines(&buf);
+        assert_eq!(
+            lines[1],
+            "            Row 2                             Row 3"
+        );
+        assert_eq!(reversed_text(&buf, 3), "46");
+        assert_eq!(text_where(&buf, 11, found_style), "Yankton");
+
+        // k back
+        rows_view.handle_control(&Control::ScrollUp).unwrap();
+        let buf = render(&rows_view);
+        assert_eq!(reversed_text(&buf, 3), "42");
+    }
+}
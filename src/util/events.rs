@@ -1,4 +1,4 @@
-use std::io;
+use std::io::{self, Read};
 use std::sync::mpsc;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
@@ -51,16 +51,19 @@ impl Events {
             let tx = tx.clone();
             let ignore_exit_key = ignore_exit_key.clone();
             thread::spawn(move || {
-                let stdin = io::stdin();
-                for evt in stdin.keys() {
-                    if let Ok(key) = evt {
-                        if let Err(err) = tx.send(Event::Input(key)) {
-                            eprintln!("{}", err);
-                            return;
-                        }
-                        if !ignore_exit_key.load(Ordering::Relaxed) && key == config.exit_key {
-                            return;
-                        }
+                // Read keys from the terminal directly if stdin is used for data
+                let input: Box<dyn Read> = if termion::is_tty(&io::stdin()) {
+                    Box::new(io::stdin())
+                } else {
+                    Box::new(termion::get_tty().unwrap())
+                };
+                for key in input.keys().flatten() {
+                    if let Err(err) = tx.send(Event::Input(key)) {
+                        eprintln!("{}", err);
+                        return;
+                    }
+                    if !ignore_exit_key.load(Ordering::Relaxed) && key == config.exit_key {
+                        return;
                     }
                 }
             })
@@ -1,6 +1,8 @@
 use crate::csv::{CsvLensReader, Row};
 use crate::find;
 use crate::input::Control;
+use crate::profile::ColumnProfile;
+use crate::sort::Sorter;
 
 use anyhow::Result;
 use std::cmp::min;
@@ -9,16 +11,38 @@ use std::time::Instant;
 struct RowsFilter {
     indices: Vec<u64>,
     total: usize,
+    /// Whether `indices` are in the order of a finished sort
+    sorted: bool,
 }
 
 impl RowsFilter {
     fn new(finder: &find::Finder, rows_from: u64, num_rows: u64) -> RowsFilter {
         let total = finder.count();
         let indices = finder.get_subset_found(rows_from as usize, num_rows as usize);
-        RowsFilter { indices, total }
+        RowsFilter {
+            indices,
+            total,
+            sorted: false,
+        }
+    }
+
+    fn sorted(found: &SortedFound, rows_from: u64, num_rows: u64) -> RowsFilter {
+        let start = min(rows_from as usize, found.rows.len());
+        let end = min(start.saturating_add(num_rows as usize), found.rows.len());
+        RowsFilter {
+            indices: found.rows[start..end].to_vec(),
+            total: found.rows.len(),
+            sorted: true,
+        }
     }
 }
 
+/// Rows found by a finder, in the order of a finished sort
+struct SortedFound {
+    finder_id: usize,
+    rows: Vec<u64>,
+}
+
 pub struct RowsView {
     reader: CsvLensReader,
     headers: Vec<String>,
@@ -26,8 +50,15 @@ pub struct RowsView {
     num_rows: u64,
     rows_from: u64,
     filter: Option<RowsFilter>,
+    sorter: Option<Sorter>,
+    /// Filtered rows sorted as of the last `set_filter`
+    sorted_found: Option<SortedFound>,
+    /// Whether rows had been fetched in the order of a finished sort
+    sort_applied: bool,
     selected: Option<u64>,
     elapsed: Option<u128>,
+    generation: u64,
+    stick_to_bottom: bool,
 }
 
 impl RowsView {
@@ -42,8 +73,13 @@ impl RowsView {
             num_rows,
             rows_from,
             filter: None,
+            sorter: None,
+            sorted_found: None,
+            sort_applied: false,
             selected: Some(0),
             elapsed: None,
+            generation: 0,
+            stick_to_bottom: false,
         };
         Ok(view)
     }
@@ -70,9 +106,31 @@ impl RowsView {
     }
 
     pub fn set_filter(&mut self, finder: &find::Finder) -> Result<()> {
-        let filter = RowsFilter::new(finder, self.rows_from, self.num_rows);
+        let filter = match self.sorter.as_ref().filter(|s| s.done()) {
+            Some(sorter) => {
+                // Sort the found rows again only as more are found
+                let is_current = self
+                    .sorted_found
+                    .as_ref()
+                    .is_some_and(|f| f.finder_id == finder.id() && f.rows.len() == finder.count());
+                if !is_current {
+                    let mut rows = finder.get_all_found_rows();
+                    sorter.sort_rows(&mut rows);
+                    self.sorted_found = Some(SortedFound {
+                        finder_id: finder.id(),
+                        rows,
+                    });
+                }
+                let found = self.sorted_found.as_ref().unwrap();
+                RowsFilter::sorted(found, self.rows_from, self.num_rows)
+            }
+            None => RowsFilter::new(finder, self.rows_from, self.num_rows),
+        };
         if let Some(cur_filter) = &self.filter {
-            if cur_filter.indices == filter.indices {
+            if cur_filter.indices == filter.indices
+                && cur_filter.total == filter.total
+                && cur_filter.sorted == filter.sorted
+            {
                 return Ok(());
             }
         }
@@ -89,9 +147,28 @@ impl RowsView {
             return Ok(());
         }
         self.filter = None;
+        self.sorted_found = None;
         self.do_get_rows()
     }
 
+    /// Order rows by a column once the sorter is done, or restore file order
+    /// with `None`. Filtered rows follow with the next `set_filter`.
+    pub fn set_sorter(&mut self, sorter: Option<Sorter>) -> Result<()> {
+        self.sorter = sorter;
+        self.sorted_found = None;
+        self.sort_applied = false;
+        self.do_get_rows()
+    }
+
+    pub fn sorter(&self) -> Option<&Sorter> {
+        self.sorter.as_ref()
+    }
+
+    /// Whether unfiltered rows are shown in the order of a finished sort
+    fn is_sorted(&self) -> bool {
+        self.filter.is_none() && self.sorter.as_ref().is_some_and(|s| s.done())
+    }
+
     pub fn rows_from(&self) -> u64 {
         self.rows_from
     }
@@ -116,6 +193,7 @@ impl RowsView {
         self.selected = Some(selected);
     }
 
+    #[allow(dead_code)]
     pub fn reset_selected(&mut self) {
         self.selected = None;
     }
@@ -160,15 +238,44 @@ impl RowsView {
         self.reader.get_total_line_numbers_approx()
     }
 
+    pub fn get_index_progress(&self) -> Option<f64> {
+        self.reader.get_index_progress()
+    }
+
+    pub fn get_index_error(&self) -> Option<String> {
+        self.reader.get_index_error()
+    }
+
+    pub fn get_column_profile(&self) -> ColumnProfile {
+        self.reader.get_column_profile()
+    }
+
+    /// Offset in the view of the row with index `row_index`, which differs
+    /// when sorted
+    pub fn view_offset(&self, row_index: u64) -> u64 {
+        match &self.sorter {
+            Some(sorter) if self.is_sorted() => sorter.position_of(row_index).unwrap_or(row_index),
+            _ => row_index,
+        }
+    }
+
     pub fn in_view(&self, row_index: u64) -> bool {
+        let offset = self.view_offset(row_index);
         let last_row = self.rows_from().saturating_add(self.num_rows());
-        if row_index >= self.rows_from() && row_index < last_row {
+        if offset >= self.rows_from() && offset < last_row {
             return true;
         }
         false
     }
 
     pub fn handle_control(&mut self, control: &Control) -> Result<()> {
+        // All rows in a filtered view are found rows, so stepping through
+        // them is moving the selection
+        let control = match control {
+            Control::ScrollToNextFound if self.is_filter() => &Control::ScrollDown,
+            Control::ScrollToPrevFound if self.is_filter() => &Control::ScrollUp,
+            _ => control,
+        };
         match control {
             Control::ScrollDown => {
                 if let Some(i) = self.selected {
@@ -188,6 +295,7 @@ impl RowsView {
                 }
             }
             Control::ScrollUp => {
+                self.stick_to_bottom = false;
                 if let Some(i) = self.selected {
                     if i == 0 {
                         self.decrease_rows_from(1)?;
@@ -199,21 +307,19 @@ impl RowsView {
                 }
             }
             Control::ScrollPageUp => {
+                self.stick_to_bottom = false;
                 self.decrease_rows_from(self.num_rows)?;
                 if self.selected.is_some() {
                     self.select_top()
                 }
             }
             Control::ScrollBottom => {
-                if let Some(total) = self.get_total() {
-                    let rows_from = total.saturating_sub(self.num_rows as usize) as u64;
-                    self.set_rows_from(rows_from)?;
-                }
-                if self.selected.is_some() {
-                    self.select_bottom()
-                }
+                self.scroll_to_bottom()?;
+                // keep up with new rows if the file is growing
+                self.stick_to_bottom = self.reader.is_growing();
             }
             Control::ScrollTo(n) => {
+                self.stick_to_bottom = false;
                 let mut rows_from = n.saturating_sub(1) as u64;
                 if let Some(n) = self.bottom_rows_from() {
                     rows_from = min(rows_from, n);
@@ -228,9 +334,60 @@ impl RowsView {
         Ok(())
     }
 
+    /// Fetch rows again if the current page is not full but more rows have
+    /// become available since, e.g. while the input is still being streamed.
+    /// Also keeps the view at the bottom after `ScrollBottom` on a growing
+    /// file.
+    pub fn refresh_if_incomplete(&mut self) -> Result<()> {
+        // the file had been replaced, e.g. rotated in follow mode
+        let generation = self.reader.generation();
+        if generation != self.generation {
+            self.generation = generation;
+            self.rows_from = 0;
+            self.do_get_rows()?;
+        }
+        if self.stick_to_bottom {
+            self.scroll_to_bottom()?;
+        }
+        // Filtered rows are sorted by `set_filter`
+        if self.filter.is_none() && self.is_sorted() != self.sort_applied {
+            self.do_get_rows()?;
+        }
+        if self.filter.is_some() || self.rows.len() as u64 >= self.num_rows {
+            return Ok(());
+        }
+        if let Some(total) = self.get_total() {
+            if total as u64 > self.rows_from.saturating_add(self.rows.len() as u64) {
+                self.do_get_rows()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_sticking_to_bottom(&self) -> bool {
+        self.stick_to_bottom
+    }
+
+    pub fn unstick_from_bottom(&mut self) {
+        self.stick_to_bottom = false;
+    }
+
+    fn scroll_to_bottom(&mut self) -> Result<()> {
+        if let Some(total) = self.get_total() {
+            let rows_from = total.saturating_sub(self.num_rows as usize) as u64;
+            self.set_rows_from(rows_from)?;
+        }
+        if self.selected.is_some() {
+            self.select_bottom()
+        }
+        Ok(())
+    }
+
     fn get_total(&self) -> Option<usize> {
         if let Some(filter) = &self.filter {
             return Some(filter.total);
+        } else if let Some(n) = self.sorter.as_ref().and_then(|s| s.count()) {
+            return Some(n);
         } else {
             if let Some(n) = self
                 .reader
@@ -266,9 +423,19 @@ impl RowsView {
     fn do_get_rows(&mut self) -> Result<()> {
         let start = Instant::now();
         let rows;
+        let sorted_indices = match &self.sorter {
+            Some(sorter) if self.filter.is_none() => {
+                sorter.get_subset(self.rows_from as usize, self.num_rows as usize)
+            }
+            _ => None,
+        };
+        self.sort_applied = sorted_indices.is_some();
         if let Some(filter) = &self.filter {
+            self.sort_applied = filter.sorted;
             let indices = &filter.indices;
             rows = self.reader.get_rows_for_indices(indices)?;
+        } else if let Some(indices) = sorted_indices {
+            rows = self.reader.get_rows_for_indices(&indices)?;
         } else {
             rows = self.reader.get_rows(self.rows_from, self.num_rows)?;
         }
@@ -282,3 +449,56 @@ impl RowsView {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::{wait_for, CsvConfig};
+    use crate::matcher::{MatchOptions, Matcher};
+    use crate::sort::SortOrder;
+    use std::sync::Arc;
+
+    fn cities(view: &RowsView) -> Vec<String> {
+        view.rows().iter().map(|r| r.fields[8].clone()).collect()
+    }
+
+    #[test]
+    fn test_sorted_filter() {
+        let config = Arc::new(CsvConfig::new("tests/data/cities.csv", b',', false));
+        let reader = CsvLensReader::new(config.clone()).unwrap();
+        let matcher = Matcher::parse("State=OH", MatchOptions::default(), &reader.headers).unwrap();
+        let finder = find::Finder::new(config.clone(), matcher).unwrap();
+        wait_for(|| Some(()).filter(|_| finder.done()));
+
+        let mut view = RowsView::new(reader, 4).unwrap();
+        view.set_filter(&finder).unwrap();
+        assert_eq!(
+            cities(&view),
+            ["Youngstown", "Toledo", "Steubenville", "Springfield"]
+        );
+
+        let sorter = Sorter::new(config.clone(), 8, SortOrder::Ascending);
+        view.set_sorter(Some(sorter)).unwrap();
+        wait_for(|| Some(()).filter(|_| view.sorter().unwrap().done()));
+        view.set_filter(&finder).unwrap();
+        assert_eq!(
+            cities(&view),
+            ["Ravenna", "Sandusky", "Springfield", "Steubenville"]
+        );
+        view.set_rows_from(2).unwrap();
+        view.set_filter(&finder).unwrap();
+        assert_eq!(
+            cities(&view),
+            ["Springfield", "Steubenville", "Toledo", "Youngstown"]
+        );
+
+        let sorter = Sorter::new(config, 8, SortOrder::Descending);
+        view.set_sorter(Some(sorter)).unwrap();
+        wait_for(|| Some(()).filter(|_| view.sorter().unwrap().done()));
+        view.set_filter(&finder).unwrap();
+        assert_eq!(
+            cities(&view),
+            ["Steubenville", "Springfield", "Sandusky", "Ravenna"]
+        );
+    }
+}